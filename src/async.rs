@@ -0,0 +1,632 @@
+//! Tokio based equivalents of [`SamConnection`](crate::SamConnection), [`Session`](crate::Session)
+//! and [`StreamConnect`](crate::StreamConnect); enabled with the `tokio` feature.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use futures::StreamExt;
+//! use i2p_client::r#async::Session;
+//! use i2p_client::{SamConfig, SessionStyle};
+//! use tokio::io::AsyncWriteExt;
+//!
+//! let session = Session::create(&SamConfig::default(), "TRANSIENT", "Bob", SessionStyle::Stream, &[]).await?;
+//! let mut incoming = session.listener();
+//! while let Some(stream) = incoming.next().await {
+//!     stream?.write_all(b"hello").await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::{debug, info};
+use nom::IResult;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use zeroize::Zeroizing;
+
+use crate::parsers::{gen_reply, sam_hello, sam_naming_reply, sam_session_status, sam_stream_status};
+use crate::destination::ToI2pSocketAddrs;
+use crate::{bounded_line, datagram_send_msg, datagram_udp_msg, decode_datagram, hello_msg, parse_datagram_header, parse_reply, parse_stream_peer, port_options, redact, session_create_msg, Destination, I2pSocketAddr, ReceivedDatagram, Resolver, SamConfig, SessionStyle, SigType, MAX_LINE_LEN};
+
+/// Run `f` for at most `timeout`, failing with `ErrorKind::TimedOut`
+async fn with_timeout<T, F>(timeout: Option<Duration>, msg: &str, f: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+{
+    match timeout {
+        Some(t) => match tokio::time::timeout(t, f).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::new(ErrorKind::TimedOut, msg))
+        },
+        None => f.await
+    }
+}
+
+/// Async counterpart of the bounded reply line reader
+async fn read_line_bounded<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<String, Error> {
+    let mut buf = Vec::new();
+    let num_bytes = reader.take(MAX_LINE_LEN as u64).read_until(b'\n', &mut buf).await?;
+    bounded_line(buf, num_bytes)
+}
+
+pub struct SamConnection {
+    conn: BufReader<TcpStream>,
+    config: SamConfig,
+    current_version: String,
+}
+
+impl SamConnection {
+    /// Connect and handshake with the SAM bridge described by `config`
+    pub async fn connect(config: &SamConfig) -> Result<SamConnection, Error> {
+        let tcp_stream = with_timeout(config.timeouts.connect, "Timed out connecting to SAM bridge",
+                                      TcpStream::connect(config.tcp_addr())).await?;
+        let mut conn = SamConnection {
+            conn: BufReader::new(tcp_stream),
+            config: config.clone(),
+            current_version: String::from("3.0"),
+        };
+        let m = conn.send_timeout(hello_msg(config), sam_hello, config.timeouts.handshake).await?;
+        if let Some(version) = m.get("VERSION").filter(|v| !v.is_empty()) {
+            conn.current_version = version.clone();
+        }
+        Ok(conn)
+    }
+
+    async fn send<F>(&mut self, msg: String, reply_parser: F) -> Result<HashMap<String, String>, Error>
+        where
+            F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
+    {
+        let timeout = self.config.timeouts.reply;
+        self.send_timeout(msg, reply_parser, timeout).await
+    }
+
+    async fn send_timeout<F>(&mut self, msg: String, reply_parser: F, timeout: Option<Duration>) -> Result<HashMap<String, String>, Error>
+        where
+            F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
+    {
+        debug!("-> {}", redact(&msg));
+        let conn = &mut self.conn;
+        let buffer = with_timeout(timeout, "Timed out waiting for SAM reply", async move {
+            conn.write_all(msg.as_bytes()).await?;
+            read_line_bounded(conn).await
+        }).await?;
+        debug!("<- {}", redact(&buffer));
+        parse_reply(&buffer, reply_parser)
+    }
+
+    /// SAM version negotiated in the handshake
+    pub fn version(&self) -> &str {
+        &self.current_version
+    }
+
+    pub async fn naming_lookup(&mut self, name: &str) -> Result<String, Error> {
+        let ret = self.send(format!("NAMING LOOKUP NAME={} \n", name), sam_naming_reply).await?;
+        ret.get("VALUE").cloned()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "NAMING REPLY without VALUE"))
+    }
+
+    pub async fn gen(&mut self, sig_type: SigType) -> Result<(String, String), Error> {
+        let ret = self.send(format!("DEST GENERATE SIGNATURE_TYPE={} \n", sig_type.as_string()), gen_reply).await?;
+        match (ret.get("PUB"), ret.get("PRIV")) {
+            (Some(public), Some(private)) => Ok((public.clone(), private.clone())),
+            _ => Err(Error::new(ErrorKind::InvalidData, "DEST REPLY without PUB/PRIV"))
+        }
+    }
+
+    pub async fn send_msg(&mut self, to: &str, msg: &[u8]) -> Result<(), Error> {
+        self.send_datagram(to, 0, 0, msg).await
+    }
+
+    /// Send a datagram from and to the given ports (0 for none); fails with
+    /// `ErrorKind::InvalidInput` when it is too large to send.
+    pub async fn send_datagram(&mut self, to: &str, from_port: u16, to_port: u16, msg: &[u8]) -> Result<(), Error> {
+        let send_env_msg = datagram_send_msg(to, from_port, to_port, msg)?;
+        info!("Sending packet (size={})...", send_env_msg.len());
+        let conn = &mut self.conn;
+        with_timeout(self.config.timeouts.reply, "Timed out sending to SAM bridge",
+                     conn.write_all(send_env_msg.as_bytes())).await
+    }
+
+    /// Wait for the next datagram for at most the idle timeout
+    pub async fn recv_msg(&mut self) -> Result<(String, Vec<u8>), Error> {
+        let timeout = self.config.timeouts.idle;
+        self.recv_msg_timeout_opt(timeout).await
+    }
+
+    /// Wait for the next datagram for at most `timeout`; no partial datagram is consumed on timeout.
+    pub async fn recv_msg_timeout(&mut self, timeout: Duration) -> Result<(String, Vec<u8>), Error> {
+        self.recv_msg_timeout_opt(Some(timeout)).await
+    }
+
+    async fn recv_msg_timeout_opt(&mut self, timeout: Option<Duration>) -> Result<(String, Vec<u8>), Error> {
+        recv_datagram(&mut self.conn, timeout, self.config.timeouts.reply).await.map(|d| (d.from.to_string(), d.payload))
+    }
+}
+
+/// Wait up to `idle` for the next datagram, then read it within `reply`; no partial datagram is consumed
+/// when `idle` runs out.
+async fn recv_datagram<R: AsyncBufRead + Unpin>(reader: &mut R, idle: Option<Duration>, reply: Option<Duration>) -> Result<ReceivedDatagram, Error> {
+    let eof = with_timeout(idle, "Timed out waiting for data", async {
+        Ok(reader.fill_buf().await?.is_empty())
+    }).await?;
+    if eof {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "SAM bridge closed the connection"));
+    }
+    with_timeout(reply, "Timed out reading datagram", async move {
+        let header = read_line_bounded(reader).await?;
+        debug!("<- HEADER: ({}) {}", header.len(), &header);
+        let (mut datagram, size) = parse_datagram_header(&header)?;
+        let mut body = vec![0u8; size];
+        reader.read_exact(&mut body).await?;
+        datagram.payload = decode_datagram(&body)?;
+        Ok(datagram)
+    }).await
+}
+
+pub struct Session {
+    sam: SamConnection,
+    nickname: String,
+    /// Sends datagrams to the UDP port of the bridge; bound on first use
+    udp: Option<UdpSocket>,
+    local_full_dest: Zeroizing<String>,
+    local_dest: String,
+    style: SessionStyle,
+    resolver: Arc<Resolver>,
+}
+
+impl Session {
+    /// Create a session on the SAM bridge described by `config`, passing `options` through to SESSION CREATE.
+    pub async fn create(
+        config: &SamConfig,
+        destination: &str,
+        nickname: &str,
+        style: SessionStyle,
+        options: &[(String, String)],
+    ) -> Result<Session, Error> {
+        Session::create_with_resolver(config, destination, nickname, style, options, Arc::new(Resolver::from_address_book())).await
+    }
+
+    /// Like [`Session::create`] but sharing `resolver` with other sessions
+    pub async fn create_with_resolver(
+        config: &SamConfig,
+        destination: &str,
+        nickname: &str,
+        style: SessionStyle,
+        options: &[(String, String)],
+        resolver: Arc<Resolver>,
+    ) -> Result<Session, Error> {
+        let mut sam = SamConnection::connect(config).await?;
+        let mut ret = sam.send(session_create_msg(style, nickname, destination, options), sam_session_status).await?;
+        let local_full_dest = ret.remove("DESTINATION").map(Zeroizing::new)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "SESSION STATUS without DESTINATION"))?;
+        let local_dest = sam.naming_lookup("ME").await?;
+        info!("local_dest (size={}): {}", local_dest.len(), local_dest);
+        Ok(Session { sam, nickname: String::from(nickname), udp: None, local_full_dest, local_dest, style, resolver })
+    }
+
+    /// Destination + private keys, reusable as DESTINATION of a later session
+    pub fn local_full_dest(&self) -> &str {
+        &self.local_full_dest
+    }
+
+    /// Public destination of this session
+    pub fn local_dest(&self) -> &str {
+        &self.local_dest
+    }
+
+    pub fn style(&self) -> SessionStyle {
+        self.style
+    }
+
+    pub async fn naming_lookup(&mut self, name: &str) -> Result<String, Error> {
+        self.sam.naming_lookup(name).await
+    }
+
+    pub async fn gen(&mut self, sig_type: SigType) -> Result<(String, String), Error> {
+        self.sam.gen(sig_type).await
+    }
+
+    /// Resolver for names and b32 addresses used by this session
+    pub fn resolver(&self) -> &Arc<Resolver> {
+        &self.resolver
+    }
+
+    pub fn set_resolver(&mut self, resolver: Arc<Resolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Full destination for `dest`, see [`Resolver::resolve`]
+    pub async fn resolve(&mut self, dest: &Destination) -> Result<Destination, Error> {
+        resolve(&self.resolver, &mut self.sam, dest).await
+    }
+
+    /// Send a datagram to the first of `to` that resolves, to its port if not 0
+    pub async fn send_msg<A: ToI2pSocketAddrs>(&mut self, to: A, msg: &[u8]) -> Result<(), Error> {
+        self.send_datagram(to, 0, msg).await
+    }
+
+    /// Like [`Session::send_msg`], from `from_port` (0 for none); sent to the UDP port of the bridge
+    pub async fn send_datagram<A: ToI2pSocketAddrs>(&mut self, to: A, from_port: u16, msg: &[u8]) -> Result<(), Error> {
+        let mut last_err = None;
+        for addr in to.to_i2p_socket_addrs()? {
+            match self.resolve(addr.dest()).await {
+                Ok(dest) => {
+                    let datagram = datagram_udp_msg(&self.nickname, dest.as_str(), from_port, addr.port(), msg)?;
+                    if self.udp.is_none() {
+                        self.udp = Some(bind_udp(&self.sam.config.udp_addr()).await?);
+                    }
+                    info!("Sending packet (size={})...", datagram.len());
+                    self.udp.as_ref().unwrap().send(&datagram).await?;
+                    return Ok(());
+                },
+                Err(e) => last_err = Some(e)
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::InvalidInput, "No I2P addresses to try")))
+    }
+
+    pub async fn recv_msg(&mut self) -> Result<(String, Vec<u8>), Error> {
+        self.sam.recv_msg().await
+    }
+
+    pub async fn recv_msg_timeout(&mut self, timeout: Duration) -> Result<(String, Vec<u8>), Error> {
+        self.sam.recv_msg_timeout(timeout).await
+    }
+
+    /// Split this DATAGRAM session into a `Stream` of incoming and a `Sink` of outgoing datagrams, each
+    /// buffering at most `capacity` datagrams. Reading from the bridge pauses while the incoming buffer is
+    /// full and the sink is not ready while the outgoing buffer is. The stream owns the session: once it
+    /// ends or is dropped the sink fails with `ErrorKind::BrokenPipe`. Must be called within a tokio runtime.
+    pub fn into_datagrams(self, capacity: usize) -> Result<(DatagramSink, DatagramStream), Error> {
+        if self.style != SessionStyle::Datagram {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Not a DATAGRAM session: {}", self.style.string())));
+        }
+        let timeouts = self.sam.config.timeouts;
+        let udp_addr = self.sam.config.udp_addr();
+        let (in_tx, in_rx) = mpsc::channel(capacity);
+        let (out_tx, out_rx) = mpsc::channel(capacity);
+        let error = Arc::new(Mutex::new(None));
+        let reader = tokio::spawn(read_datagrams(BufReader::new(self.sam.conn), in_tx, timeouts.reply, error.clone()));
+        tokio::spawn(write_datagrams(udp_addr, out_rx, error.clone()));
+        let sink = DatagramSink { tx: out_tx, error: error.clone(), nickname: self.nickname, from_port: 0, resolver: self.resolver };
+        Ok((sink, DatagramStream { rx: in_rx, reader, error }))
+    }
+
+    /// Open a stream over this (STREAM) session to the first of `addr` that succeeds, e.g. `"example.i2p:80"`
+    pub async fn connect<A: ToI2pSocketAddrs>(&self, addr: A) -> Result<StreamConnect, Error> {
+        self.connect_from(addr, 0).await
+    }
+
+    /// Open a stream to the first of `addr` that succeeds, from local port `from_port`
+    pub async fn connect_from<A: ToI2pSocketAddrs>(&self, addr: A, from_port: u16) -> Result<StreamConnect, Error> {
+        let mut last_err = None;
+        for addr in addr.to_i2p_socket_addrs()? {
+            match StreamConnect::open(self, &addr, from_port).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e)
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::InvalidInput, "No I2P addresses to try")))
+    }
+
+    /// Wait for one incoming stream on this (STREAM) session
+    pub async fn accept(&self) -> Result<StreamConnect, Error> {
+        accept(self.sam.config.clone(), self.nickname.clone(), self.local_dest.clone()).await
+    }
+
+    /// Incoming streams on this (STREAM) session as a `futures::Stream`
+    pub fn listener(&self) -> StreamListener {
+        StreamListener {
+            config: self.sam.config.clone(),
+            nickname: self.nickname.clone(),
+            local_dest: self.local_dest.clone(),
+            pending: None,
+        }
+    }
+}
+
+async fn accept(config: SamConfig, nickname: String, local_dest: String) -> Result<StreamConnect, Error> {
+    let mut sam = SamConnection::connect(&config).await?;
+    sam.send(format!("STREAM ACCEPT ID={} SILENT=false \n", nickname), sam_stream_status).await?;
+    // The router announces the peer with "$destination [FROM_PORT=n TO_PORT=n]" once connected
+    let line = read_line_bounded(&mut sam.conn).await?;
+    let (peer, local_port) = parse_stream_peer(&line)?;
+    Ok(StreamConnect { conn: sam.conn, peer, local: I2pSocketAddr::new(Destination::from(local_dest), local_port) })
+}
+
+async fn resolve(resolver: &Resolver, sam: &mut SamConnection, dest: &Destination) -> Result<Destination, Error> {
+    if let Some(d) = resolver.resolve_local(dest) {
+        return Ok(d);
+    }
+    let full = sam.naming_lookup(dest.as_str()).await?;
+    resolver.insert(dest, &full);
+    Ok(Destination::from(full))
+}
+
+async fn read_datagrams<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    mut tx: mpsc::Sender<Result<ReceivedDatagram, Error>>,
+    reply: Option<Duration>,
+    error: Arc<Mutex<Option<String>>>,
+) {
+    loop {
+        let res = recv_datagram(&mut reader, None, reply).await;
+        let failed = res.as_ref().err().map(|e| format!("Session closed: {}", e));
+        // Waits while the buffer is full, which stops reading from the bridge
+        if tx.send(res).await.is_err() {
+            break;
+        }
+        if let Some(msg) = failed {
+            error.lock().unwrap().get_or_insert(msg);
+            break;
+        }
+    }
+}
+
+/// UDP socket connected to the UDP port of the bridge
+async fn bind_udp(udp_addr: &str) -> Result<UdpSocket, Error> {
+    let bridge = tokio::net::lookup_host(udp_addr).await?.next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "SAM UDP address resolved to no addresses"))?;
+    let socket = UdpSocket::bind(if bridge.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(bridge).await?;
+    Ok(socket)
+}
+
+async fn write_datagrams(udp_addr: String, mut rx: mpsc::Receiver<Vec<u8>>, error: Arc<Mutex<Option<String>>>) {
+    let res = async {
+        let socket = bind_udp(&udp_addr).await?;
+        while let Some(datagram) = rx.next().await {
+            info!("Sending packet (size={})...", datagram.len());
+            socket.send(&datagram).await?;
+        }
+        Ok::<(), Error>(())
+    }.await;
+    if let Err(e) = res {
+        *error.lock().unwrap() = Some(e.to_string());
+    }
+}
+
+/// Incoming datagrams of a DATAGRAM session; ends after the first error. Dropping it closes the session.
+pub struct DatagramStream {
+    rx: mpsc::Receiver<Result<ReceivedDatagram, Error>>,
+    reader: JoinHandle<()>,
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl Drop for DatagramStream {
+    fn drop(&mut self) {
+        // The reader owns the control socket, so stopping it ends the session
+        self.reader.abort();
+        self.error.lock().unwrap().get_or_insert_with(|| String::from("Session closed: DatagramStream dropped"));
+    }
+}
+
+impl Stream for DatagramStream {
+    type Item = Result<ReceivedDatagram, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// Outgoing datagrams of a DATAGRAM session as `(to, payload)`, sent to the port of `to` (0 for none).
+#[derive(Clone)]
+pub struct DatagramSink {
+    tx: mpsc::Sender<Vec<u8>>,
+    error: Arc<Mutex<Option<String>>>,
+    nickname: String,
+    from_port: u16,
+    resolver: Arc<Resolver>,
+}
+
+impl DatagramSink {
+    /// Send from `from_port` (0 for none)
+    pub fn set_from_port(&mut self, from_port: u16) {
+        self.from_port = from_port;
+    }
+
+    fn closed(&self, e: mpsc::SendError) -> Error {
+        match self.error.lock().unwrap().as_ref() {
+            Some(msg) => Error::new(ErrorKind::BrokenPipe, msg.clone()),
+            None => Error::new(ErrorKind::BrokenPipe, e)
+        }
+    }
+
+    /// Fails once the session is gone, even though sending over UDP would still succeed
+    fn check_open(&self) -> Result<(), Error> {
+        match self.error.lock().unwrap().as_ref() {
+            Some(msg) => Err(Error::new(ErrorKind::BrokenPipe, msg.clone())),
+            None => Ok(())
+        }
+    }
+}
+
+impl Sink<(I2pSocketAddr, Vec<u8>)> for DatagramSink {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.check_open()?;
+        let res = futures::ready!(self.tx.poll_ready(cx));
+        Poll::Ready(res.map_err(|e| self.closed(e)))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, (to, msg): (I2pSocketAddr, Vec<u8>)) -> Result<(), Error> {
+        self.check_open()?;
+        // Names not known locally are left for the router to resolve
+        let dest = self.resolver.resolve_local(to.dest()).unwrap_or_else(|| to.dest().clone());
+        let datagram = datagram_udp_msg(&self.nickname, dest.as_str(), self.from_port, to.port(), &msg)?;
+        let res = self.tx.start_send(datagram);
+        res.map_err(|e| self.closed(e))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let res = futures::ready!(Pin::new(&mut self.tx).poll_flush(cx));
+        Poll::Ready(res.map_err(|e| self.closed(e)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let res = futures::ready!(Pin::new(&mut self.tx).poll_close(cx));
+        Poll::Ready(res.map_err(|e| self.closed(e)))
+    }
+}
+
+type AcceptFuture = Pin<Box<dyn Future<Output = Result<StreamConnect, Error>> + Send>>;
+
+/// Incoming streams of a STREAM session; each item is one accepted connection.
+pub struct StreamListener {
+    config: SamConfig,
+    nickname: String,
+    local_dest: String,
+    pending: Option<AcceptFuture>,
+}
+
+impl StreamListener {
+    /// Wait for the next incoming stream
+    pub async fn accept(&self) -> Result<StreamConnect, Error> {
+        accept(self.config.clone(), self.nickname.clone(), self.local_dest.clone()).await
+    }
+}
+
+impl Stream for StreamListener {
+    type Item = Result<StreamConnect, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.pending.is_none() {
+            let fut = accept(self.config.clone(), self.nickname.clone(), self.local_dest.clone());
+            self.pending = Some(Box::pin(fut));
+        }
+        let res = match self.pending.as_mut() {
+            Some(fut) => futures::ready!(fut.as_mut().poll(cx)),
+            None => unreachable!()
+        };
+        self.pending = None;
+        Poll::Ready(Some(res))
+    }
+}
+
+/// Async I2P stream implementing `AsyncRead`/`AsyncWrite`
+pub struct StreamConnect {
+    conn: BufReader<TcpStream>,
+    peer: I2pSocketAddr,
+    local: I2pSocketAddr,
+}
+
+impl StreamConnect {
+    /// Create a TRANSIENT STREAM session named `nickname` and connect to `destination`, like the blocking
+    /// [`StreamConnect::new`](crate::StreamConnect::new). The stream only works while the returned
+    /// session is kept; dropping it closes the session on the bridge.
+    pub async fn new(config: &SamConfig, destination: &str, port: u16, nickname: &str) -> Result<(Session, StreamConnect), Error> {
+        let session = Session::create(config, "TRANSIENT", nickname, SessionStyle::Stream, &[]).await?;
+        let stream = session.connect((destination, port)).await?;
+        Ok((session, stream))
+    }
+
+    async fn open(session: &Session, addr: &I2pSocketAddr, from_port: u16) -> Result<StreamConnect, Error> {
+        let mut sam = SamConnection::connect(&session.sam.config).await?;
+        let peer_dest = resolve(&session.resolver, &mut sam, addr.dest()).await?;
+        let create_stream_msg = format!("STREAM CONNECT ID={} DESTINATION={} SILENT=false{}\n",
+                                        session.nickname, peer_dest, port_options(from_port, addr.port()));
+        sam.send(create_stream_msg, sam_stream_status).await?;
+        Ok(StreamConnect {
+            conn: sam.conn,
+            peer: I2pSocketAddr::new(peer_dest, addr.port()),
+            local: I2pSocketAddr::new(Destination::new(&session.local_dest), from_port),
+        })
+    }
+
+    /// Full destination of the peer and its port
+    pub fn peer_addr(&self) -> I2pSocketAddr {
+        self.peer.clone()
+    }
+
+    /// Public destination of the session and the local port
+    pub fn local_addr(&self) -> I2pSocketAddr {
+        self.local.clone()
+    }
+}
+
+impl AsyncRead for StreamConnect {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.conn).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for StreamConnect {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.conn).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.conn).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.conn).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use crate::r#async::Session;
+    use crate::tests::fake_bridge;
+    use crate::{Destination, I2pSocketAddr, ReceivedDatagram, SamConfig, SessionStyle};
+
+    #[tokio::test]
+    async fn datagrams() {
+        let bridge = fake_bridge();
+        let mut config = SamConfig::new("127.0.0.1", bridge.port);
+        config.udp_port = bridge.udp_port;
+        let session = Session::create(&config, "TRANSIENT", "async", SessionStyle::Datagram, &[]).await.unwrap();
+        let (mut sink, mut stream) = session.into_datagrams(2).unwrap();
+        let peer = I2pSocketAddr::new(Destination::from("peer"), 0);
+        sink.send((peer.clone(), b"hello".to_vec())).await.unwrap();
+        sink.set_from_port(7);
+        sink.send((I2pSocketAddr::new(Destination::from("peer"), 80), b"again".to_vec())).await.unwrap();
+        let received: Vec<ReceivedDatagram> = stream.by_ref().take(2).map(Result::unwrap).collect().await;
+        assert_eq!(received[0], ReceivedDatagram { from: Destination::from("pubkey"), from_port: 0, to_port: 0, payload: b"hello".to_vec() });
+        assert_eq!(received[1].payload, b"again");
+        let headers: Vec<String> = bridge.received.lock().unwrap().iter().filter(|l| l.starts_with("3.0 ")).cloned().collect();
+        assert_eq!(headers, ["3.0 async peer", "3.0 async peer FROM_PORT=7 TO_PORT=80"]);
+
+        // Dropping the stream closes the session, after which the sink fails instead of sending into the void
+        drop(stream);
+        let err = sink.send((peer, b"gone".to_vec())).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+        let session = Session::create(&config, "TRANSIENT", "async", SessionStyle::Stream, &[]).await.unwrap();
+        assert_eq!(session.into_datagrams(2).err().unwrap().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let bridge = fake_bridge();
+        let config = SamConfig::new("127.0.0.1", bridge.port);
+        let mut session = Session::create(&config, "TRANSIENT", "async", SessionStyle::Stream, &[]).await.unwrap();
+        assert_eq!(session.local_full_dest(), "privkey");
+        assert_eq!(session.naming_lookup("ME").await.unwrap(), "pubkey");
+
+        let mut stream = BufReader::new(session.connect("peer.i2p:80").await.unwrap());
+        stream.write_all(b"ping\n").await.unwrap();
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "ping\n");
+        assert_eq!(stream.get_ref().peer_addr().to_string(), "pubkey:80");
+
+        let incoming = session.listener().next().await.unwrap().unwrap();
+        assert_eq!(incoming.peer_addr().to_string(), "peerdest:1234");
+        assert_eq!(incoming.local_addr().to_string(), "pubkey:80");
+        assert!(bridge.received.lock().unwrap().iter().any(|l| l.starts_with("STREAM ACCEPT ID=async")));
+    }
+}
@@ -3,11 +3,27 @@ extern crate dirs;
 extern crate log;
 extern crate simple_logger;
 
-use clap::{crate_version, App, Arg, AppSettings};
-use i2p_client::{I2PClient, SigType};
+use clap::{crate_version, App, Arg, ArgGroup, ArgMatches, AppSettings};
+use serde_json::{json, Value};
+use i2p_client::{ClientTunnel, Destination, HttpProxy, I2PClient, I2PClientBuilder, I2pSocketAddr, KeyInfo, KeyStore, ReceivedDatagram, SamConfig, SamConnection, ServerTunnel, SigType, Socks5Proxy, StreamConnect, StreamSession, TunnelManager, TunnelState, TunnelsConfig, WriteHalf, keys_to_dat};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Passphrase of encrypted keys, unless given with --passphrase_file
+const PASSPHRASE_ENV: &str = "I2P_CLIENT_PASSPHRASE";
 
 fn main() {
-    simple_logger::init().unwrap();
     let m = App::new("i2p_client")
         .about("A SAM I2P client for the local I2P router instance. Not compliant with any version yet.")
         .version(crate_version!())
@@ -24,23 +40,68 @@ fn main() {
         )
         .arg(
             Arg::with_name("min_version")
-                .help("Minimum SAM version")
+                .help("Minimum SAM version (default: 3.0)")
                 .long("min")
                 .takes_value(true)
         )
         .arg(
             Arg::with_name("max_version")
-                .help("Maximum SAM version")
+                .help("Maximum SAM version (default: 3.3)")
                 .long("max")
                 .takes_value(true)
         )
         .arg(
             Arg::with_name("max_connection_attempts")
-                .help("Maximum attempts to make a connection before failure is accepted. Each failed attempt results in waiting 3 seconds prior to making another attempt.")
+                .help("Maximum attempts to make a connection before failure is accepted. Failed attempts are retried with exponential backoff starting at 3 seconds.")
                 .short("c")
                 .long("max_connection_attempts")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("config")
+                .help("SAM bridge config file (sam.host, sam.port, sam.udp.host, sam.udp.port); defaults to i2p_client/i2p_client.config in the user's config directory if present")
+                .long("config")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("sam_host")
+                .help("SAM bridge host; overrides the config file and SAM_HOST (default: 127.0.0.1)")
+                .long("sam_host")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("sam_port")
+                .help("SAM bridge TCP port; overrides the config file and SAM_PORT (default: 7656)")
+                .long("sam_port")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("sam_udp_host")
+                .help("SAM bridge UDP host; overrides the config file and SAM_UDP_HOST (default: the SAM bridge host)")
+                .long("sam_udp_host")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("sam_udp_port")
+                .help("SAM bridge UDP port; overrides the config file and SAM_UDP_PORT (default: 7655)")
+                .long("sam_udp_port")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("output")
+                .help("print results as text or as one JSON document per result; errors go to stderr either way")
+                .short("o")
+                .long("output")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("passphrase_file")
+                .help("file whose first line is the passphrase of encrypted keys (default: I2P_CLIENT_PASSPHRASE, else asked for when needed); keys saved meanwhile are encrypted with it")
+                .long("passphrase_file")
+                .takes_value(true)
+        )
         .arg(
             Arg::with_name("local")
                 .help("use local keys [true|false]; true by default; when true, it will use an internally saved keyset with provided alias if provided or 'Anon' if not - when set to false, it uses whatever the I2P router provides")
@@ -76,24 +137,320 @@ fn main() {
                 )
         )
         .subcommand(
-            App::new("send")
-                .about("send message - not verified to be working; max message size=31,744 bytes, recommended size is <11KB")
+App::new("send")
+                .about("send a datagram; max size once base64 encoded is 61,500 bytes (about 46KB), recommended size is <11KB")
                 .args(&[
                     Arg::with_name("to")
-                        .help("b32 address")
+                        .help("alias, name.i2p, b32 address or base64 destination")
                         .long("to")
                         .required(true)
                         .takes_value(true),
                     Arg::with_name("message")
-                        .help("message to send as string - required, max size=31.5KB, recommended size is <11KB")
+                        .help("message to send as string")
                         .long("message")
+                        .takes_value(true),
+                    Arg::with_name("file")
+                        .help("file to send as is")
+                        .long("file")
+                        .takes_value(true),
+                    Arg::with_name("stdin")
+                        .help("- to send what is read from stdin")
+                        .possible_value("-")
+                        .index(1),
+                    Arg::with_name("from_port")
+                        .help("port to send from (default: 0)")
+                        .long("from_port")
+                        .takes_value(true),
+                    Arg::with_name("to_port")
+                        .help("port to send to (default: 0)")
+                        .long("to_port")
+                        .takes_value(true),
+                ])
+                .group(ArgGroup::with_name("payload").args(&["message", "file", "stdin"]).required(true))
+        )
+        .subcommand(
+                        App::new("receive")
+                .about("receive datagrams; by default prints the first one as text with its sender and ports")
+                .args(&[
+                    Arg::with_name("follow")
+                        .help("keep receiving until interrupted")
+                        .short("f")
+                        .long("follow"),
+                    Arg::with_name("format")
+                        .help("output format: text (invalid UTF-8 replaced), json (one object per line, payload in base64), hex, base64 or raw (payload bytes only)")
+                        .long("format")
+                        .possible_values(&["text", "json", "hex", "base64", "raw"])
+                        .default_value("text")
+                        .takes_value(true),
+                    Arg::with_name("from")
+                        .help("only datagrams from this destination, name.i2p or b32 address")
+                        .long("from")
+                        .takes_value(true),
+                    Arg::with_name("count")
+                        .help("stop after this many datagrams (default: 1 without --follow)")
+                        .short("c")
+                        .long("count")
+                        .takes_value(true),
+                    Arg::with_name("exec")
+                        .help("run this command for each datagram with the payload as its stdin and I2P_FROM, I2P_FROM_B32, I2P_FROM_PORT and I2P_TO_PORT set")
+                        .short("e")
+                        .long("exec")
+                        .takes_value(true),
+                ])
+        )
+        .subcommand(
+            App::new("connect")
+                .about("open a stream and pipe it to stdin/stdout, like netcat")
+                .args(&[
+                    Arg::with_name("dest")
+                        .help("destination, name.i2p, b32 address or dest:port")
                         .required(true)
+                        .index(1),
+                    Arg::with_name("port")
+                        .help("port on the destination (default: 0)")
+                        .index(2),
+                ])
+        )
+        .subcommand(
+            App::new("listen")
+                .about("print this destination's b32 address and pipe accepted streams to stdin/stdout or a command, like netcat -l")
+                .args(&[
+                    Arg::with_name("keep_open")
+                        .help("keep accepting streams after the first one closes")
+                        .short("k")
+                        .long("keep_open"),
+                    Arg::with_name("exec")
+                        .help("run this command for each stream with the stream as its stdin/stdout")
+                        .short("e")
+                        .long("exec")
+                        .takes_value(true),
+                    Arg::with_name("keys")
+                        .help("alias of the keys to listen on, created on first use (default: a new destination)")
+                        .long("keys")
                         .takes_value(true),
                 ])
         )
         .subcommand(
-            App::new("receive")
-                .about("receive messages - not receiving messages yet")
+            App::new("socks")
+                .about("SOCKS5/SOCKS4a proxy for .i2p hosts over a shared stream session")
+                .args(&[
+                    Arg::with_name("listen")
+                        .help("address to accept SOCKS clients on (default: 127.0.0.1:4447)")
+                        .long("listen")
+                        .takes_value(true),
+                    Arg::with_name("isolate")
+                        .help("use a separate destination per SOCKS username")
+                        .long("isolate"),
+                ])
+        )
+        .subcommand(
+            App::new("http-proxy")
+                .about("HTTP proxy for eepsites over a shared stream session")
+                .arg(
+                    Arg::with_name("listen")
+                        .help("address to accept HTTP clients on (default: 127.0.0.1:4444)")
+                        .long("listen")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            App::new("tunnel")
+                .about("tunnels between local TCP ports and I2P destinations")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("client")
+                        .about("expose a remote I2P destination as a local TCP port")
+                        .args(&[
+                            Arg::with_name("listen")
+                                .help("local address to accept TCP connections on, e.g. 127.0.0.1:8080")
+                                .long("listen")
+                                .required(true)
+                                .takes_value(true),
+                            Arg::with_name("to")
+                                .help("destination and port to tunnel to, e.g. name.i2p:80")
+                                .long("to")
+                                .required(true)
+                                .takes_value(true),
+                        ])
+                )
+                .subcommand(
+                    App::new("server")
+                        .about("publish a local TCP service on a persistent I2P destination")
+                        .args(&[
+                            Arg::with_name("target")
+                                .help("local service to connect incoming streams to, e.g. 127.0.0.1:8000")
+                                .long("target")
+                                .required(true)
+                                .takes_value(true),
+                            Arg::with_name("keys")
+                                .help("alias of the keys, created on first use; the destination stays the same across restarts")
+                                .long("keys")
+                                .required(true)
+                                .takes_value(true),
+                            Arg::with_name("http")
+                                .help("target is an HTTP server: add X-I2P-DestB64/X-I2P-DestB32/X-I2P-DestHash headers to requests")
+                                .long("http"),
+                            Arg::with_name("max_per_peer")
+                                .help("maximum open connections per peer destination")
+                                .long("max_per_peer")
+                                .takes_value(true),
+                        ])
+                )
+        )
+        .subcommand(
+            App::new("keys")
+                .about("manage the private keys of local destinations, saved in the user's data directory, e.g. ~/.local/share/i2p_client/keys")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("list")
+                        .about("list aliases with their b32 address, signature type and creation date")
+                )
+                .subcommand(
+                    App::new("show")
+                        .about("show the b32 address, signature type, creation date and public destination of an alias")
+                        .arg(Arg::with_name("alias")
+                                .help("alias of the keys")
+                                .required(true)
+                                .index(1))
+                )
+                .subcommand(
+                    App::new("create")
+                        .about("generate new keys through the SAM bridge and save them under an alias")
+                        .args(&[
+                            Arg::with_name("alias")
+                                .help("alias of the keys")
+                                .required(true)
+                                .index(1),
+                            Arg::with_name("sig_type")
+                                .help("signature type (default: EDDSA_SHA512_ED25519)")
+                                .short("s")
+                                .long("sig_type")
+                                .takes_value(true),
+                            Arg::with_name("force")
+                                .help("replace existing keys")
+                                .short("f")
+                                .long("force"),
+                            Arg::with_name("encrypt")
+                                .help("encrypt the keys with a passphrase, asked for unless given")
+                                .short("e")
+                                .long("encrypt"),
+                        ])
+                )
+                .subcommand(
+                    App::new("import")
+                        .about("save the keys of a Java I2P or i2pd .dat file (e.g. eepPriv.dat) under an alias")
+                        .args(&[
+                            Arg::with_name("alias")
+                                .help("alias of the keys")
+                                .required(true)
+                                .index(1),
+                            Arg::with_name("file")
+                                .help(".dat file to import")
+                                .required(true)
+                                .index(2),
+                            Arg::with_name("force")
+                                .help("replace existing keys")
+                                .short("f")
+                                .long("force"),
+                            Arg::with_name("encrypt")
+                                .help("encrypt the keys with a passphrase, asked for unless given")
+                                .short("e")
+                                .long("encrypt"),
+                        ])
+                )
+                .subcommand(
+                    App::new("export")
+                        .about("write the keys of an alias as a .dat file for Java I2P or i2pd")
+                        .args(&[
+                            Arg::with_name("alias")
+                                .help("alias of the keys")
+                                .required(true)
+                                .index(1),
+                            Arg::with_name("file")
+                                .help(".dat file to write, - for stdout")
+                                .required(true)
+                                .index(2),
+                            Arg::with_name("force")
+                                .help("replace an existing file")
+                                .short("f")
+                                .long("force"),
+                        ])
+                )
+                .subcommand(
+                    App::new("delete")
+                        .about("delete the keys of an alias; its destination is lost for good unless exported")
+                        .arg(Arg::with_name("alias")
+                                .help("alias of the keys")
+                                .required(true)
+                                .index(1))
+                )
+                .subcommand(
+                    App::new("rename")
+                        .about("save the keys of an alias under another alias")
+                        .args(&[
+                            Arg::with_name("alias")
+                                .help("alias of the keys")
+                                .required(true)
+                                .index(1),
+                            Arg::with_name("to")
+                                .help("new alias")
+                                .required(true)
+                                .index(2),
+                            Arg::with_name("force")
+                                .help("replace existing keys")
+                                .short("f")
+                                .long("force"),
+                        ])
+                )
+                .subcommand(
+                    App::new("passwd")
+                        .about("encrypt the keys of an alias with a new passphrase, or save them unencrypted")
+                        .args(&[
+                            Arg::with_name("alias")
+                                .help("alias of the keys")
+                                .required(true)
+                                .index(1),
+                            Arg::with_name("new_passphrase_file")
+                                .help("file whose first line is the new passphrase (default: asked for)")
+                                .long("new_passphrase_file")
+                                .takes_value(true),
+                            Arg::with_name("remove")
+                                .help("save the keys unencrypted")
+                                .long("remove")
+                                .conflicts_with("new_passphrase_file"),
+                        ])
+                )
+        )
+        .subcommand(
+            App::new("tunnels")
+                .about("run the client, server, socks and http-proxy tunnels of a TOML file; SIGHUP reloads it, SIGUSR1 prints status")
+                .arg(
+                    Arg::with_name("config")
+                        .help("tunnels file, e.g. tunnels.toml")
+                        .long("config")
+                        .required(true)
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            App::new("site")
+                .about("retrieve eepsite and save to local specified directory")
+                .args(&[
+                    Arg::with_name("host")
+                        .help("host name or URL, e.g. 1m5.i2p or http://1m5.i2p/about")
+                        .long("host")
+                        .required(true)
+                        .takes_value(true),
+                    Arg::with_name("directory")
+                        .help("directory to mirror to; prints the page if not provided")
+                        .short("d")
+                        .long("dir")
+                        .takes_value(true),
+                    Arg::with_name("depth")
+                        .help("how many links to follow from the page on the same host when mirroring (default: 0)")
+                        .long("depth")
+                        .takes_value(true),
+                ])
         )
         // .subcommand(
         //     SubCommand::with_name("ping")
@@ -107,52 +464,58 @@ fn main() {
         //                 .takes_value(true),
         //         )
         // )
-        // .subcommand(
-        //     SubCommand::with_name("site")
-        //         .help("retrieve eepsite and save to local specified directory")
-        //         .arg(
-        //             Arg::with_name("host")
-        //                 .help("host name, e.g. 1m5.i2p")
-        //                 .short("h")
-        //                 .long("host")
-        //                 .required(true)
-        //                 .takes_value(true),
-        //         )
-        //         .arg(
-        //             Arg::with_name("directory")
-        //                 .help("directory to save to")
-        //                 .short("d")
-        //                 .long("dir")
-        //                 .required(true)
-        //                 .takes_value(true),
-        //         )
-        // )
-        .get_matches();
+        .get_matches_safe()
+        .unwrap_or_else(|e| {
+            if !e.use_stderr() {
+                // --help and --version
+                e.exit();
+            }
+            eprintln!("{}", e.message);
+            process::exit(2);
+        });
+
+    let output = if m.value_of("output") == Some("json") { Output::Json } else { Output::Text };
+    // connect, listen and receive carry data on stdout, as do JSON results; keys would log private keys
+    if output == Output::Text && !matches!(m.subcommand_name(), Some("connect") | Some("listen") | Some("receive") | Some("keys")) {
+        simple_logger::init().unwrap();
+    }
 
     let local = true; // default
     // if m.value_of("local").is_some() {
     //     local = m.value_of("local").unwrap().eq("true");
     // }
-    let mut alias = String::from("Anon"); // default
-    if m.value_of("alias").is_some() {
-        alias = String::from(m.value_of("alias").unwrap());
+    let mut sam = SamConfig::load(m.value_of("config").map(Path::new)).unwrap_or_else(|e| output.fail(&e));
+    if m.value_of("sam_host").is_some() {
+        sam.host = String::from(m.value_of("sam_host").unwrap());
+    }
+    if let Some(port) = m.value_of("sam_port") {
+        sam.port = output.parse("sam_port", port);
+    }
+    if m.value_of("sam_udp_host").is_some() {
+        sam.udp_host = Some(String::from(m.value_of("sam_udp_host").unwrap()));
+    }
+    if let Some(port) = m.value_of("sam_udp_port") {
+        sam.udp_port = output.parse("sam_udp_port", port);
     }
-    let mut min_version = "3.0"; // default
     if m.value_of("min_version").is_some() {
-        min_version = m.value_of("min_version").unwrap();
+        sam.min_version = String::from(m.value_of("min_version").unwrap());
     }
-    let mut max_version = "3.1"; // default
     if m.value_of("max_version").is_some() {
-        max_version = m.value_of("max_version").unwrap();
+        sam.max_version = String::from(m.value_of("max_version").unwrap());
     }
-    let mut max_connection_attempts: u8 = 3; // default
-    if m.value_of("max_connection_attempts").is_some() {
-        max_connection_attempts = m.value_of("max_connection_attempts").unwrap().parse().unwrap();
+    let mut builder = I2PClientBuilder::new()
+        .sam(sam.clone())
+        .use_local(local);
+    if m.value_of("alias").is_some() {
+        builder = builder.alias(m.value_of("alias").unwrap());
+    }
+    if let Some(attempts) = m.value_of("max_connection_attempts") {
+        builder = builder.max_connection_attempts(output.parse("max_connection_attempts", attempts));
     }
 
     match m.subcommand_name() {
         Some("aliases") => {
-            aliases();
+            aliases(output);
         },
         Some("gen") => {
             let am = m.subcommand().1.unwrap();
@@ -160,35 +523,78 @@ fn main() {
             if am.value_of("sig_type").is_some() {
                 sig_type = am.value_of("sig_type").unwrap();
             }
-            gen(
-                sig_type,
-                local,
-                alias,
-                min_version,
-                max_version,
-                max_connection_attempts);
+            gen(sig_type, builder, output);
         },
         Some("dest") => {
-            dest(m.subcommand().1.unwrap().value_of("dest_alias").unwrap());
+            dest(m.subcommand().1.unwrap().value_of("dest_alias").unwrap(), output);
         },
         Some("send") => {
             let am = m.subcommand().1.unwrap();
-            send(
-                String::from(am.value_of("to").unwrap()),
-                String::from(am.value_of("message").unwrap()),
-                local,
-                alias,
-                min_version,
-                max_version,
-                max_connection_attempts);
+            let port = |name| am.value_of(name).map_or(0, |p| output.parse(name, p));
+            let message = match (am.value_of("message"), am.value_of("file")) {
+                (Some(message), _) => Ok(message.as_bytes().to_vec()),
+                (None, Some(file)) => std::fs::read(file),
+                (None, None) => {
+                    let mut message = Vec::new();
+                    io::stdin().read_to_end(&mut message).map(|_| message)
+                }
+            };
+            let message = message.unwrap_or_else(|e| output.fail(&io::Error::new(e.kind(), format!("Unable to read message: {}", e))));
+            send(am.value_of("to").unwrap(), port("from_port"), port("to_port"), message, builder, output);
         },
         Some("receive") => {
+            let am = m.subcommand().1.unwrap();
+            let count = match am.value_of("count") {
+                Some(count) => Some(output.parse("count", count)),
+                None if am.is_present("follow") => None,
+                None => Some(1)
+            };
+            // JSON output implies JSON records unless another format was asked for
+            let format = match output {
+                Output::Json if am.occurrences_of("format") == 0 => "json",
+                _ => am.value_of("format").unwrap()
+            };
             receive(
-                local,
-                    alias,
-                    min_version,
-                    max_version,
-                    max_connection_attempts);
+                format,
+                am.value_of("from"),
+                count,
+                am.value_of("exec"),
+                builder,
+                output);
+        },
+        Some("connect") => {
+            let am = m.subcommand().1.unwrap();
+            let port = am.value_of("port").map(|p| output.parse("port", p));
+            connect(am.value_of("dest").unwrap(), port, sam, m.value_of("alias").unwrap_or("Anon"), output);
+        },
+        Some("listen") => {
+            let am = m.subcommand().1.unwrap();
+            let store = key_store(&m, &am.value_of("keys").into_iter().collect::<Vec<_>>()).unwrap_or_else(|e| output.fail(&e));
+            listen(
+                am.is_present("keep_open"),
+                am.value_of("exec"),
+                am.value_of("keys"),
+                store,
+                sam,
+                m.value_of("alias").unwrap_or("Anon"),
+                output);
+        },
+        Some("socks") => {
+            let am = m.subcommand().1.unwrap();
+            socks(
+                am.value_of("listen").unwrap_or("127.0.0.1:4447"),
+                am.is_present("isolate"),
+                sam,
+                m.value_of("alias").unwrap_or("Anon"),
+                output);
+        },
+        Some("http-proxy") => {
+            let am = m.subcommand().1.unwrap();
+            http_proxy(
+                am.value_of("listen").unwrap_or("127.0.0.1:4444"),
+                sam,
+                m.value_of("alias").unwrap_or("Anon"),
+                output);
         },
         // Some("ping") => {
         //     let mut msg = "keep-alive";
@@ -203,19 +609,47 @@ fn main() {
         //          max_version,
         //          max_connection_attempts);
         // },
-        // Some("site") => {
-        //     if m.value_of("host").is_some() && m.value_of("directory").is_some() {
-        //         let host = m.value_of("host").unwrap();
-        //         let dir = m.value_of("directory").unwrap();
-        //         site(host,
-        //              dir,
-        //              local,
-        //              alias,
-        //              min_version,
-        //              max_version,
-        //              max_connection_attempts);
-        //     }
-        // },
+        Some("tunnel") => {
+            match m.subcommand().1.unwrap().subcommand() {
+                ("client", Some(tm)) => {
+                    client_tunnel(
+                        tm.value_of("listen").unwrap(),
+                        tm.value_of("to").unwrap(),
+                        sam,
+                        m.value_of("alias").unwrap_or("Anon"),
+                        output);
+                },
+                ("server", Some(tm)) => {
+                    let max_per_peer = tm.value_of("max_per_peer").map(|n| output.parse("max_per_peer", n));
+                    let store = key_store(&m, &[tm.value_of("keys").unwrap()]).unwrap_or_else(|e| output.fail(&e));
+                    server_tunnel(
+                        tm.value_of("target").unwrap(),
+                        tm.value_of("keys").unwrap(),
+                        tm.is_present("http"),
+                        max_per_peer,
+                        store,
+                        sam,
+                        output);
+                },
+                _ => output.fail(&io::Error::new(ErrorKind::InvalidInput, "No tunnel type was given"))
+            }
+        },
+        Some("keys") => {
+            keys(&m, sam, output);
+        },
+        Some("tunnels") => {
+            tunnels(m.subcommand().1.unwrap().value_of("config").unwrap(), &m, sam, output);
+        },
+        Some("site") => {
+            let am = m.subcommand().1.unwrap();
+            let depth = output.parse("depth", am.value_of("depth").unwrap_or("0"));
+            site(
+                am.value_of("host").unwrap(),
+                am.value_of("directory"),
+                depth,
+                builder,
+                output);
+        },
         None => {
             println!("No subcommand was used")
         },
@@ -255,63 +689,473 @@ fn main() {
     // client_alice.shutdown();
 }
 
-fn aliases() {
-    let m = I2PClient::aliases();
-    println!("Aliases...");
-    for k in m.keys() {
-        println!("{}\n{}\n", k, m.get(k).unwrap());
+/// How results and errors are printed, see `--output`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Text,
+    Json,
+}
+
+impl Output {
+    /// Print a result as `json` on one line, or as `text`
+    fn print(self, json: Value, text: &str) {
+        match self {
+            Output::Json => println!("{}", json),
+            Output::Text => println!("{}", text)
+        }
+    }
+
+    /// Print `e` to stderr
+    fn report(self, e: &io::Error) {
+        match self {
+            Output::Json => eprintln!("{}", json!({ "error": { "kind": exit_status(e).1, "message": e.to_string() } })),
+            Output::Text => eprintln!("{}", e)
+        }
+    }
+
+    /// Print `e` to stderr and exit with its status
+    fn fail(self, e: &io::Error) -> ! {
+        self.report(e);
+        process::exit(exit_status(e).0)
+    }
+
+    /// Parse the value of option `name`, failing as invalid input
+    fn parse<T: FromStr>(self, name: &str, value: &str) -> T
+        where
+            T::Err: Display,
+    {
+        value.parse().unwrap_or_else(|e| {
+            self.fail(&io::Error::new(ErrorKind::InvalidInput, format!("Invalid {} {}: {}", name, value, e)))
+        })
     }
 }
 
-fn dest(alias: &str) {
-    println!("{}\n{}\n", alias, I2PClient::dest(alias));
+/// Exit status and JSON error kind for `e`
+fn exit_status(e: &io::Error) -> (i32, &'static str) {
+    match e.kind() {
+        ErrorKind::InvalidInput => (2, "invalid_input"),
+        ErrorKind::NotFound => (3, "not_found"),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => (4, "unavailable"),
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => (5, "timed_out"),
+        ErrorKind::InvalidData => (6, "invalid_data"),
+        ErrorKind::PermissionDenied => (7, "permission_denied"),
+        ErrorKind::AddrInUse | ErrorKind::AlreadyExists => (8, "already_exists"),
+        _ => (1, "other")
+    }
 }
 
-fn gen(sig_type: &str, use_local: bool, alias: String, min_version: &str, max_version: &str, max_connection_attempts: u8) {
-    match SigType::from_str(sig_type) {
-        Ok(sig_type) => {
-            match I2PClient::new(use_local, alias, min_version, max_version, max_connection_attempts)
-            {
-                Ok(mut client) => {
-                    match client.gen(sig_type) {
-                        Ok(t) => {
-                            println!("public key:\n{}\nprivate key:\n{}", t.0, t.1)
-                        },
-                        Err(e) => println!("{}", e)
-                    }
-                },
-                Err(e) => println!("{}", e)
+/// b32 address of `dest`, or `dest` itself when it has none
+fn b32(dest: &Destination) -> String {
+    dest.to_b32().unwrap_or_else(|| dest.to_string())
+}
+
+fn aliases(output: Output) {
+    let m: BTreeMap<String, String> = I2PClient::aliases().into_iter().collect();
+    let mut text = String::from("Aliases...");
+    for (k, v) in &m {
+        text.push_str(&format!("\n{}\n{}\n", k, v));
+    }
+    output.print(json!({ "aliases": m }), &text);
+}
+
+fn dest(alias: &str, output: Output) {
+    match I2PClient::aliases().get(alias) {
+        Some(dest) => output.print(
+            json!({ "alias": alias, "dest": dest, "b32": Destination::new(dest).to_b32() }),
+            &format!("{}\n{}\n", alias, dest)),
+        None => output.fail(&io::Error::new(ErrorKind::NotFound, format!("Alias {} not found", alias)))
+    }
+}
+
+fn gen(sig_type: &str, builder: I2PClientBuilder, output: Output) {
+    let sig = SigType::from_str(sig_type).unwrap_or_else(|e| output.fail(&e));
+    let mut client = builder.build().unwrap_or_else(|e| output.fail(&e));
+    match client.gen(sig) {
+        Ok((public, private)) => output.print(
+            json!({ "sig_type": sig_type, "public": public, "private": private, "b32": Destination::new(&public).to_b32() }),
+            &format!("public key:\n{}\nprivate key:\n{}", public, private)),
+        Err(e) => output.fail(&e)
+    }
+}
+
+fn send(to: &str, from_port: u16, to_port: u16, message: Vec<u8>, builder: I2PClientBuilder, output: Output) {
+    let mut client = builder.build().unwrap_or_else(|e| output.fail(&e));
+    if output == Output::Text {
+        println!("Sending msg...");
+    }
+    // Aliases in the address book, names and b32 addresses are resolved by the session
+    match client.send_datagram(I2pSocketAddr::new(Destination::new(to), to_port), from_port, &message) {
+        Ok(_) => output.print(
+            json!({ "to": to, "from_port": from_port, "to_port": to_port, "size": message.len() }),
+            "Send successful"),
+        Err(e) => output.fail(&e)
+    }
+}
+
+fn receive(format: &str, from: Option<&str>, count: Option<u64>, exec: Option<&str>, builder: I2PClientBuilder, output: Output) {
+    let mut client = builder.build().unwrap_or_else(|e| output.fail(&e));
+    // Compare b32 addresses so a full destination, a b32 address or a name all match the sender
+    let from = from.map(|from| {
+        let dest = Destination::new(from);
+        dest.to_b32()
+            .map_or_else(|| client.resolve(&dest).map(|d| d.to_b32().unwrap_or_else(|| d.to_string())), Ok)
+            .unwrap_or_else(|e| output.fail(&io::Error::new(e.kind(), format!("Unable to resolve {}: {}", from, e))))
+    });
+    let mut received = 0;
+    while count.map_or(true, |count| received < count) {
+        let datagram = client.receive_datagram().unwrap_or_else(|e| output.fail(&e));
+        let from_b32 = b32(&datagram.from);
+        if from.as_ref().is_some_and(|from| *from != from_b32) {
+            continue;
+        }
+        received += 1;
+        let res = match exec {
+            Some(command) => exec_datagram(command, &datagram, &from_b32),
+            None => print_datagram(format, &datagram, &from_b32)
+        };
+        if let Err(e) = res {
+            // The reader went away, e.g. `receive --follow | head`
+            if e.kind() == ErrorKind::BrokenPipe {
+                break;
             }
+            output.fail(&e);
+        }
+    }
+}
+
+fn print_datagram(format: &str, datagram: &ReceivedDatagram, from_b32: &str) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let payload = &datagram.payload;
+    match format {
+        "json" => {
+            let mut record = serde_json::json!({
+                "from": datagram.from.as_str(),
+                "from_b32": from_b32,
+                "from_port": datagram.from_port,
+                "to_port": datagram.to_port,
+                "size": payload.len(),
+                "payload": base64::encode(payload),
+            });
+            if let Ok(text) = std::str::from_utf8(payload) {
+                record["text"] = text.into();
+            }
+            writeln!(out, "{}", record)?;
+        },
+        "hex" => {
+            let hex: String = payload.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "{} {} {} {}", from_b32, datagram.from_port, datagram.to_port, hex)?;
+        },
+        "base64" => writeln!(out, "{} {} {} {}", from_b32, datagram.from_port, datagram.to_port, base64::encode(payload))?,
+        "raw" => {
+            // Keep stdout to the payload bytes so binary data passes through untouched
+            eprintln!("Datagram from {} (port {} to {}, {} bytes)", from_b32, datagram.from_port, datagram.to_port, payload.len());
+            out.write_all(payload)?;
         },
-        Err(e) => println!("{}", e)
+        _ => writeln!(out, "from {} (port {} to {}): {}", from_b32, datagram.from_port, datagram.to_port, String::from_utf8_lossy(payload))?
     }
+    out.flush()
 }
 
-fn send(to: String, message: String, use_local: bool, alias: String, min_version: &str, max_version: &str, max_connection_attempts: u8) {
-    match I2PClient::new(use_local, alias, min_version, max_version, max_connection_attempts) {
-        Ok(mut client) => {
-            println!("Sending msg...");
-            client.send(to.clone(), message.clone().into_bytes());
-            println!("Send successful")
+/// Run `command` with the payload as its stdin and the sender in its environment
+fn exec_datagram(command: &str, datagram: &ReceivedDatagram, from_b32: &str) -> io::Result<()> {
+    let mut child = shell(command);
+    child.env("I2P_FROM", datagram.from.as_str())
+        .env("I2P_FROM_B32", from_b32)
+        .env("I2P_FROM_PORT", datagram.from_port.to_string())
+        .env("I2P_TO_PORT", datagram.to_port.to_string())
+        .stdin(Stdio::piped());
+    let mut child = child.spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // A command that ignores its input only fails here with a broken pipe
+    if let Err(e) = stdin.write_all(&datagram.payload) {
+        if e.kind() != ErrorKind::BrokenPipe {
+            return Err(e);
+        }
+    }
+    drop(stdin);
+    let status = child.wait()?;
+    if !status.success() {
+        eprintln!("{}: {}", command, status);
+    }
+    Ok(())
+}
+
+fn keys(m: &ArgMatches, sam: SamConfig, output: Output) {
+    let (command, am) = m.subcommand_matches("keys").unwrap().subcommand();
+    let am = am.unwrap();
+    let alias = am.value_of("alias").unwrap_or_default();
+    let force = am.is_present("force");
+    // Only export and passwd read private keys; create and import need a new passphrase to encrypt
+    let store = match command {
+        "export" | "passwd" => key_store(m, &[alias]),
+        _ if am.is_present("encrypt") => new_passphrase(m).and_then(|p| KeyStore::open_default().map(|s| s.with_passphrase(p.as_str()))),
+        _ => passphrase(m).and_then(|p| KeyStore::open_default().map(|s| match p {
+            Some(p) => s.with_passphrase(p.as_str()),
+            None => s
+        }))
+    };
+    let store = store.unwrap_or_else(|e| output.fail(&e));
+    let res = match command {
+        "list" => store.list().map(|keys| {
+            let json: Vec<Value> = keys.iter().map(key_json).collect();
+            let text: Vec<String> = keys.iter().map(|k| {
+                format!("{:<16} {:<60} {:<24} {:<20} {}", k.alias, k.b32, k.sig_type, k.created_utc().unwrap_or_default(),
+                        if k.encrypted { "encrypted" } else { "" }).trim_end().to_string()
+            }).collect();
+            output.print(json!({ "keys": json }), &text.join("\n"));
+        }),
+        "show" => store.info(alias).map(|k| output.print(key_json(&k), &key_text(&k))),
+        "create" => {
+            let sig = SigType::from_str(am.value_of("sig_type").unwrap_or("EDDSA_SHA512_ED25519")).unwrap_or_else(|e| output.fail(&e));
+            if !force && store.contains(alias) {
+                output.fail(&io::Error::new(ErrorKind::AlreadyExists, format!("Keys for alias {} already exist", alias)));
+            }
+            SamConnection::connect_with(&sam)
+                .and_then(|mut conn| conn.gen(sig))
+                .and_then(|(_, private)| store.save(alias, &private, force))
+                .map(|k| output.print(key_json(&k), &key_text(&k)))
+        },
+        "import" => store.import_dat(alias, am.value_of("file").unwrap(), force)
+            .map(|k| output.print(key_json(&k), &key_text(&k))),
+        "export" => match am.value_of("file").unwrap() {
+            "-" => store.get(alias).and_then(|keys| keys_to_dat(&keys)).and_then(|dat| {
+                let mut stdout = io::stdout();
+                stdout.write_all(&dat).and_then(|_| stdout.flush())
+            }),
+            file => store.export_dat(alias, file, force)
+                .map(|_| output.print(json!({ "alias": alias, "file": file }), &format!("Exported {} to {}", alias, file)))
+        },
+        "delete" => store.delete(alias)
+            .map(|_| output.print(json!({ "alias": alias, "deleted": true }), &format!("Deleted {}", alias))),
+        "rename" => store.rename(alias, am.value_of("to").unwrap(), force)
+            .map(|k| output.print(key_json(&k), &key_text(&k))),
+        "passwd" => {
+            let passphrase = match am.value_of("new_passphrase_file") {
+                _ if am.is_present("remove") => Ok(None),
+                Some(file) => read_passphrase(file).map(Some),
+                None => ask_passphrase(&format!("New passphrase for the keys of {}: ", alias), true).map(Some)
+            };
+            passphrase.and_then(|p| store.change_passphrase(alias, p.as_ref().map(|p| p.as_str())))
+                .map(|k| output.print(key_json(&k), &key_text(&k)))
         },
-        Err(e) => println!("{}", e)
+        _ => Err(io::Error::new(ErrorKind::InvalidInput, "No keys command was given"))
+    };
+    if let Err(e) = res {
+        output.fail(&e);
     }
 }
 
-fn receive(use_local: bool, alias: String, min_version: &str, max_version: &str, max_connection_attempts: u8) {
-    match I2PClient::new(use_local, alias, min_version, max_version, max_connection_attempts) {
-        Ok(mut client) => {
-            match client.receive() {
-                Ok(tup) => {
-                    match String::from_utf8(tup.1) {
-                        Ok(msg) => println!("msg received: {}\nfrom: {}", msg, tup.0),
-                        Err(e) => println!("{}", e)
+fn key_json(k: &KeyInfo) -> Value {
+    json!({ "alias": k.alias, "b32": k.b32, "sig_type": k.sig_type, "created": k.created_utc(), "encrypted": k.encrypted, "dest": k.dest.as_str() })
+}
+
+fn key_text(k: &KeyInfo) -> String {
+    format!("alias: {}\nb32: {}\nsig_type: {}\ncreated: {}\nencrypted: {}\ndest: {}",
+            k.alias, k.b32, k.sig_type, k.created_utc().unwrap_or_default(), k.encrypted, k.dest)
+}
+
+/// The key store, with the passphrase of I2P_CLIENT_PASSPHRASE or --passphrase_file, or asked for
+/// if one of `aliases` is encrypted
+fn key_store(m: &ArgMatches, aliases: &[&str]) -> io::Result<KeyStore> {
+    let store = KeyStore::open_default()?;
+    let passphrase = match passphrase(m)? {
+        Some(passphrase) => passphrase,
+        None => match aliases.iter().find(|alias| store.is_encrypted(alias).unwrap_or(false)) {
+            Some(alias) => ask_passphrase(&format!("Passphrase for the keys of {}: ", alias), false)?,
+            None => return Ok(store)
+        }
+    };
+    Ok(store.with_passphrase(passphrase.as_str()))
+}
+
+/// Passphrase of I2P_CLIENT_PASSPHRASE or --passphrase_file, if any
+fn passphrase(m: &ArgMatches) -> io::Result<Option<Zeroizing<String>>> {
+    if let Some(file) = m.value_of("passphrase_file") {
+        return read_passphrase(file).map(Some);
+    }
+    match env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => check_passphrase(Zeroizing::new(passphrase)).map(Some),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", PASSPHRASE_ENV, e)))
+    }
+}
+
+/// Passphrase to encrypt new keys with: the given one or one asked for twice
+fn new_passphrase(m: &ArgMatches) -> io::Result<Zeroizing<String>> {
+    match passphrase(m)? {
+        Some(passphrase) => Ok(passphrase),
+        None => ask_passphrase("Passphrase for the new keys: ", true)
+    }
+}
+
+/// First line of `path`
+fn read_passphrase(path: &str) -> io::Result<Zeroizing<String>> {
+    let contents = Zeroizing::new(fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?);
+    check_passphrase(Zeroizing::new(String::from(contents.lines().next().unwrap_or_default())))
+}
+
+/// Read a passphrase from the terminal, twice if `confirm` is set
+fn ask_passphrase(prompt: &str, confirm: bool) -> io::Result<Zeroizing<String>> {
+    let no_terminal = |e: io::Error| io::Error::new(ErrorKind::PermissionDenied,
+        format!("A passphrase is needed: unable to ask for it ({}), set {} or use --passphrase_file", e, PASSPHRASE_ENV));
+    let passphrase = Zeroizing::new(rpassword::prompt_password(prompt).map_err(no_terminal)?);
+    if confirm {
+        let again = Zeroizing::new(rpassword::prompt_password("Repeat the passphrase: ").map_err(no_terminal)?);
+        if again != passphrase {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "The passphrases do not match"));
+        }
+    }
+    check_passphrase(passphrase)
+}
+
+fn check_passphrase(passphrase: Zeroizing<String>) -> io::Result<Zeroizing<String>> {
+    if passphrase.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Empty passphrase"));
+    }
+    Ok(passphrase)
+}
+
+fn connect(dest: &str, port: Option<u16>, sam: SamConfig, alias: &str, output: Output) {
+    let stream = match StreamSession::create(&sam, "TRANSIENT", alias, &[]) {
+        Ok(session) => match port {
+            Some(port) => session.connect((dest, port)),
+            None => session.connect(dest)
+        },
+        Err(e) => Err(e)
+    };
+    let (mut read, mut write) = stream.unwrap_or_else(|e| output.fail(&e)).into_split();
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin(), &mut write);
+        let _ = write.shutdown();
+    });
+    if let Err(e) = io::copy(&mut read, &mut io::stdout()) {
+        output.fail(&e);
+    }
+}
+
+fn listen(keep_open: bool, exec: Option<&str>, keys: Option<&str>, store: KeyStore, sam: SamConfig, alias: &str, output: Output) {
+    let session = match keys {
+        Some(keys) => StreamSession::create_persistent_in(&sam, &store, keys, &[]),
+        None => StreamSession::create(&sam, "TRANSIENT", alias, &[])
+    };
+    let session = session.unwrap_or_else(|e| output.fail(&e));
+    eprintln!("Listening on {}", b32(&Destination::new(session.local_dest())));
+
+    // stdin is read once and goes to whichever stream is open, waiting for one if needed
+    let current: Arc<(Mutex<Option<WriteHalf>>, Condvar)> = Arc::new((Mutex::new(None), Condvar::new()));
+    let stdin_closed = Arc::new(AtomicBool::new(false));
+    if exec.is_none() {
+        let (current, stdin_closed) = (current.clone(), stdin_closed.clone());
+        thread::spawn(move || {
+            let (writer, opened) = &*current;
+            let mut buf = [0u8; 8192];
+            let mut stdin = io::stdin();
+            while let Ok(n) = stdin.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let mut writer = opened.wait_while(writer.lock().unwrap(), |w| w.is_none()).unwrap();
+                if writer.as_mut().unwrap().write_all(&buf[..n]).is_err() {
+                    *writer = None;
+                }
+            }
+            let mut writer = writer.lock().unwrap();
+            stdin_closed.store(true, Ordering::SeqCst);
+            if let Some(write) = writer.take() {
+                let _ = write.shutdown();
+            }
+        });
+    }
+
+    loop {
+        let stream = session.accept().unwrap_or_else(|e| output.fail(&e));
+        if let Ok(peer) = stream.peer_addr() {
+            eprintln!("Connection from {}", b32(peer.dest()));
+        }
+        match exec {
+            Some(command) => {
+                let command = String::from(command);
+                let child = thread::spawn(move || {
+                    if let Err(e) = exec_stream(&command, stream) {
+                        output.report(&io::Error::new(e.kind(), format!("{}: {}", command, e)));
                     }
-                },
-                Err(e) => println!("{}", e)
+                });
+                if !keep_open {
+                    let _ = child.join();
+                }
+            },
+            None => {
+                let (mut read, write) = stream.into_split();
+                let (writer, opened) = &*current;
+                let mut open = writer.lock().unwrap();
+                if stdin_closed.load(Ordering::SeqCst) {
+                    let _ = write.shutdown();
+                } else {
+                    *open = Some(write);
+                    opened.notify_all();
+                }
+                drop(open);
+                if let Err(e) = io::copy(&mut read, &mut io::stdout()) {
+                    output.report(&e);
+                }
+                *writer.lock().unwrap() = None;
             }
-        },
-        Err(e) => println!("{}", e)
+        }
+        if !keep_open {
+            break;
+        }
+    }
+}
+
+/// `command` run by the platform shell
+fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Run `command` with the stream as its stdin and stdout
+fn exec_stream(command: &str, stream: StreamConnect) -> io::Result<()> {
+    let mut child = shell(command).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+    let (mut read, mut write) = stream.into_split();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    thread::spawn(move || {
+        // Dropping stdin closes it once the peer is done
+        let _ = io::copy(&mut read, &mut stdin);
+    });
+    io::copy(&mut stdout, &mut write)?;
+    let _ = write.shutdown();
+    child.wait()?;
+    Ok(())
+}
+
+/// Bind `listen`, failing with the address in the error
+fn bind(listen: &str, output: Output) -> TcpListener {
+    TcpListener::bind(listen)
+        .unwrap_or_else(|e| output.fail(&io::Error::new(e.kind(), format!("Unable to listen on {}: {}", listen, e))))
+}
+
+fn socks(listen: &str, isolate: bool, sam: SamConfig, alias: &str, output: Output) {
+    let listener = bind(listen, output);
+    let session = StreamSession::create(&sam, "TRANSIENT", alias, &[]).unwrap_or_else(|e| output.fail(&e));
+    let mut proxy = Socks5Proxy::new(session);
+    if isolate {
+        proxy = proxy.isolate(sam);
+    }
+    output.print(json!({ "listen": listen }), &format!("SOCKS proxy listening on {}", listen));
+    if let Err(e) = proxy.serve(listener) {
+        output.fail(&e);
     }
 }
 
@@ -323,9 +1167,147 @@ fn receive(use_local: bool, alias: String, min_version: &str, max_version: &str,
 //     }
 // }
 
-// fn site(host: &str, dir: &str, use_local: bool, alias: String, min_version: &str, max_version: &str, max_connection_attempts: u8) {
-    // let mut client = I2PClient::new(use_local, alias, min_version, max_version, max_connection_attempts);
-    // match client.site(host) {
-    //
-    // }
-// }
\ No newline at end of file
+fn site(host: &str, dir: Option<&str>, depth: u32, builder: I2PClientBuilder, output: Output) {
+    let url = if host.contains("://") { String::from(host) } else { format!("http://{}/", host) };
+    let mut client = builder.build_stream().unwrap_or_else(|e| output.fail(&e));
+    match dir {
+        Some(dir) => match client.mirror(&url, dir, depth) {
+            Ok(saved) => output.print(
+                json!({ "url": url, "dir": dir, "files": saved }),
+                &format!("Saved {} files to {}", saved.len(), dir)),
+            Err(e) => output.fail(&e)
+        },
+        None => match client.fetch(&url) {
+            Ok(response) => {
+                let res = match output {
+                    Output::Json => {
+                        println!("{}", json!({
+                            "url": response.url,
+                            "status": response.status,
+                            "reason": response.reason,
+                            "headers": response.headers,
+                            "body": base64::encode(&response.body),
+                        }));
+                        Ok(())
+                    },
+                    Output::Text => io::stdout().write_all(&response.body)
+                };
+                if let Err(e) = res {
+                    output.fail(&e);
+                }
+            },
+            Err(e) => output.fail(&e)
+        }
+    }
+}
+
+fn client_tunnel(listen: &str, to: &str, sam: SamConfig, alias: &str, output: Output) {
+    let to = to.parse().unwrap_or_else(|e| output.fail(&e));
+    let listener = bind(listen, output);
+    let session = StreamSession::create(&sam, "TRANSIENT", alias, &[]).unwrap_or_else(|e| output.fail(&e));
+    let tunnel = ClientTunnel::new(session, to);
+    output.print(json!({ "listen": listen, "to": tunnel.to().to_string() }), &format!("Tunnelling {} to {}", listen, tunnel.to()));
+    if let Err(e) = tunnel.serve(listener) {
+        output.fail(&e);
+    }
+}
+
+fn server_tunnel(target: &str, keys: &str, http: bool, max_per_peer: Option<usize>, store: KeyStore, sam: SamConfig, output: Output) {
+    let target = target.parse().unwrap_or_else(|e| {
+        output.fail(&io::Error::new(ErrorKind::InvalidInput, format!("Invalid target {}: {}", target, e)))
+    });
+    let session = StreamSession::create_persistent_in(&sam, &store, keys, &[]).unwrap_or_else(|e| output.fail(&e));
+    let address = b32(&Destination::new(session.local_dest()));
+    let mut tunnel = ServerTunnel::new(session, target).inject_headers(http);
+    if let Some(max) = max_per_peer {
+        tunnel = tunnel.max_connections_per_peer(max);
+    }
+    output.print(json!({ "target": target.to_string(), "address": address }), &format!("Publishing {} at {}", target, address));
+    if let Err(e) = tunnel.serve() {
+        output.fail(&e);
+    }
+}
+
+fn tunnels(path: &str, m: &ArgMatches, sam: SamConfig, output: Output) {
+    let config = TunnelsConfig::load(Path::new(path)).unwrap_or_else(|e| output.fail(&e));
+    let aliases: Vec<&str> = config.tunnels.values().filter_map(|tunnel| tunnel.keys.as_deref()).collect();
+    let store = key_store(m, &aliases).unwrap_or_else(|e| output.fail(&e));
+    let mut manager = TunnelManager::new(sam).with_key_store(store);
+    if let Err(e) = manager.apply(&config) {
+        output.fail(&e);
+    }
+    print_tunnel_status(&manager, output);
+    wait_for_signals(path, manager, output);
+}
+
+fn print_tunnel_status(manager: &TunnelManager, output: Output) {
+    let status = manager.status();
+    let json = status.iter().map(|status| {
+        let stats = &status.stats;
+        json!({
+            "name": status.name,
+            "type": status.kind.to_string(),
+            "address": status.address,
+            "state": match &status.state {
+                TunnelState::Running => "running",
+                TunnelState::Failed(_) => "failed"
+            },
+            "error": match &status.state {
+                TunnelState::Running => None,
+                TunnelState::Failed(e) => Some(e)
+            },
+            "connections": stats.connections,
+            "active": stats.active,
+            "failed": stats.failed,
+            "sent": stats.sent,
+            "received": stats.received,
+        })
+    }).collect::<Vec<_>>();
+    let text = status.iter().map(|status| {
+        let stats = &status.stats;
+        format!("{:<16} {:<10} {:<60} {} (connections: {}, active: {}, failed: {}, sent: {}, received: {})",
+                status.name, status.kind.to_string(), status.address, status.state,
+                stats.connections, stats.active, stats.failed, stats.sent, stats.received)
+    }).collect::<Vec<_>>();
+    output.print(json!({ "tunnels": json }), &text.join("\n"));
+}
+
+#[cfg(unix)]
+fn wait_for_signals(path: &str, mut manager: TunnelManager, output: Output) {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGHUP, SIGUSR1, SIGINT, SIGTERM]).unwrap_or_else(|e| output.fail(&e));
+    for signal in signals.forever() {
+        match signal {
+            SIGHUP => {
+                if output == Output::Text {
+                    println!("Reloading {}", path);
+                }
+                match TunnelsConfig::load(Path::new(path)).and_then(|config| manager.apply(&config)) {
+                    Ok(_) => print_tunnel_status(&manager, output),
+                    Err(e) => output.report(&io::Error::new(e.kind(), format!("Keeping the running tunnels: {}", e)))
+                }
+            },
+            SIGUSR1 => print_tunnel_status(&manager, output),
+            _ => break
+        }
+    }
+    manager.stop();
+}
+
+#[cfg(not(unix))]
+fn wait_for_signals(_path: &str, _manager: TunnelManager, _output: Output) {
+    loop {
+        std::thread::park();
+    }
+}
+
+fn http_proxy(listen: &str, sam: SamConfig, alias: &str, output: Output) {
+    let listener = bind(listen, output);
+    let session = StreamSession::create(&sam, "TRANSIENT", alias, &[]).unwrap_or_else(|e| output.fail(&e));
+    output.print(json!({ "listen": listen }), &format!("HTTP proxy listening on {}", listen));
+    if let Err(e) = HttpProxy::new(session).serve(listener) {
+        output.fail(&e);
+    }
+}
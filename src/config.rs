@@ -0,0 +1,254 @@
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::info;
+
+pub static DEFAULT_SAM_HOST: &str = "127.0.0.1";
+pub const DEFAULT_SAM_PORT: u16 = 7656;
+pub const DEFAULT_SAM_UDP_PORT: u16 = 7655;
+pub static DEFAULT_MIN_VERSION: &str = "3.0";
+pub static DEFAULT_MAX_VERSION: &str = "3.3";
+
+/// Timeouts on SAM sockets; `None` blocks indefinitely.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// Establishing the TCP connection to the SAM bridge
+    pub connect: Option<Duration>,
+    /// Waiting for HELLO REPLY
+    pub handshake: Option<Duration>,
+    /// Waiting for replies to commands (SESSION CREATE may wait on tunnels) and writing commands
+    pub reply: Option<Duration>,
+    /// Waiting for the next datagram, or for data on an established stream
+    pub idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Some(Duration::from_secs(30)),
+            handshake: Some(Duration::from_secs(30)),
+            reply: Some(Duration::from_secs(300)),
+            idle: None,
+        }
+    }
+}
+
+static CONFIG_DIR: &str = "i2p_client";
+static CONFIG_FILE: &str = "i2p_client.config";
+
+/// How to reach the SAM bridge: TCP endpoint for control/stream sockets, UDP endpoint for datagrams,
+/// credentials and the SAM versions offered in the handshake.
+///
+/// Resolved in increasing order of precedence from the defaults (`127.0.0.1:7656`, UDP `7655`),
+/// the config file, the `SAM_HOST`/`SAM_PORT`/`SAM_UDP_HOST`/`SAM_UDP_PORT`/`SAM_USER`/`SAM_PASSWORD`
+/// environment variables and finally whatever the caller sets explicitly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamConfig {
+    pub host: String,
+    pub port: u16,
+    /// Host of the UDP port datagrams are sent to; the TCP host is used when not set
+    pub udp_host: Option<String>,
+    pub udp_port: u16,
+    /// SAMv3.2 authentication user, sent in HELLO when set
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub min_version: String,
+    pub max_version: String,
+    pub timeouts: Timeouts,
+}
+
+impl Default for SamConfig {
+    fn default() -> Self {
+        SamConfig {
+            host: String::from(DEFAULT_SAM_HOST),
+            port: DEFAULT_SAM_PORT,
+            udp_host: None,
+            udp_port: DEFAULT_SAM_UDP_PORT,
+            user: None,
+            password: None,
+            min_version: String::from(DEFAULT_MIN_VERSION),
+            max_version: String::from(DEFAULT_MAX_VERSION),
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+impl SamConfig {
+    pub fn new(host: &str, port: u16) -> SamConfig {
+        SamConfig {
+            host: String::from(host),
+            port,
+            ..SamConfig::default()
+        }
+    }
+
+    /// TCP endpoint as `host:port`
+    pub fn tcp_addr(&self) -> String {
+        host_port(&self.host, self.port)
+    }
+
+    /// UDP endpoint datagrams are sent to, as `host:port`
+    pub fn udp_addr(&self) -> String {
+        host_port(self.udp_host.as_ref().unwrap_or(&self.host), self.udp_port)
+    }
+
+    /// Default config file: `<config dir>/i2p_client/i2p_client.config`, e.g. `~/.config/i2p_client/i2p_client.config` on Linux
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|mut p| {
+            p.push(CONFIG_DIR);
+            p.push(CONFIG_FILE);
+            p
+        })
+    }
+
+    /// Load defaults, then the config file (`path` if provided, which must exist, otherwise the
+    /// default file if present), then the environment.
+    pub fn load(path: Option<&Path>) -> Result<SamConfig, Error> {
+        let mut config = SamConfig::default();
+        match path {
+            Some(p) => config.apply_file(p)?,
+            None => {
+                if let Some(p) = SamConfig::default_path().filter(|p| p.exists()) {
+                    config.apply_file(&p)?;
+                }
+            }
+        }
+        config.apply_vars(|k| env::var(k).ok())?;
+        Ok(config)
+    }
+
+    /// Defaults overridden by the environment only.
+    pub fn from_env() -> Result<SamConfig, Error> {
+        let mut config = SamConfig::default();
+        config.apply_vars(|k| env::var(k).ok())?;
+        Ok(config)
+    }
+
+    /// Apply a config file of `key=value` lines; recognized keys are `sam.host`, `sam.port`,
+    /// `sam.udp.host`, `sam.udp.port`, `sam.user`, `sam.password`, `sam.min_version`, `sam.max_version`
+    /// and the timeouts in seconds (0 to block indefinitely) `sam.timeout.connect`, `sam.timeout.handshake`,
+    /// `sam.timeout.reply` and `sam.timeout.idle`.
+    pub fn apply_file(&mut self, path: &Path) -> Result<(), Error> {
+        info!("sam config file: {}", path.display());
+        let file = File::open(path)?;
+        self.apply_config(BufReader::new(file))
+    }
+
+    fn apply_config<R: BufRead>(&mut self, reader: R) -> Result<(), Error> {
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid config line: {}", line)))
+            };
+            match key {
+                "sam.host" => self.host = String::from(value),
+                "sam.port" => self.port = parse_port(key, value)?,
+                "sam.udp.host" => self.udp_host = Some(String::from(value)),
+                "sam.udp.port" => self.udp_port = parse_port(key, value)?,
+                "sam.user" => self.user = Some(String::from(value)),
+                "sam.password" => self.password = Some(String::from(value)),
+                "sam.min_version" => self.min_version = String::from(value),
+                "sam.max_version" => self.max_version = String::from(value),
+                "sam.timeout.connect" => self.timeouts.connect = parse_timeout(key, value)?,
+                "sam.timeout.handshake" => self.timeouts.handshake = parse_timeout(key, value)?,
+                "sam.timeout.reply" => self.timeouts.reply = parse_timeout(key, value)?,
+                "sam.timeout.idle" => self.timeouts.idle = parse_timeout(key, value)?,
+                _ => info!("Ignoring unknown config key: {}", key)
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_vars<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), Error> {
+        if let Some(host) = var("SAM_HOST") {
+            self.host = host;
+        }
+        if let Some(port) = var("SAM_PORT") {
+            self.port = parse_port("SAM_PORT", &port)?;
+        }
+        if let Some(host) = var("SAM_UDP_HOST") {
+            self.udp_host = Some(host);
+        }
+        if let Some(port) = var("SAM_UDP_PORT") {
+            self.udp_port = parse_port("SAM_UDP_PORT", &port)?;
+        }
+        if let Some(user) = var("SAM_USER") {
+            self.user = Some(user);
+        }
+        if let Some(password) = var("SAM_PASSWORD") {
+            self.password = Some(password);
+        }
+        Ok(())
+    }
+}
+
+/// `host:port`, bracketing IPv6 literals
+fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn parse_port(key: &str, value: &str) -> Result<u16, Error> {
+    value.parse().map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid port for {}: {}", key, value)))
+}
+
+fn parse_timeout(key: &str, value: &str) -> Result<Option<Duration>, Error> {
+    match value.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+        Err(_) => Err(Error::new(ErrorKind::InvalidInput, format!("Invalid timeout in seconds for {}: {}", key, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind};
+    use std::time::Duration;
+    use crate::config::SamConfig;
+
+    #[test]
+    fn defaults() {
+        let config = SamConfig::default();
+        assert_eq!(config.tcp_addr(), "127.0.0.1:7656");
+        assert_eq!(config.udp_addr(), "127.0.0.1:7655");
+        assert_eq!(SamConfig::new("::1", 7656).tcp_addr(), "[::1]:7656");
+    }
+
+    #[test]
+    fn config_then_env() {
+        let mut config = SamConfig::default();
+        config.apply_config(Cursor::new("# router in a container\nsam.host = 172.17.0.2\nsam.udp.port=17655\nsam.timeout.reply=0\nsam.timeout.idle=60\n")).unwrap();
+        assert_eq!(config.timeouts.reply, None);
+        assert_eq!(config.timeouts.idle, Some(Duration::from_secs(60)));
+        assert_eq!(config.tcp_addr(), "172.17.0.2:7656");
+        assert_eq!(config.udp_addr(), "172.17.0.2:17655");
+
+        config.apply_vars(|k| match k {
+            "SAM_PORT" => Some(String::from("17656")),
+            "SAM_UDP_HOST" => Some(String::from("10.0.0.1")),
+            _ => None
+        }).unwrap();
+        assert_eq!(config.tcp_addr(), "172.17.0.2:17656");
+        assert_eq!(config.udp_addr(), "10.0.0.1:17655");
+    }
+
+    #[test]
+    fn invalid() {
+        let mut config = SamConfig::default();
+        let err = config.apply_config(Cursor::new("sam.port=http\n")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = config.apply_config(Cursor::new("sam.port\n")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,317 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
+/// Public key (256 bytes) + signing key (128 bytes) + certificate type (1) and length (2)
+const MIN_DEST_LEN: usize = 387;
+/// Certificate type giving the signature and encryption types
+const KEY_CERT: u8 = 5;
+
+/// An I2P destination as used in SAM commands: the full base64 destination or a name the
+/// router can resolve (e.g. `example.i2p`, `<hash>.b32.i2p`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Destination(String);
+
+impl Destination {
+    pub fn new(dest: &str) -> Destination {
+        Destination(String::from(dest))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether this is a full base64 destination rather than a name to look up
+    pub fn is_base64(&self) -> bool {
+        self.dest_bytes().is_some()
+    }
+
+    /// Whether this is a `<hash>.b32.i2p` address
+    pub fn is_b32(&self) -> bool {
+        self.0.ends_with(".b32.i2p")
+    }
+
+    /// The `<hash>.b32.i2p` address: computed for full destinations, as is for b32 addresses,
+    /// None for other names.
+    pub fn to_b32(&self) -> Option<String> {
+        if self.is_b32() {
+            return Some(self.0.clone());
+        }
+        self.dest_bytes().map(|bytes| format!("{}.b32.i2p", base32(&Sha256::digest(&bytes))))
+    }
+
+    /// The public destination of a full destination that has private keys appended, e.g. the
+    /// keys saved for an alias; None for names.
+    pub fn public(&self) -> Option<Destination> {
+        self.dest_bytes().map(|bytes| Destination(encode_i2p_base64(&bytes)))
+    }
+
+    /// Signature type code from the key certificate, e.g. 7 for EdDSA-SHA512-Ed25519; 0 (DSA-SHA1)
+    /// without one and None for names.
+    pub fn sig_type(&self) -> Option<u16> {
+        let bytes = self.dest_bytes()?;
+        match bytes[384] {
+            KEY_CERT if bytes.len() >= MIN_DEST_LEN + 2 => Some(u16::from_be_bytes([bytes[387], bytes[388]])),
+            _ => Some(0)
+        }
+    }
+
+    /// Decoded destination without any trailing private keys
+    pub(crate) fn dest_bytes(&self) -> Option<Vec<u8>> {
+        let bytes = decode_i2p_base64(&self.0)?;
+        if bytes.len() < MIN_DEST_LEN {
+            return None;
+        }
+        let cert_len = u16::from_be_bytes([bytes[385], bytes[386]]) as usize;
+        let len = MIN_DEST_LEN + cert_len;
+        if bytes.len() < len {
+            return None;
+        }
+        Some(bytes[..len].to_vec())
+    }
+}
+
+/// Decode base64 in the I2P alphabet, which uses `-` and `~` in place of `+` and `/`
+pub(crate) fn decode_i2p_base64(s: &str) -> Option<Vec<u8>> {
+    if s.contains(['+', '/']) {
+        return None;
+    }
+    base64::decode(s.replace('-', "+").replace('~', "/")).ok()
+}
+
+/// Encode base64 in the I2P alphabet
+pub(crate) fn encode_i2p_base64(bytes: &[u8]) -> String {
+    base64::encode(bytes).replace('+', "-").replace('/', "~")
+}
+
+/// RFC 4648 base32, lower case and unpadded as in b32 addresses
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for Destination {
+    fn from(dest: String) -> Destination {
+        Destination(dest)
+    }
+}
+
+impl From<&str> for Destination {
+    fn from(dest: &str) -> Destination {
+        Destination::new(dest)
+    }
+}
+
+impl AsRef<str> for Destination {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A destination and port, the I2P counterpart of `SocketAddr`; written as `dest:port`,
+/// e.g. `name.b32.i2p:80`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct I2pSocketAddr {
+    dest: Destination,
+    port: u16,
+}
+
+impl I2pSocketAddr {
+    pub fn new(dest: Destination, port: u16) -> I2pSocketAddr {
+        I2pSocketAddr { dest, port }
+    }
+
+    pub fn dest(&self) -> &Destination {
+        &self.dest
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+}
+
+impl fmt::Display for I2pSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.dest, self.port)
+    }
+}
+
+impl FromStr for I2pSocketAddr {
+    type Err = Error;
+
+    /// Parse `dest:port`; base64 destinations never contain `:`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid I2P socket address: {}", s));
+        let i = s.rfind(':').ok_or_else(invalid)?;
+        let (dest, port) = (&s[..i], &s[i + 1..]);
+        if dest.is_empty() {
+            return Err(invalid());
+        }
+        let port = port.parse().map_err(|_| invalid())?;
+        Ok(I2pSocketAddr::new(Destination::new(dest), port))
+    }
+}
+
+/// Conversion into the I2P socket addresses to try, the counterpart of `std::net::ToSocketAddrs`.
+/// Names and b32 addresses are resolved when connecting or sending, see [`Resolver`](crate::Resolver).
+///
+/// Strings are `dest:port` or just `dest`, meaning port 0 (any); a bare [`Destination`] also means port 0.
+pub trait ToI2pSocketAddrs {
+    type Iter: Iterator<Item = I2pSocketAddr>;
+
+    fn to_i2p_socket_addrs(&self) -> Result<Self::Iter, Error>;
+}
+
+impl ToI2pSocketAddrs for I2pSocketAddr {
+    type Iter = std::option::IntoIter<I2pSocketAddr>;
+
+    fn to_i2p_socket_addrs(&self) -> Result<Self::Iter, Error> {
+        Ok(Some(self.clone()).into_iter())
+    }
+}
+
+impl ToI2pSocketAddrs for Destination {
+    type Iter = std::option::IntoIter<I2pSocketAddr>;
+
+    fn to_i2p_socket_addrs(&self) -> Result<Self::Iter, Error> {
+        Ok(Some(I2pSocketAddr::new(self.clone(), 0)).into_iter())
+    }
+}
+
+impl ToI2pSocketAddrs for (Destination, u16) {
+    type Iter = std::option::IntoIter<I2pSocketAddr>;
+
+    fn to_i2p_socket_addrs(&self) -> Result<Self::Iter, Error> {
+        Ok(Some(I2pSocketAddr::new(self.0.clone(), self.1)).into_iter())
+    }
+}
+
+impl ToI2pSocketAddrs for (&str, u16) {
+    type Iter = std::option::IntoIter<I2pSocketAddr>;
+
+    fn to_i2p_socket_addrs(&self) -> Result<Self::Iter, Error> {
+        Ok(Some(I2pSocketAddr::new(Destination::new(self.0), self.1)).into_iter())
+    }
+}
+
+impl ToI2pSocketAddrs for str {
+    type Iter = std::option::IntoIter<I2pSocketAddr>;
+
+    fn to_i2p_socket_addrs(&self) -> Result<Self::Iter, Error> {
+        if self.contains(':') {
+            return self.parse().map(|addr| Some(addr).into_iter());
+        }
+        if self.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Empty I2P destination"));
+        }
+        Ok(Some(I2pSocketAddr::new(Destination::new(self), 0)).into_iter())
+    }
+}
+
+impl ToI2pSocketAddrs for String {
+    type Iter = std::option::IntoIter<I2pSocketAddr>;
+
+    fn to_i2p_socket_addrs(&self) -> Result<Self::Iter, Error> {
+        self.as_str().to_i2p_socket_addrs()
+    }
+}
+
+impl<T: ToI2pSocketAddrs + ?Sized> ToI2pSocketAddrs for &T {
+    type Iter = T::Iter;
+
+    fn to_i2p_socket_addrs(&self) -> Result<Self::Iter, Error> {
+        (**self).to_i2p_socket_addrs()
+    }
+}
+
+/// Run `f` on each address in turn until one succeeds, like `TcpStream::connect` does
+pub(crate) fn each_addr<A, F, T>(addr: A, mut f: F) -> Result<T, Error>
+    where
+        A: ToI2pSocketAddrs,
+        F: FnMut(&I2pSocketAddr) -> Result<T, Error>,
+{
+    let mut last_err = None;
+    for addr in addr.to_i2p_socket_addrs()? {
+        match f(&addr) {
+            Ok(t) => return Ok(t),
+            Err(e) => last_err = Some(e)
+        }
+    }
+    Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::InvalidInput, "No I2P addresses to try")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::destination::{base32, Destination, I2pSocketAddr, ToI2pSocketAddrs};
+
+    #[test]
+    fn b32() {
+        assert_eq!(base32(b"foobar"), "mzxw6ytboi");
+        // 384 bytes of keys and a NULL certificate, followed by private keys that are not hashed
+        let mut bytes = vec![7u8; 384];
+        bytes.extend_from_slice(&[0, 0, 0]);
+        let b64 = base64::encode(&bytes).replace('+', "-").replace('/', "~");
+        let dest = Destination::new(&b64);
+        assert!(dest.is_base64());
+        let b32 = dest.to_b32().unwrap();
+        bytes.extend_from_slice(&[1u8; 256]);
+        let full = Destination::from(base64::encode(&bytes).replace('+', "-").replace('/', "~"));
+        assert_eq!(full.to_b32().unwrap(), b32);
+        assert_eq!(b32.len(), 52 + ".b32.i2p".len());
+        assert_eq!(Destination::new(&b32).to_b32().unwrap(), b32);
+        assert!(!Destination::new("example.i2p").is_base64());
+        assert_eq!(Destination::new("example.i2p").to_b32(), None);
+        assert_eq!(full.public(), Some(dest.clone()));
+        assert_eq!(full.sig_type(), Some(0));
+        assert_eq!(Destination::new("example.i2p").sig_type(), None);
+
+        // Key certificate for EdDSA-SHA512-Ed25519 and ElGamal
+        let mut bytes = vec![7u8; 384];
+        bytes.extend_from_slice(&[5, 0, 4, 0, 7, 0, 0]);
+        assert_eq!(Destination::from(base64::encode(&bytes).replace('+', "-").replace('/', "~")).sig_type(), Some(7));
+    }
+
+    #[test]
+    fn socket_addr() {
+        let addr: I2pSocketAddr = "name.b32.i2p:80".parse().unwrap();
+        assert_eq!(addr.dest().as_str(), "name.b32.i2p");
+        assert_eq!(addr.port(), 80);
+        assert_eq!(addr.to_string(), "name.b32.i2p:80");
+        assert!("name.b32.i2p".parse::<I2pSocketAddr>().is_err());
+        assert!(":80".parse::<I2pSocketAddr>().is_err());
+        assert!("name.i2p:http".parse::<I2pSocketAddr>().is_err());
+
+        let addrs: Vec<I2pSocketAddr> = "example.i2p:8080".to_i2p_socket_addrs().unwrap().collect();
+        assert_eq!(addrs, vec![I2pSocketAddr::new(Destination::new("example.i2p"), 8080)]);
+        assert_eq!("AAAA~-".to_i2p_socket_addrs().unwrap().next().unwrap().port(), 0);
+        assert_eq!(("example.i2p", 80).to_i2p_socket_addrs().unwrap().next().unwrap().to_string(), "example.i2p:80");
+        assert_eq!(Destination::new("x.b32.i2p").to_i2p_socket_addrs().unwrap().next().unwrap().to_string(), "x.b32.i2p:0");
+        assert!("".to_i2p_socket_addrs().is_err());
+    }
+}
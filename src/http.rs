@@ -0,0 +1,304 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use log::{debug, info, warn};
+
+use crate::{pipe, Destination, StreamSession};
+
+/// Longest request or response head accepted
+pub(crate) const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// Headers that identify the user or only concern the hop to the proxy
+static STRIPPED_HEADERS: &[&str] = &[
+    "user-agent", "referer", "x-forwarded-for", "forwarded", "via", "from",
+    "proxy-connection", "proxy-authorization", "connection", "keep-alive",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Target {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+}
+
+#[derive(Debug, PartialEq)]
+enum ProxyRequest {
+    /// CONNECT tunnel
+    Connect(Target),
+    /// Request rewritten to origin form, to be sent as is
+    Forward(Target, String),
+}
+
+/// Status and explanation for an error page
+#[derive(Debug, PartialEq)]
+struct HttpError {
+    status: u16,
+    reason: &'static str,
+    detail: String,
+}
+
+impl HttpError {
+    fn new(status: u16, reason: &'static str, detail: String) -> HttpError {
+        HttpError { status, reason, detail }
+    }
+}
+
+/// HTTP proxy for eepsites: forwards absolute-form requests and CONNECT tunnels for `.i2p` hosts over
+/// a shared STREAM session, stripping identifying headers. One request is served per client
+/// connection (`Connection: close`).
+pub struct HttpProxy {
+    session: StreamSession,
+}
+
+impl HttpProxy {
+    pub fn new(session: StreamSession) -> HttpProxy {
+        HttpProxy { session }
+    }
+
+    /// Accept clients on `listener` until it fails, handling each on its own thread.
+    pub fn serve(self, listener: TcpListener) -> Result<(), Error> {
+        info!("HTTP proxy listening on {}", listener.local_addr()?);
+        let proxy = Arc::new(self);
+        for client in listener.incoming() {
+            let client = client?;
+            let proxy = proxy.clone();
+            thread::spawn(move || {
+                let peer = client.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                if let Err(e) = proxy.handle(client) {
+                    debug!("HTTP client {}: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Serve a single client connection.
+    pub fn handle(&self, mut client: TcpStream) -> Result<(), Error> {
+        let mut reader = BufReader::new(client.try_clone()?);
+        let head = read_head(&mut reader)?;
+        if head.is_empty() {
+            return Ok(());
+        }
+        let request = match parse_request(&head) {
+            Ok(request) => request,
+            Err(e) => return send_error(&mut client, e)
+        };
+        let target = match &request {
+            ProxyRequest::Connect(target) | ProxyRequest::Forward(target, _) => target
+        };
+        info!("{} {}:{}", head[0].split(' ').next().unwrap_or_default(), target.host, target.port);
+        let dest = match self.session.resolve(&Destination::new(&target.host)) {
+            Ok(dest) => dest,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return send_error(&mut client, HttpError::new(404, "Not Found",
+                    format!("The eepsite {} is not in the address book and could not be looked up.", target.host)));
+            },
+            Err(e) => return send_error(&mut client, connect_error(&target.host, &e))
+        };
+        let mut stream = match self.session.connect((dest, target.port)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Unable to reach {}:{}: {}", target.host, target.port, e);
+                return send_error(&mut client, connect_error(&target.host, &e));
+            }
+        };
+        match &request {
+            ProxyRequest::Connect(_) => client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?,
+            ProxyRequest::Forward(_, head) => stream.write_all(head.as_bytes())?
+        }
+        // Request body the client sent along with the head
+        stream.write_all(reader.buffer())?;
+        drop(reader);
+        pipe(client, stream)?;
+        Ok(())
+    }
+}
+
+fn connect_error(host: &str, e: &Error) -> HttpError {
+    match e.kind() {
+        ErrorKind::TimedOut => HttpError::new(504, "Gateway Timeout", format!("The eepsite {} did not respond in time.", host)),
+        _ => HttpError::new(502, "Bad Gateway", format!("The eepsite {} is unreachable: {}", host, e))
+    }
+}
+
+fn send_error(client: &mut TcpStream, e: HttpError) -> Result<(), Error> {
+    debug!("{} {}: {}", e.status, e.reason, e.detail);
+    client.write_all(error_page(&e).as_bytes())?;
+    let _ = client.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+fn error_page(e: &HttpError) -> String {
+    let body = format!("<html><head><title>{status} {reason}</title></head><body><h1>{reason}</h1><p>{detail}</p></body></html>\n",
+                       status = e.status, reason = e.reason, detail = escape_html(&e.detail));
+    format!("HTTP/1.1 {} {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            e.status, e.reason, body.len(), body)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Read a request or response head up to the empty line, returning its lines without line endings;
+/// empty if the peer closed before sending anything.
+pub(crate) fn read_head<R: BufRead>(reader: &mut R) -> Result<Vec<String>, Error> {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut buf = Vec::new();
+        let n = reader.by_ref().take((MAX_HEAD_LEN - total) as u64 + 1).read_until(b'\n', &mut buf)?;
+        total += n;
+        if total > MAX_HEAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, format!("HTTP head exceeds {} bytes", MAX_HEAD_LEN)));
+        }
+        if n == 0 {
+            if lines.is_empty() {
+                return Ok(lines);
+            }
+            return Err(Error::new(ErrorKind::UnexpectedEof, "HTTP head truncated"));
+        }
+        let line = String::from_utf8(buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            if lines.is_empty() {
+                // Stray empty lines before a request are allowed (RFC 7230 3.5)
+                continue;
+            }
+            return Ok(lines);
+        }
+        lines.push(String::from(line));
+    }
+}
+
+/// Read a single line of a body, e.g. a chunk size, of at most `MAX_HEAD_LEN` bytes
+pub(crate) fn read_line<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+    let mut buf = Vec::new();
+    let n = reader.by_ref().take(MAX_HEAD_LEN as u64).read_until(b'\n', &mut buf)?;
+    if buf.last() != Some(&b'\n') {
+        if n == MAX_HEAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, format!("HTTP line exceeds {} bytes", MAX_HEAD_LEN)));
+        }
+        return Err(Error::new(ErrorKind::UnexpectedEof, "HTTP body truncated"));
+    }
+    String::from_utf8(buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// `host[:port]`, None if the port is invalid
+pub(crate) fn parse_authority(authority: &str, default_port: u16) -> Option<Target> {
+    let (host, port) = match authority.rfind(':') {
+        Some(i) => (&authority[..i], authority[i + 1..].parse().ok()?),
+        None => (authority, default_port)
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(Target { host: host.to_ascii_lowercase(), port })
+}
+
+/// Whether `host` is a `.i2p` name (b32 included); the proxies refuse anything else
+pub(crate) fn is_i2p_host(host: &str) -> bool {
+    host.len() > ".i2p".len() && host.to_ascii_lowercase().ends_with(".i2p")
+}
+
+fn parse_request(head: &[String]) -> Result<ProxyRequest, HttpError> {
+    let bad_request = |detail: &str| HttpError::new(400, "Bad Request", String::from(detail));
+    let parts: Vec<&str> = head[0].split(' ').collect();
+    if parts.len() != 3 || !parts[2].starts_with("HTTP/") {
+        return Err(bad_request("Malformed request line."));
+    }
+    let (method, uri, version) = (parts[0], parts[1], parts[2]);
+    let not_i2p = |host: &str| HttpError::new(403, "Forbidden", format!("{} is not an I2P host; only .i2p sites are reachable through this proxy.", host));
+
+    if method == "CONNECT" {
+        let target = parse_authority(uri, 443).ok_or_else(|| bad_request("Malformed CONNECT target."))?;
+        if !is_i2p_host(&target.host) {
+            return Err(not_i2p(&target.host));
+        }
+        return Ok(ProxyRequest::Connect(target));
+    }
+
+    if uri.len() < 7 || !uri[..7].eq_ignore_ascii_case("http://") {
+        return Err(bad_request("Requests must use an absolute http:// URL; configure this as an HTTP proxy."));
+    }
+    let rest = &uri[7..];
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    };
+    let target = parse_authority(authority, 80).ok_or_else(|| bad_request("Malformed URL."))?;
+    if !is_i2p_host(&target.host) {
+        return Err(not_i2p(&target.host));
+    }
+
+    let mut out = format!("{} {} {}\r\n", method, path, version);
+    if target.port == 80 {
+        out.push_str(&format!("Host: {}\r\n", target.host));
+    } else {
+        out.push_str(&format!("Host: {}:{}\r\n", target.host, target.port));
+    }
+    for line in &head[1..] {
+        let name = line.split(':').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if name == "host" || STRIPPED_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out.push_str("Connection: close\r\n\r\n");
+    Ok(ProxyRequest::Forward(target, out))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use crate::http::{parse_request, read_head, HttpProxy, ProxyRequest, Target};
+    use crate::tests::fake_bridge;
+    use crate::{SamConfig, StreamSession};
+
+    fn head(s: &str) -> Vec<String> {
+        read_head(&mut Cursor::new(s)).unwrap()
+    }
+
+    #[test]
+    fn rewrite() {
+        let request = parse_request(&head("GET http://Example.i2p:8080/a?b=c HTTP/1.1\r\nHost: example.i2p:8080\r\nUser-Agent: curl/8\r\nReferer: http://x.i2p/\r\nX-Forwarded-For: 10.0.0.1\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n")).unwrap();
+        assert_eq!(request, ProxyRequest::Forward(
+            Target { host: String::from("example.i2p"), port: 8080 },
+            String::from("GET /a?b=c HTTP/1.1\r\nHost: example.i2p:8080\r\nAccept: */*\r\nConnection: close\r\n\r\n")));
+
+        assert_eq!(parse_request(&head("CONNECT example.i2p:443 HTTP/1.1\r\n\r\n")).unwrap(),
+                   ProxyRequest::Connect(Target { host: String::from("example.i2p"), port: 443 }));
+        assert_eq!(parse_request(&head("GET http://example.com/ HTTP/1.1\r\n\r\n")).unwrap_err().status, 403);
+        assert_eq!(parse_request(&head("GET / HTTP/1.1\r\n\r\n")).unwrap_err().status, 400);
+        assert_eq!(parse_request(&head("GET http://example.i2p:x/ HTTP/1.1\r\n\r\n")).unwrap_err().status, 400);
+        assert!(read_head(&mut Cursor::new("GET / HTTP/1.1\r\nHost: x")).is_err());
+        assert!(read_head(&mut Cursor::new("")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn proxy() {
+        let bridge = fake_bridge();
+        let session = StreamSession::create(&SamConfig::new("127.0.0.1", bridge.port), "TRANSIENT", "Http", &[]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || HttpProxy::new(session).serve(listener));
+
+        // The fake bridge echoes the stream, so the client reads back what reached the eepsite
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET http://example.i2p/ HTTP/1.1\r\nUser-Agent: curl/8\r\n\r\n").unwrap();
+        let expected = "GET / HTTP/1.1\r\nHost: example.i2p\r\nConnection: close\r\n\r\n";
+        let mut buf = vec![0u8; expected.len()];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET http://example.org/ HTTP/1.1\r\n\r\n").unwrap();
+        let mut page = String::new();
+        client.read_to_string(&mut page).unwrap();
+        assert!(page.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(page.contains("example.org is not an I2P host"));
+    }
+}
@@ -0,0 +1,734 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use log::info;
+use zeroize::Zeroizing;
+
+use crate::destination::{decode_i2p_base64, encode_i2p_base64};
+use crate::Destination;
+
+/// Key files larger than this are not private keys; keeps `list` from reading unrelated files
+const MAX_KEYS_LEN: u64 = 16 * 1024;
+const MAX_ALIAS_LEN: usize = 128;
+
+/// Distinguishes temp files of concurrent writes within this process
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// First field of encrypted key files, followed by the format version
+const ENCRYPTED: &str = "i2p_client-encrypted-keys";
+/// Argon2id memory (KiB), iterations and parallelism for new files, as recommended by OWASP;
+/// files record their own
+const KDF_PARAMS: (u32, u32, u32) = (19 * 1024, 2, 1);
+/// Highest memory (1 GiB), iterations and parallelism accepted from a file, so a crafted one
+/// cannot make opening it take all memory or hours
+const MAX_KDF_PARAMS: (u32, u32, u32) = (1024 * 1024, 16, 16);
+const SALT_LEN: usize = 16;
+
+/// What the key store knows about the keys saved under an alias
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyInfo {
+    pub alias: String,
+    /// Public destination
+    pub dest: Destination,
+    pub b32: String,
+    /// e.g. EDDSA_SHA512_ED25519
+    pub sig_type: String,
+    /// When the keys were saved, if the file system tells
+    pub created: Option<SystemTime>,
+    /// Whether the private keys are encrypted with a passphrase
+    pub encrypted: bool,
+}
+
+impl KeyInfo {
+    /// Creation time as e.g. `2020-03-12T09:30:00Z`
+    pub fn created_utc(&self) -> Option<String> {
+        self.created.map(format_utc)
+    }
+}
+
+/// Private keys of local destinations, one file per alias holding the full destination (public
+/// destination and private keys, as returned by `DEST GENERATE`) in I2P base64.
+///
+/// The directory is only accessible by the user and key files are only readable by the user (on
+/// unix); they are written to a temp file that is synced and then renamed, so a crash never leaves
+/// half written keys behind.
+///
+/// With a passphrase ([`KeyStore::with_passphrase`]) keys are saved encrypted with ChaCha20-Poly1305
+/// under a key derived by Argon2id; the public destination stays readable so listing keys needs
+/// no passphrase. Decrypted keys are returned in [`Zeroizing`] strings, wiped when dropped.
+#[derive(Clone)]
+pub struct KeyStore {
+    dir: PathBuf,
+    legacy_dir: Option<PathBuf>,
+    passphrase: Option<Zeroizing<String>>,
+    kdf_params: (u32, u32, u32),
+}
+
+impl fmt::Debug for KeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyStore")
+            .field("dir", &self.dir)
+            .field("legacy_dir", &self.legacy_dir)
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+/// Content of a key file
+enum KeyFile {
+    Plain(Zeroizing<String>),
+    Encrypted(EncryptedKeys),
+}
+
+impl KeyStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> KeyStore {
+        KeyStore { dir: dir.into(), legacy_dir: None, passphrase: None, kdf_params: KDF_PARAMS }
+    }
+
+    /// The key store in the user's data directory, e.g. ~/.local/share/i2p_client/keys, taking
+    /// over keys saved in ~/.i2p by earlier versions
+    pub fn open_default() -> Result<KeyStore, Error> {
+        let data = dirs::data_dir().ok_or_else(|| Error::new(ErrorKind::NotFound, "No data directory"))?;
+        let mut store = KeyStore::new(data.join("i2p_client").join("keys"));
+        if let Some(home) = dirs::home_dir() {
+            store = store.migrate_from(home.join(".i2p"));
+        }
+        Ok(store)
+    }
+
+    /// Move keys found in `legacy_dir` (files named after their alias, as in ~/.i2p) into this store
+    /// when their alias is first used
+    pub fn migrate_from<P: Into<PathBuf>>(mut self, legacy_dir: P) -> KeyStore {
+        self.legacy_dir = Some(legacy_dir.into());
+        self
+    }
+
+    /// Decrypt keys with `passphrase` and encrypt the keys saved from now on
+    pub fn with_passphrase<S: Into<String>>(mut self, passphrase: S) -> KeyStore {
+        self.passphrase = Some(Zeroizing::new(passphrase.into()));
+        self
+    }
+
+    pub fn has_passphrase(&self) -> bool {
+        self.passphrase.is_some()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// File holding the keys of `alias`; fails with `ErrorKind::InvalidInput` for aliases that
+    /// are not a plain file name, e.g. `../x`
+    pub fn path(&self, alias: &str) -> Result<PathBuf, Error> {
+        check_alias(alias)?;
+        Ok(self.dir.join(alias))
+    }
+
+    pub fn contains(&self, alias: &str) -> bool {
+        self.read(alias).is_ok()
+    }
+
+    /// Whether the keys of `alias` need a passphrase
+    pub fn is_encrypted(&self, alias: &str) -> Result<bool, Error> {
+        Ok(matches!(self.read(alias)?, KeyFile::Encrypted(_)))
+    }
+
+    /// Keys of every alias, by alias; files that do not hold keys are skipped
+    pub fn list(&self) -> Result<Vec<KeyInfo>, Error> {
+        let mut aliases = self.aliases(&self.dir)?;
+        if let Some(legacy_dir) = &self.legacy_dir {
+            aliases.extend(self.aliases(legacy_dir)?);
+        }
+        aliases.sort();
+        aliases.dedup();
+        // Legacy keys are listed where they are, they only move when used
+        Ok(aliases.iter().filter_map(|alias| {
+            let (keys, path) = match self.read_file(alias) {
+                Err(e) if e.kind() == ErrorKind::NotFound => self.legacy_file(alias),
+                res => res
+            }.ok()?;
+            key_info(alias, keys, &path).ok()
+        }).collect())
+    }
+
+    /// Names in `dir` that may be aliases
+    fn aliases(&self, dir: &Path) -> Result<Vec<String>, Error> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e)
+        };
+        let mut aliases = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let alias = match entry.file_name().into_string() {
+                Ok(alias) if check_alias(&alias).is_ok() => alias,
+                _ => continue
+            };
+            match entry.metadata() {
+                Ok(m) if m.is_file() && m.len() <= MAX_KEYS_LEN => aliases.push(alias),
+                _ => continue
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Private keys of `alias`, failing with `ErrorKind::NotFound` when there are none and with
+    /// `ErrorKind::PermissionDenied` when they are encrypted and the passphrase is missing or wrong
+    pub fn get(&self, alias: &str) -> Result<Zeroizing<String>, Error> {
+        match self.read(alias)? {
+            KeyFile::Plain(keys) => Ok(keys),
+            KeyFile::Encrypted(encrypted) => match &self.passphrase {
+                Some(passphrase) => encrypted.decrypt(passphrase).map_err(|e| Error::new(e.kind(), format!("{} for the keys of {}", e, alias))),
+                None => Err(Error::new(ErrorKind::PermissionDenied, format!("The keys of {} are encrypted, a passphrase is needed", alias)))
+            }
+        }
+    }
+
+    fn read(&self, alias: &str) -> Result<KeyFile, Error> {
+        match self.read_file(alias) {
+            Err(e) if e.kind() == ErrorKind::NotFound => self.migrate(alias),
+            res => res.map(|(keys, _)| keys)
+        }
+    }
+
+    /// Keys of `alias` in the store and their file
+    fn read_file(&self, alias: &str) -> Result<(KeyFile, PathBuf), Error> {
+        let path = self.path(alias)?;
+        Ok((read_file(&path)?, path))
+    }
+
+    /// Keys of `alias` in the legacy directory and their file, left where they are
+    fn legacy_file(&self, alias: &str) -> Result<(KeyFile, PathBuf), Error> {
+        let not_found = || Error::new(ErrorKind::NotFound, format!("No keys for alias {}", alias));
+        let legacy = match &self.legacy_dir {
+            Some(dir) => dir.join(alias),
+            None => return Err(not_found())
+        };
+        match read_file(&legacy) {
+            Ok(keys) => Ok((keys, legacy)),
+            // Not ours, e.g. a file of the router
+            Err(e) if e.kind() == ErrorKind::InvalidData => Err(not_found()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(not_found()),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Move the legacy keys of `alias`, if any, into the store
+    fn migrate(&self, alias: &str) -> Result<KeyFile, Error> {
+        let (_, legacy) = self.legacy_file(alias)?;
+        let bytes = Zeroizing::new(fs::read(&legacy)?);
+        info!("Moving the keys of {} from {} to {}", alias, legacy.display(), self.dir.display());
+        self.create_dir()?;
+        write_file(&self.path(alias)?, &bytes, false)?;
+        fs::remove_file(&legacy)?;
+        self.read(alias)
+    }
+
+    /// Public side of the keys of `alias`; needs no passphrase
+    pub fn info(&self, alias: &str) -> Result<KeyInfo, Error> {
+        let keys = self.read(alias)?;
+        key_info(alias, keys, &self.path(alias)?)
+    }
+
+    /// Save `keys` under `alias`, encrypted if the store has a passphrase; existing keys are only
+    /// replaced when `force` is set, otherwise this fails with `ErrorKind::AlreadyExists`.
+    pub fn save(&self, alias: &str, keys: &str, force: bool) -> Result<KeyInfo, Error> {
+        check_keys(keys)?;
+        // Legacy keys count as existing
+        if !force && self.contains(alias) {
+            return Err(exists_error(alias));
+        }
+        let contents = match &self.passphrase {
+            Some(passphrase) => Zeroizing::new(EncryptedKeys::encrypt(keys, passphrase, self.kdf_params)?),
+            None => Zeroizing::new(String::from(keys))
+        };
+        self.create_dir()?;
+        write_file(&self.path(alias)?, contents.as_bytes(), force).map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => exists_error(alias),
+            _ => e
+        })?;
+        self.info(alias)
+    }
+
+    /// Save the keys of `alias` again with `passphrase`, or unencrypted if `None`; reading them
+    /// needs the current passphrase of the store if they are encrypted.
+    pub fn change_passphrase(&self, alias: &str, passphrase: Option<&str>) -> Result<KeyInfo, Error> {
+        let keys = self.get(alias)?;
+        let store = KeyStore { passphrase: passphrase.map(|p| Zeroizing::new(String::from(p))), ..self.clone() };
+        store.save(alias, &keys, true)
+    }
+
+    pub fn delete(&self, alias: &str) -> Result<(), Error> {
+        self.read(alias)?;
+        fs::remove_file(self.path(alias)?)?;
+        sync_dir(&self.dir)
+    }
+
+    /// Move the keys of `from` to `to`, replacing keys saved as `to` only when `force` is set
+    pub fn rename(&self, from: &str, to: &str, force: bool) -> Result<KeyInfo, Error> {
+        self.read(from)?;
+        let (from_path, to_path) = (self.path(from)?, self.path(to)?);
+        if force {
+            fs::rename(&from_path, &to_path)?;
+        } else {
+            if self.contains(to) {
+                return Err(exists_error(to));
+            }
+            // Unlike rename, linking fails if `to` appeared meanwhile
+            fs::hard_link(&from_path, &to_path).map_err(|e| match e.kind() {
+                ErrorKind::AlreadyExists => exists_error(to),
+                _ => e
+            })?;
+            fs::remove_file(&from_path)?;
+        }
+        sync_dir(&self.dir)?;
+        self.info(to)
+    }
+
+    /// Save the keys in a `.dat` file of Java I2P or i2pd (e.g. `eepPriv.dat`) under `alias`
+    pub fn import_dat<P: AsRef<Path>>(&self, alias: &str, path: P, force: bool) -> Result<KeyInfo, Error> {
+        let path = path.as_ref();
+        let bytes = Zeroizing::new(fs::read(path).map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?);
+        self.save(alias, &keys_from_dat(&bytes)?, force)
+    }
+
+    /// Write the keys of `alias` as a `.dat` file for Java I2P or i2pd, readable only by the user;
+    /// an existing file is only replaced when `force` is set.
+    pub fn export_dat<P: AsRef<Path>>(&self, alias: &str, path: P, force: bool) -> Result<(), Error> {
+        let path = path.as_ref();
+        let bytes = keys_to_dat(&self.get(alias)?)?;
+        write_file(path, &bytes, force).map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    fn create_dir(&self) -> Result<(), Error> {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&self.dir)
+    }
+}
+
+fn exists_error(alias: &str) -> Error {
+    Error::new(ErrorKind::AlreadyExists, format!("Keys for alias {} already exist", alias))
+}
+
+/// Aliases name files in the store: no separators, `..`, hidden or temp files, or characters
+/// Windows does not allow
+fn check_alias(alias: &str) -> Result<(), Error> {
+    let invalid = alias.is_empty()
+        || alias.len() > MAX_ALIAS_LEN
+        || alias.starts_with('.')
+        || alias.chars().any(|c| c.is_control() || "/\\<>:\"|?*".contains(c));
+    if invalid {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid alias: {:?}", alias)));
+    }
+    Ok(())
+}
+
+/// Public side of `keys`, saved in `path`
+fn key_info(alias: &str, keys: KeyFile, path: &Path) -> Result<KeyInfo, Error> {
+    let (public, encrypted) = match keys {
+        KeyFile::Plain(keys) => (Destination::new(&keys).public().unwrap(), false),
+        KeyFile::Encrypted(encrypted) => (encrypted.public, true)
+    };
+    let metadata = fs::metadata(path)?;
+    Ok(KeyInfo {
+        alias: String::from(alias),
+        b32: public.to_b32().unwrap(),
+        sig_type: sig_type_name(public.sig_type().unwrap()),
+        dest: public,
+        created: metadata.created().or_else(|_| metadata.modified()).ok(),
+        encrypted,
+    })
+}
+
+fn read_file(path: &Path) -> Result<KeyFile, Error> {
+    let contents = Zeroizing::new(fs::read_to_string(path)?);
+    let contents = contents.trim();
+    let res = if contents.starts_with(ENCRYPTED) {
+        EncryptedKeys::parse(contents).map(KeyFile::Encrypted)
+    } else {
+        check_keys(contents).map(|_| KeyFile::Plain(Zeroizing::new(String::from(contents))))
+    };
+    res.map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Keys encrypted with a passphrase, saved as one line of `:` separated fields: format name and
+/// version, KDF and its parameters, salt, nonce, public destination and ciphertext, binary fields
+/// in I2P base64. All fields before the ciphertext are authenticated as associated data.
+struct EncryptedKeys {
+    header: String,
+    kdf_params: (u32, u32, u32),
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    public: Destination,
+    ciphertext: Vec<u8>,
+}
+
+impl EncryptedKeys {
+    fn encrypt(keys: &str, passphrase: &str, kdf_params: (u32, u32, u32)) -> Result<String, Error> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let public = Destination::new(keys).public().unwrap();
+        let (m, t, p) = kdf_params;
+        let header = format!("{}:1:argon2id:{}:{}:{}:{}:{}:{}", ENCRYPTED, m, t, p,
+            encode_i2p_base64(&salt), encode_i2p_base64(&nonce), public);
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&*derive_key(passphrase, &salt, kdf_params)?));
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: keys.as_bytes(), aad: header.as_bytes() })
+            .map_err(|_| Error::new(ErrorKind::Other, "Encryption failed"))?;
+        Ok(format!("{}:{}", header, encode_i2p_base64(&ciphertext)))
+    }
+
+    fn parse(contents: &str) -> Result<EncryptedKeys, Error> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("Invalid encrypted keys: {}", msg));
+        let fields: Vec<&str> = contents.split(':').collect();
+        if fields.len() != 10 {
+            return Err(invalid("wrong number of fields"));
+        }
+        if fields[1] != "1" || fields[2] != "argon2id" {
+            return Err(invalid(&format!("unsupported version {} or KDF {}", fields[1], fields[2])));
+        }
+        let number = |field: &str| field.parse::<u32>().map_err(|_| invalid("KDF parameters"));
+        let binary = |field: &str, name: &str| decode_i2p_base64(field).ok_or_else(|| invalid(name));
+        let nonce = binary(fields[7], "nonce")?;
+        if nonce.len() != 12 {
+            return Err(invalid("nonce"));
+        }
+        let public = Destination::new(fields[8]);
+        if public.public().as_ref() != Some(&public) {
+            return Err(invalid("public destination"));
+        }
+        let kdf_params = (number(fields[3])?, number(fields[4])?, number(fields[5])?);
+        let (max_m, max_t, max_p) = MAX_KDF_PARAMS;
+        if kdf_params.0 > max_m || kdf_params.1 > max_t || kdf_params.2 > max_p {
+            return Err(invalid(&format!("KDF parameters above {}:{}:{}", max_m, max_t, max_p)));
+        }
+        Ok(EncryptedKeys {
+            header: fields[..9].join(":"),
+            kdf_params,
+            salt: binary(fields[6], "salt")?,
+            nonce,
+            public,
+            ciphertext: binary(fields[9], "ciphertext")?,
+        })
+    }
+
+    /// Fails with `ErrorKind::PermissionDenied` when `passphrase` is wrong
+    fn decrypt(&self, passphrase: &str) -> Result<Zeroizing<String>, Error> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&*derive_key(passphrase, &self.salt, self.kdf_params)?));
+        let plain = Zeroizing::new(cipher.decrypt(Nonce::from_slice(&self.nonce), Payload { msg: &self.ciphertext, aad: self.header.as_bytes() })
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Wrong passphrase"))?);
+        let keys = Zeroizing::new(String::from_utf8(plain.to_vec()).map_err(|_| Error::new(ErrorKind::InvalidData, "Decrypted keys are not text"))?);
+        check_keys(&keys)?;
+        if Destination::new(&keys).public().as_ref() != Some(&self.public) {
+            return Err(Error::new(ErrorKind::InvalidData, "Decrypted keys do not match the public destination"));
+        }
+        Ok(keys)
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], (m, t, p): (u32, u32, u32)) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let invalid = |e: argon2::Error| Error::new(ErrorKind::InvalidData, format!("Invalid KDF parameters: {}", e));
+    let params = Params::new(m, t, p, Some(32)).map_err(invalid)?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut *key)
+        .map_err(invalid)?;
+    Ok(key)
+}
+
+/// Write `bytes` to `path` through a synced temp file only the user can read, then move it in
+/// place; without `force` this fails with `ErrorKind::AlreadyExists` if `path` exists.
+fn write_file(path: &Path, bytes: &[u8], force: bool) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+    let name = path.file_name().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No file name"))?;
+    let temp = dir.join(format!(".{}.tmp-{}-{}", name.to_string_lossy(), process::id(), TEMP_FILES.fetch_add(1, Ordering::SeqCst)));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let res = options.open(&temp)
+        .and_then(|mut file| file.write_all(bytes).and_then(|_| file.sync_all()))
+        .and_then(|_| if force {
+            fs::rename(&temp, path)
+        } else {
+            // Unlike rename, linking fails if `path` exists
+            fs::hard_link(&temp, path).and_then(|_| fs::remove_file(&temp))
+        })
+        .and_then(|_| sync_dir(dir));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    res
+}
+
+/// Persist renames in `dir`; directories can only be synced on unix
+fn sync_dir(dir: &Path) -> Result<(), Error> {
+    if cfg!(unix) {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Keys in a `.dat` file: the binary form of the base64 keys, which some tools save as text instead
+pub fn keys_from_dat(bytes: &[u8]) -> Result<Zeroizing<String>, Error> {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        if check_keys(text.trim()).is_ok() {
+            return Ok(Zeroizing::new(String::from(text.trim())));
+        }
+    }
+    let keys = Zeroizing::new(encode_i2p_base64(bytes));
+    check_keys(&keys)?;
+    Ok(keys)
+}
+
+/// Binary `.dat` form of base64 keys
+pub fn keys_to_dat(keys: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    check_keys(keys)?;
+    Ok(Zeroizing::new(decode_i2p_base64(keys).unwrap()))
+}
+
+/// Check `keys` holds a destination followed by its private keys
+fn check_keys(keys: &str) -> Result<(), Error> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg);
+    let dest = Destination::new(keys);
+    let public = dest.dest_bytes().ok_or_else(|| invalid("Not a destination with private keys"))?;
+    let len = decode_i2p_base64(keys).unwrap().len();
+    // Encryption private key (ElGamal unless a key certificate says otherwise) and signing private key
+    let crypto_type = match public[384] {
+        5 if public.len() >= 391 => u16::from_be_bytes([public[389], public[390]]),
+        _ => 0
+    };
+    let private_len = match crypto_type {
+        4 => 32,
+        _ => 256
+    };
+    let signing_len = match dest.sig_type().unwrap() {
+        0 => 20,
+        1 => 32,
+        2 => 48,
+        3 => 66,
+        4 => 512,
+        5 => 768,
+        6 => 1024,
+        _ => 32
+    };
+    if len < public.len() + private_len + signing_len {
+        return Err(invalid("Destination without private keys"));
+    }
+    Ok(())
+}
+
+/// Name of a signature type code as used by SAM
+fn sig_type_name(code: u16) -> String {
+    let name = match code {
+        0 => "DSA_SHA1",
+        1 => "ECDSA_SHA256_P256",
+        2 => "ECDSA_SHA384_P384",
+        3 => "ECDSA_SHA512_P521",
+        4 => "RSA_SHA256_2048",
+        5 => "RSA_SHA384_3072",
+        6 => "RSA_SHA512_4096",
+        7 => "EDDSA_SHA512_ED25519",
+        8 => "EDDSA_SHA512_ED25519PH",
+        11 => "REDDSA_SHA512_ED25519",
+        _ => return format!("UNKNOWN({})", code)
+    };
+    String::from(name)
+}
+
+/// `time` as e.g. `2020-03-12T09:30:00Z`
+fn format_utc(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's days_from_civil inverse
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::keys::{format_utc, keys_from_dat, keys_to_dat, KeyStore};
+
+    /// Destination with an EdDSA key certificate followed by ElGamal and EdDSA private keys
+    fn keys(fill: u8) -> Vec<u8> {
+        let mut bytes = vec![fill; 384];
+        bytes.extend_from_slice(&[5, 0, 4, 0, 7, 0, 0]);
+        bytes.extend_from_slice(&[fill; 256 + 32]);
+        bytes
+    }
+
+    #[test]
+    fn store() {
+        let dir = std::env::temp_dir().join(format!("i2p_client-keys-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = KeyStore::new(&dir);
+        assert!(store.list().unwrap().is_empty());
+
+        let dat = dir.join("eepPriv.dat");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&dat, keys(1)).unwrap();
+        fs::write(dir.join("router.config"), "i2np.udp.port=1234\n").unwrap();
+        let info = store.import_dat("site", &dat, false).unwrap();
+        assert_eq!(info.sig_type, "EDDSA_SHA512_ED25519");
+        assert!(info.b32.ends_with(".b32.i2p"));
+        assert!(info.created.is_some());
+        assert_eq!(store.import_dat("site", &dat, false).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        fs::write(&dat, keys(2)).unwrap();
+        let replaced = store.import_dat("site", &dat, true).unwrap();
+        assert_ne!(replaced.b32, info.b32);
+        assert_eq!(store.list().unwrap(), vec![replaced.clone()]);
+
+        store.save("other", &store.get("site").unwrap(), false).unwrap();
+        assert_eq!(store.rename("site", "other", false).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(store.rename("site", "renamed", false).unwrap().b32, replaced.b32);
+        assert_eq!(store.get("site").unwrap_err().kind(), ErrorKind::NotFound);
+
+        let exported = dir.join("exported.dat");
+        store.export_dat("renamed", &exported, false).unwrap();
+        assert_eq!(fs::read(&exported).unwrap(), keys(2));
+        assert_eq!(store.export_dat("renamed", &exported, false).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        store.delete("other").unwrap();
+        assert_eq!(store.delete("other").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(store.list().unwrap().iter().map(|k| k.alias.as_str()).collect::<Vec<_>>(), vec!["renamed"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn migration() {
+        let dir = std::env::temp_dir().join(format!("i2p_client-migrate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let legacy = dir.join("legacy");
+        fs::create_dir_all(&legacy).unwrap();
+        let keys = keys_from_dat(&keys(4)).unwrap();
+        fs::write(legacy.join("old"), &keys).unwrap();
+        fs::write(legacy.join("eepPriv.dat"), b"not keys").unwrap();
+        let store = KeyStore::new(dir.join("keys")).migrate_from(&legacy);
+
+        assert_eq!(store.list().unwrap().iter().map(|k| k.alias.as_str()).collect::<Vec<_>>(), vec!["old"]);
+        // Listing leaves them in place, using the alias moves them
+        assert!(legacy.join("old").exists());
+        assert!(!store.path("old").unwrap().exists());
+        assert_eq!(store.save("old", &keys, false).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(store.get("old").unwrap(), keys);
+        assert!(!legacy.join("old").exists());
+        assert!(store.path("old").unwrap().exists());
+        assert_eq!(store.get("eepPriv.dat").unwrap_err().kind(), ErrorKind::NotFound);
+        assert!(fs::read_dir(store.dir()).unwrap().all(|e| !e.unwrap().file_name().to_string_lossy().contains(".tmp-")));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &std::path::Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(store.dir()), 0o700);
+            assert_eq!(mode(&store.path("old").unwrap()), 0o600);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn encrypted() {
+        let dir = std::env::temp_dir().join(format!("i2p_client-encrypted-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cheap = |store: KeyStore| KeyStore { kdf_params: (64, 1, 1), ..store };
+        let plain = cheap(KeyStore::new(&dir));
+        let store = cheap(KeyStore::new(&dir).with_passphrase("correct horse"));
+        let keys = keys_from_dat(&keys(5)).unwrap();
+
+        let info = store.save("secret", &keys, false).unwrap();
+        assert!(info.encrypted);
+        let contents = fs::read_to_string(plain.path("secret").unwrap()).unwrap();
+        assert!(contents.starts_with("i2p_client-encrypted-keys:1:argon2id:64:1:1:"));
+        assert!(!contents.contains(&keys[info.dest.as_str().len()..]));
+        assert_eq!(store.get("secret").unwrap(), keys);
+        // The public side needs no passphrase
+        assert_eq!(plain.info("secret").unwrap(), info);
+        assert!(plain.is_encrypted("secret").unwrap());
+        assert_eq!(plain.get("secret").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let wrong = cheap(KeyStore::new(&dir).with_passphrase("wrong"));
+        assert_eq!(wrong.get("secret").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(plain.save("secret", &keys, false).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        // Tampering with the authenticated header
+        let tampered = contents.replace(":64:1:1:", ":64:2:1:");
+        fs::write(plain.path("secret").unwrap(), tampered).unwrap();
+        assert_eq!(store.get("secret").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        // Costs too high to even try
+        for params in &[":1048577:1:1:", ":64:17:1:", ":64:1:17:"] {
+            fs::write(plain.path("secret").unwrap(), contents.replace(":64:1:1:", params)).unwrap();
+            assert_eq!(store.get("secret").unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        fs::write(plain.path("secret").unwrap(), &contents).unwrap();
+
+        let info = store.change_passphrase("secret", Some("battery staple")).unwrap();
+        assert!(info.encrypted);
+        assert_eq!(store.get("secret").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let store = cheap(KeyStore::new(&dir).with_passphrase("battery staple"));
+        assert!(!store.change_passphrase("secret", None).unwrap().encrypted);
+        assert_eq!(plain.get("secret").unwrap(), keys);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn aliases() {
+        let store = KeyStore::new("keys");
+        for alias in &["", "../x", "a/b", "a\\b", ".hidden", "a\nb", "c:x"] {
+            assert_eq!(store.path(alias).unwrap_err().kind(), ErrorKind::InvalidInput, "{:?}", alias);
+        }
+        assert_eq!(store.path(&"a".repeat(129)).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(store.path("my-site.v2").unwrap(), std::path::Path::new("keys").join("my-site.v2"));
+    }
+
+    #[test]
+    fn dat() {
+        let keys = keys_from_dat(&keys(3)).unwrap();
+        assert_eq!(*keys_to_dat(&keys).unwrap(), self::keys(3));
+        // Base64 saved as text
+        assert_eq!(keys_from_dat(format!("{}\n", *keys).as_bytes()).unwrap(), keys);
+        // A public destination only
+        assert_eq!(keys_from_dat(&self::keys(3)[..391]).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(keys_from_dat(b"not keys").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(UNIX_EPOCH + Duration::from_secs(1_584_005_400)), "2020-03-12T09:30:00Z");
+        assert_eq!(format_utc(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00Z");
+    }
+}
@@ -4,30 +4,59 @@ extern crate base64;
 extern crate nom;
 extern crate log;
 
-use std::fs::File;
 
 use log::{debug,info,warn};
 
 use std::clone::Clone;
 use std::collections::HashMap;
 use std::convert::{TryFrom};
-use std::{io, thread};
+use std::str::FromStr;
+use std::io;
 use std::io::{BufReader, Error, ErrorKind, BufRead, Write, Read};
-use std::path::{Path};
-use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
-
-use nom::{IResult};
-
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+
+use nom::IResult;
+use zeroize::Zeroizing;
+
+#[cfg(feature = "tokio")]
+pub mod r#async;
+mod config;
+mod destination;
+mod http;
+mod keys;
+mod naming;
 mod parsers;
+mod retry;
+mod site;
+mod socks;
+mod tunnel;
+mod tunnels;
+pub use crate::config::{SamConfig, Timeouts, DEFAULT_SAM_HOST, DEFAULT_SAM_PORT, DEFAULT_SAM_UDP_PORT};
+pub use crate::destination::{Destination, I2pSocketAddr, ToI2pSocketAddrs};
+use crate::destination::each_addr;
+pub use crate::naming::{address_book, Resolver};
+pub use crate::http::HttpProxy;
+pub use crate::keys::{keys_from_dat, keys_to_dat, KeyInfo, KeyStore};
+pub use crate::retry::RetryPolicy;
+pub use crate::site::Response;
+pub use crate::socks::Socks5Proxy;
+pub use crate::tunnel::{ClientTunnel, ConnectionStats, ServerTunnel, TunnelStats};
+pub use crate::tunnels::{TunnelConfig, TunnelManager, TunnelState, TunnelStatus, TunnelType, TunnelsConfig};
 use crate::parsers::{datagram_received, gen_reply, sam_hello, sam_naming_reply, sam_session_status, sam_stream_status};
 use std::time::Duration;
 
-static DEFAULT_API: &'static str = "127.0.0.1:7656";
-// static DEFAULT_UDP_API: &'static str = "127.0.0.1:7655";
-
-static I2P_PID: &'static str = "i2p.pid";
-static I2P_STATUS: &'static str = "i2p.status";
-static I2P_ADDR_BK: &'static str = "eepsite/docroot/hosts.txt";
+/// Longest reply line accepted from the SAM bridge; DEST REPLY with the largest key types stays well below this.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Largest datagram payload (as sent on the wire, i.e. base64) accepted from the SAM bridge.
+const MAX_DATAGRAM_LEN: usize = 64 * 1024;
+/// Largest `DATAGRAM SEND` command (base64 payload included) or UDP datagram to the bridge (header
+/// included) that fits the tunnel limit.
+const MAX_SEND_LEN: usize = 61_500;
+/// Datagrams above this are more likely to get lost.
+const RECOMMENDED_SEND_LEN: usize = 31_500;
 
 #[derive(Debug, Copy, Clone)]
 pub enum SigType {
@@ -51,28 +80,38 @@ impl SigType {
             SigType::DsaSha1 => "DSA_SHA1"
         }
     }
+
+    /// Same as [`FromStr::from_str`], kept so callers need not import the trait
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(sig_type: &str) -> Result<Self, Error> {
+        <SigType as FromStr>::from_str(sig_type)
+    }
+}
+
+impl FromStr for SigType {
+    type Err = Error;
+    fn from_str(sig_type: &str) -> Result<Self, Self::Err> {
         match sig_type {
             "EDDSA_SHA512_ED25519" => Ok(SigType::EdDsaSha512Ed25519),
             "EDDSA_SHA512_ED25519PH" => Ok(SigType::EdDsaSha512Ed25519ph),
             "REDDSA_SHA512_ED25519" => Ok(SigType::RedDsaSha512Ed25519),
             "DSA_SHA1" => Ok(SigType::DsaSha1),
-            _ => Result::Err(Error::new(ErrorKind::InvalidData, format!("SigType provided not supported: {}", sig_type)))
+            _ => Result::Err(Error::new(ErrorKind::InvalidInput, format!("SigType provided not supported: {}", sig_type)))
         }
     }
 }
 
 fn verify_received<'a>(vec: &'a [(&str, &str)]) -> Result<HashMap<&'a str, &'a str>, Error> {
-    let new_vec = vec.clone();
-    let map: HashMap<&str, &str> = new_vec.iter().map(|&(k, v)| (k, v)).collect();
-    let res = map.get("RESULT").unwrap_or(&"OK").clone();
-    let msg = map.get("MESSAGE").unwrap_or(&"").clone();
+    let map: HashMap<&str, &str> = vec.iter().map(|&(k, v)| (k, v)).collect();
+    let res = *map.get("RESULT").unwrap_or(&"OK");
+    // Not every error reply has a MESSAGE
+    let msg = *map.get("MESSAGE").unwrap_or(&res);
     match res {
         "OK" => Ok(map),
         "CANT_REACH_PEER" | "KEY_NOT_FOUND" | "PEER_NOT_FOUND" => {
             Err(Error::new(ErrorKind::NotFound, msg))
         }
-        "DUPLICATED_DEST" => Err(Error::new(ErrorKind::AddrInUse, msg)),
+        "DUPLICATED_ID" | "DUPLICATED_DEST" => Err(Error::new(ErrorKind::AlreadyExists, msg)),
         "INVALID_KEY" | "INVALID_ID" => Err(Error::new(ErrorKind::InvalidInput, msg)),
         "TIMEOUT" => Err(Error::new(ErrorKind::TimedOut, msg)),
         "I2P_ERROR" => Err(Error::new(ErrorKind::Other, msg)),
@@ -81,16 +120,16 @@ fn verify_received<'a>(vec: &'a [(&str, &str)]) -> Result<HashMap<&'a str, &'a s
 }
 
 fn verify_response<'a>(vec: &'a [(&str, &str)]) -> Result<HashMap<&'a str, &'a str>, Error> {
-    let new_vec = vec.clone();
-    let map: HashMap<&str, &str> = new_vec.iter().map(|&(k, v)| (k, v)).collect();
-    let res = map.get("RESULT").unwrap_or(&"OK").clone();
-    let msg = map.get("MESSAGE").unwrap_or(&"").clone();
+    let map: HashMap<&str, &str> = vec.iter().map(|&(k, v)| (k, v)).collect();
+    let res = *map.get("RESULT").unwrap_or(&"OK");
+    // Not every error reply has a MESSAGE
+    let msg = *map.get("MESSAGE").unwrap_or(&res);
     match res {
         "OK" => Ok(map),
         "CANT_REACH_PEER" | "KEY_NOT_FOUND" | "PEER_NOT_FOUND" => {
             Err(Error::new(ErrorKind::NotFound, msg))
         }
-        "DUPLICATED_DEST" => Err(Error::new(ErrorKind::AddrInUse, msg)),
+        "DUPLICATED_ID" | "DUPLICATED_DEST" => Err(Error::new(ErrorKind::AlreadyExists, msg)),
         "INVALID_KEY" | "INVALID_ID" => Err(Error::new(ErrorKind::InvalidInput, msg)),
         "TIMEOUT" => Err(Error::new(ErrorKind::TimedOut, msg)),
         "I2P_ERROR" => Err(Error::new(ErrorKind::Other, msg)),
@@ -98,10 +137,271 @@ fn verify_response<'a>(vec: &'a [(&str, &str)]) -> Result<HashMap<&'a str, &'a s
     }
 }
 
+/// `TcpStream::connect_timeout` over every address `addr` resolves to, returning the last error.
+fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<TcpStream, Error> {
+    let mut last_err = Error::new(ErrorKind::InvalidInput, "SAM address resolved to no addresses");
+    for a in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, timeout) {
+            Ok(s) => return Ok(s),
+            Err(e) => last_err = e
+        }
+    }
+    Err(last_err)
+}
+
+/// Surface socket read/write timeouts (`WouldBlock` on Unix, `TimedOut` on Windows) as `ErrorKind::TimedOut`.
+fn timed_out(e: Error, msg: &str) -> Error {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => Error::new(ErrorKind::TimedOut, msg),
+        _ => e
+    }
+}
+
+fn to_owned_map(m: HashMap<&str, &str>) -> HashMap<String, String> {
+    m.iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// Read a single `\n` terminated line of at most `MAX_LINE_LEN` bytes.
+fn read_line_bounded<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+    let mut buf = Vec::new();
+    let num_bytes = reader.by_ref().take(MAX_LINE_LEN as u64).read_until(b'\n', &mut buf)?;
+    bounded_line(buf, num_bytes)
+}
+
+/// Check a line read with at most `MAX_LINE_LEN` bytes, shared by the blocking and async readers.
+fn bounded_line(buf: Vec<u8>, num_bytes: usize) -> Result<String, Error> {
+    if num_bytes == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "SAM bridge closed the connection"));
+    }
+    if buf.last() != Some(&b'\n') {
+        if num_bytes == MAX_LINE_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, format!("SAM reply exceeds {} bytes", MAX_LINE_LEN)));
+        }
+        return Err(Error::new(ErrorKind::UnexpectedEof, "SAM reply truncated"));
+    }
+    String::from_utf8(buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// HELLO with the configured versions and credentials
+fn hello_msg(config: &SamConfig) -> String {
+    let mut hello_msg = format!("HELLO VERSION MIN={} MAX={}", config.min_version, config.max_version);
+    if let (Some(user), Some(password)) = (&config.user, &config.password) {
+        hello_msg.push_str(&format!(" USER=\"{}\" PASSWORD=\"{}\"", user, password));
+    }
+    hello_msg.push_str(" \n");
+    hello_msg
+}
+
+/// SESSION CREATE with `options` passed through
+fn session_create_msg(style: SessionStyle, nickname: &str, destination: &str, options: &[(String, String)]) -> String {
+    let mut create_session_msg = format!("SESSION CREATE STYLE={} ID={} DESTINATION={}", style.string(), nickname, destination);
+    for (k, v) in options {
+        create_session_msg.push_str(&format!(" {}={}", k, v));
+    }
+    create_session_msg.push_str(" \n");
+    create_session_msg
+}
+
+/// SESSION ADD of a subsession to a PRIMARY session, with `options` passed through
+fn session_add_msg(style: SessionStyle, nickname: &str, options: &[(String, String)]) -> String {
+    let mut add_session_msg = format!("SESSION ADD STYLE={} ID={}", style.string(), nickname);
+    for (k, v) in options {
+        add_session_msg.push_str(&format!(" {}={}", k, v));
+    }
+    add_session_msg.push_str(" \n");
+    add_session_msg
+}
+
+/// Whether the negotiated `version` (e.g. `3.1`) is at least `major.minor`
+fn version_at_least(version: &str, major: u32, minor: u32) -> bool {
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0)) >= (major, minor)
+}
+
+/// `line` for the log, without the private keys of SESSION CREATE/STATUS and DEST REPLY or the
+/// HELLO password
+fn redact(line: &str) -> String {
+    if let Some(i) = line.find(" PASSWORD=") {
+        return format!("{} PASSWORD=[redacted]", &line[..i]);
+    }
+    let session = line.starts_with("SESSION ");
+    line.split(' ').map(|field| match field.split_once('=') {
+        Some(("DESTINATION", value)) if session && value.trim_end() != "TRANSIENT" => "DESTINATION=[redacted]",
+        Some(("PRIV", _)) => "PRIV=[redacted]",
+        _ => field
+    }).collect::<Vec<_>>().join(" ")
+}
+
+/// Parse a reply line and verify its RESULT.
+fn parse_reply<F>(line: &str, reply_parser: F) -> Result<HashMap<String, String>, Error>
+    where
+        F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
+{
+    match reply_parser(line) {
+        IResult::Done(_, vec_opts) => verify_response(&vec_opts).map(to_owned_map),
+        _ => Err(Error::new(ErrorKind::InvalidData, format!("Malformed SAM reply: {}", line.trim_end())))
+    }
+}
+
+/// Parse the line announcing an accepted stream, `$destination [FROM_PORT=n TO_PORT=n]`, into the
+/// peer's address and the local port it connected to; ports default to 0 before SAMv3.2.
+fn parse_stream_peer(line: &str) -> Result<(I2pSocketAddr, u16), Error> {
+    let mut fields = line.split_whitespace();
+    let dest = fields.next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "STREAM ACCEPT without peer destination"))?;
+    let (mut from_port, mut to_port) = (0, 0);
+    for field in fields {
+        let (key, value) = match field.find('=') {
+            Some(i) => (&field[..i], &field[i + 1..]),
+            None => continue
+        };
+        let port = match key {
+            "FROM_PORT" => &mut from_port,
+            "TO_PORT" => &mut to_port,
+            _ => continue
+        };
+        *port = value.parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid port in STREAM ACCEPT: {}", field)))?;
+    }
+    Ok((I2pSocketAddr::new(Destination::new(dest), from_port), to_port))
+}
+
+/// Parse a `DATAGRAM RECEIVED` header line into the sender and ports (payload still empty) and
+/// the SIZE of the payload that follows.
+fn parse_datagram_header(header: &str) -> Result<(ReceivedDatagram, usize), Error> {
+    let vec_opts = match datagram_received(header) {
+        IResult::Done(_, vec_opts) => vec_opts,
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("Malformed datagram header: {}", header.trim_end())))
+    };
+    let ret = verify_received(&vec_opts)?;
+    let size: usize = ret.get("SIZE")
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Datagram header without a valid SIZE"))?;
+    if size > MAX_DATAGRAM_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Datagram SIZE {} exceeds {} bytes", size, MAX_DATAGRAM_LEN)));
+    }
+    // Ports are only sent from SAM 3.2 on
+    let port = |key: &str| match ret.get(key) {
+        Some(p) => p.parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid {} in datagram header: {}", key, p))),
+        None => Ok(0)
+    };
+    let from = Destination::new(ret.get("DESTINATION").copied().unwrap_or_default());
+    Ok((ReceivedDatagram { from, from_port: port("FROM_PORT")?, to_port: port("TO_PORT")?, payload: Vec::new() }, size))
+}
+
+fn decode_datagram(body: &[u8]) -> Result<Vec<u8>, Error> {
+    debug!("<- BODY: ({}) {}", body.len(), String::from_utf8_lossy(body));
+    base64::decode(body).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// `DATAGRAM SEND` command for `msg`; ports of 0 are left out for bridges older than SAM 3.2.
+/// Fails with `ErrorKind::InvalidInput` when the command exceeds [`MAX_SEND_LEN`].
+fn datagram_send_msg(to: &str, from_port: u16, to_port: u16, msg: &[u8]) -> Result<String, Error> {
+    let enc_msg = base64::encode(msg);
+    let send_env_msg = format!("DATAGRAM SEND DESTINATION={} SIZE={}{} \n{}\n", to, enc_msg.len(), port_options(from_port, to_port), enc_msg.as_str());
+    check_send_len(send_env_msg.len(), msg.len())?;
+    Ok(send_env_msg)
+}
+
+/// Datagram for the UDP port of the SAM bridge, sent as session `nickname`: the line
+/// `3.0 $nickname $destination [FROM_PORT=n] [TO_PORT=n]` followed by the raw payload
+fn datagram_udp_msg(nickname: &str, to: &str, from_port: u16, to_port: u16, msg: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut datagram = format!("3.0 {} {}{}\n", nickname, to, port_options(from_port, to_port)).into_bytes();
+    datagram.extend_from_slice(msg);
+    check_send_len(datagram.len(), msg.len())?;
+    Ok(datagram)
+}
+
+/// ` FROM_PORT=n TO_PORT=n`, leaving out ports that are 0 for bridges older than SAM 3.2
+fn port_options(from_port: u16, to_port: u16) -> String {
+    let mut ports = String::new();
+    if from_port != 0 {
+        ports.push_str(&format!(" FROM_PORT={}", from_port));
+    }
+    if to_port != 0 {
+        ports.push_str(&format!(" TO_PORT={}", to_port));
+    }
+    ports
+}
+
+fn check_send_len(len: usize, msg_len: usize) -> Result<(), Error> {
+    if len > MAX_SEND_LEN {
+        warn!("Unable to send messages greater than 61.5KB (tunnel limit). Rejecting.");
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("Message of {} bytes exceeds the {} byte limit with its SAM header", msg_len, MAX_SEND_LEN)));
+    }
+    if len > RECOMMENDED_SEND_LEN {
+        warn!("Message length is greater than 31.5KB; recommended to stay below this and ideally less than 11KB.")
+    }
+    Ok(())
+}
+
+/// The UDP address of the SAM bridge and a local address to send to it from
+fn udp_addrs(config: &SamConfig) -> Result<(SocketAddr, SocketAddr), Error> {
+    let bridge = config.udp_addr().to_socket_addrs()?.next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "SAM UDP address resolved to no addresses"))?;
+    let local = match bridge {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+    Ok((bridge, local))
+}
+
+/// A datagram received on a DATAGRAM session
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedDatagram {
+    pub from: Destination,
+    /// Sender's port, 0 if not given
+    pub from_port: u16,
+    /// Port it was sent to, 0 if not given
+    pub to_port: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Read a `DATAGRAM RECEIVED` header and its SIZE bytes of payload; return the
+/// sender and the decoded message.
+fn read_datagram<R: BufRead>(reader: &mut R) -> Result<ReceivedDatagram, Error> {
+    let header = read_line_bounded(reader)?;
+    debug!("<- HEADER: ({}) {}", header.len(), &header);
+    let (mut datagram, size) = parse_datagram_header(&header)?;
+    let mut body = vec![0u8; size];
+    reader.read_exact(&mut body)?;
+    datagram.payload = decode_datagram(&body)?;
+    Ok(datagram)
+}
+
+/// Parse a hosts.txt style address book (`name=destination` per line); comments,
+/// blank and malformed lines are skipped.
+fn parse_hosts<R: BufRead>(reader: R) -> HashMap<String, String> {
+    let mut m: HashMap<String,String> = HashMap::new();
+    for line in reader.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("{}", e);
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.find('=') {
+            Some(i) => {
+                m.insert(String::from(&line[..i]), String::from(&line[i + 1..]));
+            },
+            None => debug!("Skipping malformed address book entry: {}", line)
+        }
+    }
+    m
+}
+
 pub struct SamConnection {
     conn: TcpStream,
-    min_version: String,
-    max_version: String,
+    reader: BufReader<TcpStream>,
+    config: SamConfig,
     current_version: String
 }
 
@@ -110,110 +410,157 @@ impl SamConnection {
         where
             F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
     {
-        debug!("-> {}", &msg);
-        self.conn.write_all(&msg.into_bytes())?;
-
-        let mut reader = BufReader::new(&self.conn);
-        let mut buffer = String::new();
-        reader.read_line(&mut buffer)?;
-        debug!("<- {}", &buffer);
-
-        let response = reply_parser(&buffer);
-        let vec = response.unwrap();
-        let vec_opts = vec.1;
-        verify_response(&vec_opts).map(|m| {
-            m.iter()
-                .map(|(k, v)| (k.to_string(), v.to_string()))
-                .collect()
+        let timeout = self.config.timeouts.reply;
+        self.send_timeout(msg, reply_parser, timeout)
+    }
+
+    fn send_timeout<F>(&mut self, msg: String, reply_parser: F, timeout: Option<Duration>) -> Result<HashMap<String, String>, Error>
+        where
+            F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
+    {
+        let line = self.request(msg, timeout)?;
+        parse_reply(&line, reply_parser)
+    }
+
+    /// Write `msg` and read the reply line
+    fn request(&mut self, msg: String, timeout: Option<Duration>) -> Result<String, Error> {
+        debug!("-> {}", redact(&msg));
+        self.set_timeouts(timeout)?;
+        self.conn.write_all(&msg.into_bytes()).map_err(|e| timed_out(e, "Timed out sending to SAM bridge"))?;
+
+        let buffer = read_line_bounded(&mut self.reader).map_err(|e| timed_out(e, "Timed out waiting for SAM reply"))?;
+        debug!("<- {}", redact(&buffer));
+        Ok(buffer)
+    }
+
+    /// Send a STREAM command; INVALID_ID means the bridge no longer knows the session, e.g. after a
+    /// router restart, and fails with `ErrorKind::NotConnected`
+    fn send_stream(&mut self, msg: String) -> Result<HashMap<String, String>, Error> {
+        let timeout = self.config.timeouts.reply;
+        let line = self.request(msg, timeout)?;
+        parse_reply(&line, sam_stream_status).map_err(|e| {
+            if line.contains(" RESULT=INVALID_ID") {
+                Error::new(ErrorKind::NotConnected, e.to_string())
+            } else {
+                e
+            }
         })
     }
 
-    fn send_async(&mut self, msg: String) {
-        debug!("-> {}", &msg);
-        match self.conn.write_all(&msg.into_bytes()) {
-            Ok(_) => debug!("{}", "msg written to conn"),
-            Err(e) => warn!("{}", e)
+    /// Fail when the bridge has closed the control socket, without consuming anything it sent
+    fn check_alive(&mut self) -> Result<(), Error> {
+        if !self.reader.buffer().is_empty() {
+            return Ok(());
+        }
+        self.conn.set_nonblocking(true)?;
+        let res = self.conn.peek(&mut [0u8; 1]);
+        self.conn.set_nonblocking(false)?;
+        match res {
+            Ok(0) => Err(Error::new(ErrorKind::UnexpectedEof, "SAM bridge closed the connection")),
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(())
         }
     }
 
-    fn handshake(&mut self) -> Result<HashMap<String, String>, Error> {
-        let hello_msg = format!("HELLO VERSION MIN={} MAX={} \n", self.min_version, self.max_version);
-        self.send(hello_msg, sam_hello)
+    fn set_timeouts(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        let timeout = timeout.map(|t| t.max(Duration::from_millis(1)));
+        self.conn.set_read_timeout(timeout)?;
+        self.conn.set_write_timeout(timeout)
     }
 
-    fn receive<F>(&mut self, received_parser: F) -> Result<HashMap<String, String>, Error>
-        where
-            F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
-    {
-        let mut reader = BufReader::new( &self.conn);
-        let mut buf = String::new();
-        let mut header = String::new();
-        let mut body = String::new();
-        let header_num_bytes = reader.read_line(&mut buf).unwrap();
-        header.push_str(buf.as_str());
-        buf.clear();
-        let body_num_bytes = reader.read_line(&mut buf).unwrap();
-        body.push_str(buf.as_str());
-        buf.clear();
-        debug!("<- HEADER: ({}) {}", header_num_bytes, &header);
-        debug!("<- BODY: ({}) {}", body_num_bytes, &body);
-        let received = received_parser(&header);
-        let vec = received.unwrap();
-        let mut vec_opts = vec.1;
-        vec_opts.push(("MSG", body.as_str()));
-        verify_received(&vec_opts).map(|m| {
-            m.iter()
-                .map(|(k, v)| (k.to_string(), v.to_string()))
-                .collect()
-        })
+    /// Block until data is available to read, for at most `timeout`
+    fn wait_readable(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.conn.set_read_timeout(timeout.map(|t| t.max(Duration::from_millis(1))))?;
+        match self.reader.fill_buf() {
+            Ok([]) => Err(Error::new(ErrorKind::UnexpectedEof, "SAM bridge closed the connection")),
+            Ok(_) => Ok(()),
+            Err(e) => Err(timed_out(e, "Timed out waiting for data"))
+        }
+    }
+
+    fn send_async(&mut self, msg: String) -> Result<(), Error> {
+        debug!("-> {}", redact(&msg));
+        self.set_timeouts(self.config.timeouts.reply)?;
+        self.conn.write_all(&msg.into_bytes()).map_err(|e| timed_out(e, "Timed out sending to SAM bridge"))?;
+        debug!("{}", "msg written to conn");
+        Ok(())
+    }
+
+    fn handshake(&mut self) -> Result<HashMap<String, String>, Error> {
+        let hello_msg = hello_msg(&self.config);
+        let timeout = self.config.timeouts.handshake;
+        self.send_timeout(hello_msg, sam_hello, timeout)
     }
 
     pub fn connect<A: ToSocketAddrs>(addr: A, min_version: &str, max_version: &str) -> Result<SamConnection, Error> {
-        let tcp_stream = TcpStream::connect(addr)?;
-        let mut conn = SamConnection {
-            conn: tcp_stream ,
+        let config = SamConfig {
             min_version: String::from(min_version),
             max_version: String::from(max_version),
+            ..SamConfig::default()
+        };
+        let mut conn = SamConnection::open(addr, &config)?;
+        // Record where we actually connected so further connections for this session go there too
+        let peer = conn.conn.peer_addr()?;
+        conn.config.host = peer.ip().to_string();
+        conn.config.port = peer.port();
+        Ok(conn)
+    }
+
+    /// Connect and handshake with the SAM bridge described by `config`
+    pub fn connect_with(config: &SamConfig) -> Result<SamConnection, Error> {
+        SamConnection::open(config.tcp_addr(), config)
+    }
+
+    fn open<A: ToSocketAddrs>(addr: A, config: &SamConfig) -> Result<SamConnection, Error> {
+        let tcp_stream = match config.timeouts.connect {
+            Some(timeout) => connect_timeout(addr, timeout)?,
+            None => TcpStream::connect(addr)?
+        };
+        let reader = BufReader::new(tcp_stream.try_clone()?);
+        let mut conn = SamConnection {
+            conn: tcp_stream,
+            reader,
+            config: config.clone(),
             current_version: String::from("3.0")
         };
-        match conn.handshake() {
-            Ok(m) => {
-                if !m["VERSION"].is_empty() {
-                    conn.current_version = m["VERSION"].clone();
-                }
-                if m["RESULT"].eq("NOVERSION") {
-                    return Err(Error::new(ErrorKind::InvalidInput, "No version"));
+        let m = conn.handshake()?;
+        match m.get("RESULT").map(String::as_str) {
+            Some("NOVERSION") => Err(Error::new(ErrorKind::InvalidInput, "No version")),
+            Some("I2P_ERROR") => Err(Error::new(ErrorKind::ConnectionRefused, m.get("MESSAGE").cloned().unwrap_or_default())),
+            _ => {
+                if let Some(version) = m.get("VERSION").filter(|v| !v.is_empty()) {
+                    conn.current_version = version.clone();
                 }
-                if m["RESULT"].eq("I2P_ERROR") {
-                    return Err(Error::new(ErrorKind::ConnectionRefused, m["MESSAGE"].clone()));
-                }
-                return Ok(conn)
-            },
-            Err(e) => return Err(e)
+                Ok(conn)
+            }
         }
     }
 
     pub fn naming_lookup(&mut self, name: &str) -> Result<String, Error> {
         let create_naming_lookup_msg = format!("NAMING LOOKUP NAME={} \n", name);
         let ret = self.send(create_naming_lookup_msg, sam_naming_reply)?;
-        Ok(ret["VALUE"].clone())
+        ret.get("VALUE").cloned()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "NAMING REPLY without VALUE"))
     }
 
     pub fn gen(&mut self, sig_type: SigType) -> Result<(String,String), Error> {
         let create_gen_msg = format!("DEST GENERATE SIGNATURE_TYPE={} \n", sig_type.as_string());
         let ret = self.send(create_gen_msg, gen_reply)?;
-        Ok((ret["PUB"].clone(),ret["PRIV"].clone()))
+        match (ret.get("PUB"), ret.get("PRIV")) {
+            (Some(public), Some(private)) => Ok((public.clone(), private.clone())),
+            _ => Err(Error::new(ErrorKind::InvalidData, "DEST REPLY without PUB/PRIV"))
+        }
     }
 
     pub fn duplicate(&self) -> io::Result<SamConnection> {
-        self.conn.try_clone().map(|s| SamConnection {
-            conn: s,
-            min_version: self.min_version.clone(),
-            max_version: self.max_version.clone(),
+        Ok(SamConnection {
+            conn: self.conn.try_clone()?,
+            reader: BufReader::new(self.conn.try_clone()?),
+            config: self.config.clone(),
             current_version: self.current_version.clone() })
     }
 
-    /// Ping request to peer based on established session
+    // Ping request to peer based on established session
     // pub fn ping(&mut self, msg: &str) -> Option<String> {
     //     match self.send(format!("PING {}", msg), pong_received) {
     //         Ok(ret) => {
@@ -227,15 +574,7 @@ impl SamConnection {
     //     }
     // }
 
-    // pub fn site(&mut self, host_dest: &str) -> Result<Vec<u8>, Error> {
-    //     let local_dest = "";
-    //     let send_env_msg = format!("DATAGRAM SEND FROM={} DESTINATION={} SIZE={} MSG={} \n",
-    //                                local_dest, host_dest, 0, "");
-    //     info!("Sending site request...");
-    //     self.send(send_env_msg, datagram_received).unwrap()
-    // }
-
-    /// Listener waiting for Ping request from peer on established session
+    // Listener waiting for Ping request from peer on established session
     // pub fn pong(&mut self) -> Result<Packet, Error> {
     //     info!("Waiting on remote ping...");
     //     let ret = self.receive(ping_received)?;
@@ -250,39 +589,53 @@ impl SamConnection {
     //         Some(env)))
     // }
 
-    pub fn send_msg(&mut self, to: String, msg: Vec<u8>) {
-        let enc_msg = base64::encode(msg);
-        let send_env_msg = format!("DATAGRAM SEND DESTINATION={} SIZE={} \n{}\n", to, enc_msg.len(), enc_msg.as_str());
-        if send_env_msg.len() > 31_500 {
-            warn!("Message length is greater than 31.5KB; recommended to stay below this and ideally less than 11KB.")
-        } else if send_env_msg.len() > 61_500 {
-            warn!("Unable to send messages greater than 61.5KB (tunnel limit). Rejecting.");
-            return;
-        }
+    pub fn send_msg(&mut self, to: String, msg: Vec<u8>) -> Result<(), Error> {
+        self.send_datagram(&to, 0, 0, &msg)
+    }
+
+    /// Send a datagram from and to the given ports (0 for none); fails with
+    /// `ErrorKind::InvalidInput` when it is too large to send.
+    pub fn send_datagram(&mut self, to: &str, from_port: u16, to_port: u16, msg: &[u8]) -> Result<(), Error> {
+        let send_env_msg = datagram_send_msg(to, from_port, to_port, msg)?;
         info!("Sending packet (size={})...", send_env_msg.len());
-        self.send_async(send_env_msg);
+        self.send_async(send_env_msg)?;
         info!("Msg sent.");
+        Ok(())
     }
 
+    /// Wait for the next datagram for at most the idle timeout
     pub fn recv_msg(&mut self) -> Result<(String,Vec<u8>), Error> {
+        self.recv_datagram().map(|d| (d.from.to_string(), d.payload))
+    }
+
+    /// Wait for the next datagram for at most `timeout`, failing with `ErrorKind::TimedOut`;
+    /// suitable for polling loops as no partial datagram is consumed on timeout.
+    pub fn recv_msg_timeout(&mut self, timeout: Duration) -> Result<(String,Vec<u8>), Error> {
+        self.recv_datagram_timeout(Some(timeout)).map(|d| (d.from.to_string(), d.payload))
+    }
+
+    /// Like [`SamConnection::recv_msg`], including the ports
+    pub fn recv_datagram(&mut self) -> Result<ReceivedDatagram, Error> {
+        let timeout = self.config.timeouts.idle;
+        self.recv_datagram_timeout(timeout)
+    }
+
+    fn recv_datagram_timeout(&mut self, timeout: Option<Duration>) -> Result<ReceivedDatagram, Error> {
         info!("Waiting on msg...");
-        let res = self.receive(datagram_received);
-        let ret = res.unwrap();
-        let size :usize = ret["SIZE"].clone().parse().unwrap();
-        // TODO: Figure out why the header gets appended to the body so we don't have to strip it off
-        let jacked_msg = ret["MSG"].clone();
-        let enc_msg = jacked_msg.split_at(size).0;
-        let dec_msg_bytes = base64::decode(enc_msg).unwrap();
-        let from = ret["DESTINATION"].clone();
-        Ok((from, dec_msg_bytes))
+        self.wait_readable(timeout)?;
+        // Once a datagram starts arriving, the rest of it is bounded by the reply timeout
+        self.set_timeouts(self.config.timeouts.reply)?;
+        read_datagram(&mut self.reader).map_err(|e| timed_out(e, "Timed out reading datagram"))
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum SessionStyle {
     Datagram,
     Raw,
     Stream,
+    /// SAMv3.3 primary session; subsessions are added to it
+    Primary,
 }
 
 impl SessionStyle {
@@ -291,6 +644,7 @@ impl SessionStyle {
             SessionStyle::Datagram => "DATAGRAM",
             SessionStyle::Raw => "RAW",
             SessionStyle::Stream => "STREAM",
+            SessionStyle::Primary => "PRIMARY",
         }
     }
 }
@@ -302,6 +656,7 @@ impl TryFrom<&str> for SessionStyle {
             "DATAGRAM" => Ok(SessionStyle::Datagram),
             "RAW" => Ok(SessionStyle::Raw),
             "STREAM" => Ok(SessionStyle::Stream),
+            "PRIMARY" => Ok(SessionStyle::Primary),
             _ => Err(())
         }
     }
@@ -309,9 +664,13 @@ impl TryFrom<&str> for SessionStyle {
 
 pub struct Session {
     sam: SamConnection,
-    local_full_dest: String,
+    nickname: String,
+    /// Sends datagrams to the UDP port of the bridge; bound on first use
+    udp: Option<UdpSocket>,
+    local_full_dest: Zeroizing<String>,
     local_dest: String,
-    style: SessionStyle
+    style: SessionStyle,
+    resolver: Arc<Resolver>,
 }
 
 impl Session {
@@ -323,14 +682,54 @@ impl Session {
         min_version: &str,
         max_version: &str,
     ) -> Result<Session, Error> {
-        let mut sam = SamConnection::connect(sam_addr, min_version, max_version)?;
-        let create_session_msg = format!("SESSION CREATE STYLE={} ID={} DESTINATION={} \n", style.string(), nickname, destination);
-        let ret = sam.send(create_session_msg, sam_session_status)?;
-        let local_full_dest = ret["DESTINATION"].clone();
-        info!("local_full_dest (size={}): {}",local_full_dest.len(),local_full_dest);
+        let sam = SamConnection::connect(sam_addr, min_version, max_version)?;
+        Session::establish(sam, destination, nickname, style, &[], Arc::new(Resolver::from_address_book()))
+    }
+
+    /// Create a session on the SAM bridge described by `config`, passing `options`
+    /// (e.g. `inbound.length=2`) through to SESSION CREATE. The session gets a resolver of its own;
+    /// use [`Session::create_with_resolver`] to share one between sessions.
+    pub fn create_with(
+        config: &SamConfig,
+        destination: &str,
+        nickname: &str,
+        style: SessionStyle,
+        options: &[(String, String)],
+    ) -> Result<Session, Error> {
+        Session::create_with_resolver(config, destination, nickname, style, options, Arc::new(Resolver::from_address_book()))
+    }
+
+    /// Like [`Session::create_with`] but sharing `resolver`, e.g. the one of the session being replaced
+    pub fn create_with_resolver(
+        config: &SamConfig,
+        destination: &str,
+        nickname: &str,
+        style: SessionStyle,
+        options: &[(String, String)],
+        resolver: Arc<Resolver>,
+    ) -> Result<Session, Error> {
+        let sam = SamConnection::connect_with(config)?;
+        Session::establish(sam, destination, nickname, style, options, resolver)
+    }
+
+    fn establish(
+        mut sam: SamConnection,
+        destination: &str,
+        nickname: &str,
+        style: SessionStyle,
+        options: &[(String, String)],
+        resolver: Arc<Resolver>,
+    ) -> Result<Session, Error> {
+        if style == SessionStyle::Primary && !version_at_least(&sam.current_version, 3, 3) {
+            return Err(Error::new(ErrorKind::Unsupported,
+                                  format!("PRIMARY sessions need SAM 3.3, the bridge negotiated {}", sam.current_version)));
+        }
+        let mut ret = sam.send(session_create_msg(style, nickname, destination, options), sam_session_status)?;
+        let local_full_dest = ret.remove("DESTINATION").map(Zeroizing::new)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "SESSION STATUS without DESTINATION"))?;
         let local_dest = sam.naming_lookup("ME")?;
         info!("local_dest (size={}): {}",local_dest.len(),local_dest);
-        Ok(Session { sam, local_full_dest, local_dest, style })
+        Ok(Session { sam, nickname: String::from(nickname), udp: None, local_full_dest, local_dest, style, resolver })
     }
 
     pub fn sam_api(&self) -> io::Result<SocketAddr> {
@@ -341,12 +740,30 @@ impl Session {
         self.sam.naming_lookup(name)
     }
 
+    /// Resolver for names and b32 addresses used by this session; shared with its duplicates
+    pub fn resolver(&self) -> &Arc<Resolver> {
+        &self.resolver
+    }
+
+    pub fn set_resolver(&mut self, resolver: Arc<Resolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Full destination for `dest`, see [`Resolver::resolve`]
+    pub fn resolve(&mut self, dest: &Destination) -> Result<Destination, Error> {
+        let resolver = self.resolver.clone();
+        resolver.resolve(dest, |name| self.sam.naming_lookup(name))
+    }
+
     pub fn duplicate(&self) -> io::Result<Session> {
         self.sam.duplicate().map( |s | Session {
             sam: s,
+            nickname: self.nickname.clone(),
+            udp: None,
             local_full_dest: self.local_full_dest.clone(),
             local_dest: self.local_dest.clone(),
-            style: SessionStyle::try_from(self.style.string()).unwrap()
+            style: SessionStyle::try_from(self.style.string()).unwrap(),
+            resolver: self.resolver.clone(),
         })
     }
 
@@ -354,33 +771,275 @@ impl Session {
         self.sam.gen(sig_type)
     }
 
-    pub fn send_msg(&mut self, to: String, msg: Vec<u8>) {
-        self.sam.send_msg(to, msg);
+    /// Send a datagram to the first of `to` that resolves, to its port if not 0
+    pub fn send_msg<A: ToI2pSocketAddrs>(&mut self, to: A, msg: Vec<u8>) -> Result<(), Error> {
+        self.send_datagram(to, 0, &msg)
+    }
+
+    /// Like [`Session::send_msg`], from `from_port` (0 for none). Datagrams go to the UDP port of the
+    /// bridge ([`SamConfig::udp_addr`]); fails with `ErrorKind::InvalidInput` when too large to send.
+    pub fn send_datagram<A: ToI2pSocketAddrs>(&mut self, to: A, from_port: u16, msg: &[u8]) -> Result<(), Error> {
+        let nickname = self.nickname.clone();
+        self.send_datagram_as(&nickname, to, from_port, msg)
+    }
+
+    /// Like [`Session::send_datagram`], from the DATAGRAM subsession `nickname` of this PRIMARY session
+    pub fn send_datagram_as<A: ToI2pSocketAddrs>(&mut self, nickname: &str, to: A, from_port: u16, msg: &[u8]) -> Result<(), Error> {
+        let (dest, to_port) = each_addr(to, |addr| self.resolve(addr.dest()).map(|dest| (dest, addr.port())))?;
+        let datagram = datagram_udp_msg(nickname, dest.as_str(), from_port, to_port, msg)?;
+        // Sending over UDP cannot tell whether the session still exists
+        self.sam.check_alive()?;
+        let (bridge, local) = udp_addrs(&self.sam.config)?;
+        if self.udp.is_none() {
+            self.udp = Some(UdpSocket::bind(local)?);
+        }
+        info!("Sending packet (size={}) to {}...", datagram.len(), bridge);
+        self.udp.as_ref().unwrap().send_to(&datagram, bridge)?;
+        Ok(())
     }
 
     pub fn recv_msg(&mut self) -> Result<(String,Vec<u8>),Error> {
         self.sam.recv_msg()
     }
 
+    pub fn recv_msg_timeout(&mut self, timeout: Duration) -> Result<(String,Vec<u8>),Error> {
+        self.sam.recv_msg_timeout(timeout)
+    }
+
+    /// Like [`Session::recv_msg`], including the ports
+    pub fn recv_datagram(&mut self) -> Result<ReceivedDatagram, Error> {
+        self.sam.recv_datagram()
+    }
+
     // pub fn ping(&mut self, msg: &str) -> Option<String> {
     //     self.sam.ping(msg)
     // }
 
-    // pub fn site(&mut self, host_dest: &str) -> Result<Vec<u8>, Error> {
-    //     self.sam.site(host_dest)
-    // }
+    /// SESSION ADD the subsession `nickname` to this PRIMARY session (SAM 3.3); its streams and datagrams
+    /// then use `nickname` as ID. DATAGRAM and RAW subsessions need a `PORT` option to receive on.
+    pub fn add_subsession(&mut self, style: SessionStyle, nickname: &str, options: &[(String, String)]) -> Result<(), Error> {
+        if self.style != SessionStyle::Primary || style == SessionStyle::Primary {
+            return Err(Error::new(ErrorKind::InvalidInput, "Subsessions are added to a PRIMARY session and cannot be PRIMARY"));
+        }
+        self.sam.send(session_add_msg(style, nickname, options), sam_session_status).map(|_| ())
+    }
+
+    /// SESSION REMOVE the subsession `nickname` of this PRIMARY session
+    pub fn remove_subsession(&mut self, nickname: &str) -> Result<(), Error> {
+        self.sam.send(format!("SESSION REMOVE ID={} \n", nickname), sam_session_status).map(|_| ())
+    }
+
+    /// STREAM FORWARD incoming connections of this (STREAM) session to `host:port`; forwarding lasts as
+    /// long as the returned connection is open.
+    pub fn forward(&self, nickname: &str, host: Option<&str>, port: u16, silent: bool) -> Result<SamConnection, Error> {
+        let mut sam = SamConnection::connect_with(&self.sam.config)?;
+        let mut forward_msg = format!("STREAM FORWARD ID={} PORT={}", nickname, port);
+        if let Some(host) = host {
+            forward_msg.push_str(&format!(" HOST={}", host));
+        }
+        forward_msg.push_str(&format!(" SILENT={} \n", silent));
+        sam.send_stream(forward_msg)?;
+        Ok(sam)
+    }
+
+    /// STREAM ACCEPT on this (STREAM) session, blocking until a peer connects.
+    pub fn accept(&self, nickname: &str) -> Result<StreamConnect, Error> {
+        let (sam, peer, local_port) = self.accept_sam(nickname, None)?;
+        Ok(StreamConnect::from_sam(sam, Arc::new(self.duplicate()?), peer, local_port))
+    }
+
+    /// Accepted connection with the peer address and the local port it connected to
+    /// (when given, `pending` can cancel it until then)
+    fn accept_sam(&self, nickname: &str, pending: Option<&PendingAccept>) -> Result<(SamConnection, I2pSocketAddr, u16), Error> {
+        let mut sam = SamConnection::connect_with(&self.sam.config)?;
+        if let Some(pending) = pending {
+            pending.register(&sam.conn)?;
+        }
+        let line = sam.send_stream(format!("STREAM ACCEPT ID={} SILENT=false \n", nickname))
+            // The router announces the peer with "$destination [FROM_PORT=n TO_PORT=n]" once connected
+            .and_then(|_| sam.set_timeouts(None))
+            .and_then(|_| read_line_bounded(&mut sam.reader));
+        if let Some(pending) = pending {
+            pending.clear();
+        }
+        let line = line?;
+        let (peer, local_port) = parse_stream_peer(&line)?;
+        let idle = sam.config.timeouts.idle;
+        sam.set_timeouts(idle)?;
+        Ok((sam, peer, local_port))
+    }
+
+    /// STREAM CONNECT to the first of `addr` that succeeds on this (STREAM) session, over a new
+    /// connection to the bridge.
+    pub fn connect<A: ToI2pSocketAddrs>(&self, nickname: &str, addr: A) -> Result<StreamConnect, Error> {
+        let (sam, peer) = each_addr(addr, |addr| self.connect_sam(nickname, addr, 0))?;
+        Ok(StreamConnect::from_sam(sam, Arc::new(self.duplicate()?), peer, 0))
+    }
+
+    /// Names and b32 addresses are resolved first so the stream knows the peer's full destination.
+    fn connect_sam(&self, nickname: &str, addr: &I2pSocketAddr, from_port: u16) -> Result<(SamConnection, I2pSocketAddr), Error> {
+        let mut sam = SamConnection::connect_with(&self.sam.config)?;
+        let peer_dest = self.resolver.resolve(addr.dest(), |name| sam.naming_lookup(name))?;
+        let create_stream_msg = format!("STREAM CONNECT ID={} DESTINATION={} SILENT=false{}\n",
+                                        nickname, peer_dest, port_options(from_port, addr.port()));
+        sam.send_stream(create_stream_msg)?;
+        let idle = sam.config.timeouts.idle;
+        sam.set_timeouts(idle)?;
+        Ok((sam, I2pSocketAddr::new(peer_dest, addr.port())))
+    }
 
     pub fn close(&mut self) {
         self.sam.conn.shutdown(Shutdown::Both).unwrap();
     }
 }
 
+/// A STREAM session created once and shared by any number of outbound and inbound streams, so they
+/// all use the same destination and tunnels. Each stream gets its own connection to the bridge.
+#[derive(Clone)]
+pub struct StreamSession {
+    session: Arc<Session>,
+    nickname: String,
+}
+
+impl StreamSession {
+    /// Create the STREAM session `nickname` with `destination` (`TRANSIENT` or private keys).
+    pub fn create(config: &SamConfig, destination: &str, nickname: &str, options: &[(String, String)]) -> Result<StreamSession, Error> {
+        StreamSession::create_with_resolver(config, destination, nickname, options, Arc::new(Resolver::from_address_book()))
+    }
+
+    /// Like [`StreamSession::create`] but sharing `resolver` with other sessions
+    pub fn create_with_resolver(
+        config: &SamConfig,
+        destination: &str,
+        nickname: &str,
+        options: &[(String, String)],
+        resolver: Arc<Resolver>,
+    ) -> Result<StreamSession, Error> {
+        let session = Session::create_with_resolver(config, destination, nickname, SessionStyle::Stream, options, resolver)?;
+        Ok(StreamSession { session: Arc::new(session), nickname: String::from(nickname) })
+    }
+
+    /// Create the STREAM session `alias` with its keys in [`KeyStore::open_default`], generating and
+    /// saving them the first time, so the session keeps its destination across restarts.
+    pub fn create_persistent(config: &SamConfig, alias: &str, options: &[(String, String)]) -> Result<StreamSession, Error> {
+        StreamSession::create_persistent_in(config, &KeyStore::open_default()?, alias, options)
+    }
+
+    /// [`StreamSession::create_persistent`] with the keys in `store`
+    pub fn create_persistent_in(config: &SamConfig, store: &KeyStore, alias: &str, options: &[(String, String)]) -> Result<StreamSession, Error> {
+        StreamSession::create_persistent_with_resolver(config, store, alias, options, Arc::new(Resolver::from_address_book()))
+    }
+
+    /// Like [`StreamSession::create_persistent_in`] but sharing `resolver` with other sessions
+    pub fn create_persistent_with_resolver(
+        config: &SamConfig,
+        store: &KeyStore,
+        alias: &str,
+        options: &[(String, String)],
+        resolver: Arc<Resolver>,
+    ) -> Result<StreamSession, Error> {
+        let keys = load_or_create_keys(config, store, alias, SessionStyle::Stream, &RetryPolicy::none(), &resolver)?;
+        let session = Session::create_with_resolver(config, &keys, alias, SessionStyle::Stream, options, resolver)?;
+        Ok(StreamSession { session: Arc::new(session), nickname: String::from(alias) })
+    }
+
+    /// Destination + private keys, to create the session again with the same identity
+    pub fn local_full_dest(&self) -> &str {
+        &self.session.local_full_dest
+    }
+
+    pub fn local_dest(&self) -> &str {
+        &self.session.local_dest
+    }
+
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    /// Resolver for names and b32 addresses, e.g. to add entries or clear its cache
+    pub fn resolver(&self) -> &Arc<Resolver> {
+        &self.session.resolver
+    }
+
+    /// Full destination for `dest`, looked up over a new connection to the bridge if needed
+    pub fn resolve(&self, dest: &Destination) -> Result<Destination, Error> {
+        self.session.resolver.resolve(dest, |name| SamConnection::connect_with(&self.session.sam.config)?.naming_lookup(name))
+    }
+
+    /// Open a stream to the first of `addr` that succeeds, e.g. `"example.i2p:80"` or `(dest, 80)`
+    pub fn connect<A: ToI2pSocketAddrs>(&self, addr: A) -> Result<StreamConnect, Error> {
+        self.connect_from(addr, 0)
+    }
+
+    /// Open a stream to the first of `addr` that succeeds, from local port `from_port`
+    pub fn connect_from<A: ToI2pSocketAddrs>(&self, addr: A, from_port: u16) -> Result<StreamConnect, Error> {
+        let (sam, peer) = each_addr(addr, |addr| self.session.connect_sam(&self.nickname, addr, from_port))?;
+        Ok(StreamConnect::from_sam(sam, self.session.clone(), peer, from_port))
+    }
+
+    /// Block until a peer connects
+    pub fn accept(&self) -> Result<StreamConnect, Error> {
+        let (sam, peer, local_port) = self.session.accept_sam(&self.nickname, None)?;
+        Ok(StreamConnect::from_sam(sam, self.session.clone(), peer, local_port))
+    }
+
+    /// Like [`StreamSession::accept`], failing with `ErrorKind::ConnectionAborted` once `pending` is cancelled
+    pub(crate) fn accept_cancellable(&self, pending: &PendingAccept) -> Result<StreamConnect, Error> {
+        let (sam, peer, local_port) = self.session.accept_sam(&self.nickname, Some(pending))?;
+        Ok(StreamConnect::from_sam(sam, self.session.clone(), peer, local_port))
+    }
+
+    /// End the session on the router, failing pending accepts, even while clones are still alive
+    pub fn close(&self) {
+        let _ = self.session.sam.conn.shutdown(Shutdown::Both);
+    }
+}
+
+/// STREAM ACCEPT another thread can cancel, as closing the session need not end it on every bridge
+#[derive(Default)]
+pub(crate) struct PendingAccept {
+    /// Whether it was cancelled, and the connection of the accept in progress
+    state: Mutex<(bool, Option<TcpStream>)>,
+}
+
+impl PendingAccept {
+    fn register(&self, conn: &TcpStream) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.0 {
+            return Err(Error::new(ErrorKind::ConnectionAborted, "STREAM ACCEPT cancelled"));
+        }
+        state.1 = Some(conn.try_clone()?);
+        Ok(())
+    }
+
+    fn clear(&self) {
+        self.state.lock().unwrap().1 = None;
+    }
+
+    /// Fail the accept in progress, if any, and every later one
+    pub(crate) fn cancel(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = true;
+        if let Some(conn) = state.1.take() {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// A stream on a STREAM session. Clones and halves share the socket and its read buffer, so bytes
+/// are never lost between them, and keep the session alive until the last of them is dropped.
 pub struct StreamConnect {
-    sam: SamConnection,
-    session: Session,
-    peer_dest: String,
-    peer_port: u16,
-    local_port: u16,
+    conn: TcpStream,
+    reader: Arc<Mutex<BufReader<TcpStream>>>,
+    session: Arc<Session>,
+    peer: I2pSocketAddr,
+    local: I2pSocketAddr,
+}
+
+/// Reads from a shared stream buffer; a poisoned lock only means another reader panicked mid-read.
+fn read_shared(reader: &Mutex<BufReader<TcpStream>>, buf: &mut [u8]) -> io::Result<usize> {
+    let mut reader = reader.lock().unwrap_or_else(|e| e.into_inner());
+    reader.read(buf).map_err(|e| timed_out(e, "Timed out reading from stream"))
 }
 
 impl StreamConnect {
@@ -392,196 +1051,255 @@ impl StreamConnect {
         min_version: &str,
         max_version: &str,
     ) -> io::Result<StreamConnect> {
-        let mut session = Session::create(sam_addr, "TRANSIENT", nickname, SessionStyle::Stream, min_version, max_version)?;
-        let mut sam = SamConnection::connect(session.sam_api()?, min_version, max_version).unwrap();
-        let create_stream_msg = format!("STREAM CONNECT ID={} DESTINATION={} SILENT=false TO_PORT={}\n", nickname, destination, port);
-        sam.send(create_stream_msg, sam_stream_status)?;
-        let peer_dest = session.naming_lookup(destination)?;
-        Ok(StreamConnect { sam, session, peer_dest, peer_port: port, local_port: 0})
+        let session = Session::create(sam_addr, "TRANSIENT", nickname, SessionStyle::Stream, min_version, max_version)?;
+        let (sam, peer) = each_addr((destination, port), |addr| session.connect_sam(nickname, addr, 0))?;
+        Ok(StreamConnect::from_sam(sam, Arc::new(session), peer, 0))
     }
 
-    pub fn peer_addr(&self) -> io::Result<(String, u16)> {
-        Ok((self.peer_dest.clone(), self.peer_port))
+    fn from_sam(sam: SamConnection, session: Arc<Session>, peer: I2pSocketAddr, local_port: u16) -> StreamConnect {
+        let local = I2pSocketAddr::new(Destination::new(&session.local_dest), local_port);
+        StreamConnect {
+            conn: sam.conn,
+            reader: Arc::new(Mutex::new(sam.reader)),
+            session,
+            peer,
+            local,
+        }
     }
 
-    pub fn local_addr(&self) -> io::Result<(String, u16)> {
-        Ok((self.session.local_full_dest.clone(), self.local_port))
+    /// Full destination of the peer and its port (FROM_PORT of accepted streams, TO_PORT of connected ones)
+    pub fn peer_addr(&self) -> io::Result<I2pSocketAddr> {
+        Ok(self.peer.clone())
+    }
+
+    /// Public destination of the session and the local port (TO_PORT of accepted streams, FROM_PORT of connected ones)
+    pub fn local_addr(&self) -> io::Result<I2pSocketAddr> {
+        Ok(self.local.clone())
     }
 
     pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
-        self.sam.conn.shutdown(how)
+        self.conn.shutdown(how)
     }
 
-    pub fn duplicate(&self) -> io::Result<StreamConnect> {
+    /// Read timeout on the stream; the idle timeout by default
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.conn.set_read_timeout(timeout)
+    }
+
+    /// Write timeout on the stream; the idle timeout by default
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.conn.set_write_timeout(timeout)
+    }
+
+    /// Another handle to the same stream, like [`TcpStream::try_clone`]
+    pub fn try_clone(&self) -> io::Result<StreamConnect> {
         Ok(StreamConnect {
-            sam: self.sam.duplicate()?,
-            session: self.session.duplicate()?,
-            peer_dest: self.peer_dest.clone(),
-            peer_port: self.peer_port,
-            local_port: self.local_port,
+            conn: self.conn.try_clone()?,
+            reader: self.reader.clone(),
+            session: self.session.clone(),
+            peer: self.peer.clone(),
+            local: self.local.clone(),
         })
     }
+
+    #[deprecated(note = "use try_clone")]
+    pub fn duplicate(&self) -> io::Result<StreamConnect> {
+        self.try_clone()
+    }
+
+    /// Owned read and write halves of this stream that can be moved to different threads
+    pub fn split(&self) -> io::Result<(ReadHalf, WriteHalf)> {
+        self.try_clone().map(StreamConnect::into_split)
+    }
+
+    /// Split into owned read and write halves that can be moved to different threads
+    pub fn into_split(self) -> (ReadHalf, WriteHalf) {
+        let read = ReadHalf {
+            reader: self.reader,
+            _session: self.session.clone(),
+            peer: self.peer.clone(),
+        };
+        let write = WriteHalf {
+            conn: self.conn,
+            _session: self.session,
+            peer: self.peer,
+        };
+        (read, write)
+    }
 }
 
 impl Read for StreamConnect {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.sam.conn.read(buf)
+        read_shared(&self.reader, buf)
     }
 }
 
 impl Write for StreamConnect {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.sam.conn.write(buf)
+        self.conn.write(buf).map_err(|e| timed_out(e, "Timed out writing to stream"))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+/// Read half of a [`StreamConnect`]
+pub struct ReadHalf {
+    reader: Arc<Mutex<BufReader<TcpStream>>>,
+    /// Keeps the session open while the half is in use
+    _session: Arc<Session>,
+    peer: I2pSocketAddr,
+}
+
+impl ReadHalf {
+    pub fn peer_addr(&self) -> io::Result<I2pSocketAddr> {
+        Ok(self.peer.clone())
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        reader.get_ref().set_read_timeout(timeout)
+    }
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_shared(&self.reader, buf)
+    }
+}
+
+/// Write half of a [`StreamConnect`]
+pub struct WriteHalf {
+    conn: TcpStream,
+    /// Keeps the session open while the half is in use
+    _session: Arc<Session>,
+    peer: I2pSocketAddr,
+}
+
+impl WriteHalf {
+    pub fn peer_addr(&self) -> io::Result<I2pSocketAddr> {
+        Ok(self.peer.clone())
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.conn.set_write_timeout(timeout)
+    }
+
+    /// Close the writing side, signalling end of stream to the peer; the read half keeps working
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.conn.shutdown(Shutdown::Write)
+    }
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.write(buf).map_err(|e| timed_out(e, "Timed out writing to stream"))
     }
     fn flush(&mut self) -> io::Result<()> {
-        self.sam.conn.flush()
+        self.conn.flush()
     }
 }
 
+/// Copy data both ways between a TCP connection and an I2P stream until both sides are done,
+/// half-closing each side as the other finishes sending. Returns the bytes sent to and received from I2P.
+pub(crate) fn pipe(tcp: TcpStream, stream: StreamConnect) -> io::Result<(u64, u64)> {
+    let (mut i2p_read, mut i2p_write) = stream.into_split();
+    let mut tcp_read = tcp.try_clone()?;
+    let mut tcp_write = tcp;
+    let outbound = thread::spawn(move || {
+        let res = io::copy(&mut tcp_read, &mut i2p_write);
+        let _ = i2p_write.shutdown();
+        res
+    });
+    let inbound = io::copy(&mut i2p_read, &mut tcp_write);
+    // Unblock the outbound copy if the peer went away first
+    let _ = tcp_write.shutdown(if inbound.is_ok() { Shutdown::Write } else { Shutdown::Both });
+    let sent = outbound.join().map_err(|_| Error::new(ErrorKind::Other, "pipe thread panicked"))??;
+    Ok((sent, inbound?))
+}
+
 pub enum ClientType {
     Local    = 0,
     Embedded = 1,
 }
 
+/// Connection state changes reported to the handler set with [`I2PClient::set_event_handler`]
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The session's control socket broke (e.g. the router restarted); reconnecting
+    Disconnected(String),
+    /// Session recreated with the same destination and options, forwards replayed
+    Reconnected,
+    /// Reconnecting gave up per the retry policy; the error is returned to the caller
+    ReconnectFailed(String),
+}
+
+/// Registered STREAM FORWARD, replayed after reconnecting
+struct Forward {
+    host: Option<String>,
+    port: u16,
+    silent: bool,
+    sam: SamConnection,
+}
+
+/// Subsession added to a PRIMARY session, replayed after reconnecting
+struct Subsession {
+    style: SessionStyle,
+    nickname: String,
+    options: Vec<(String, String)>,
+}
+
 pub struct I2PClient {
     /// Destination used for establishing a Session (884 bytes): destination + priv key + signing key
-    pub local_full_dest: String,
+    pub local_full_dest: Zeroizing<String>,
     /// Destination used for sending a Datagram (516 bytes): destination
     pub local_dest: String,
+    sam: SamConfig,
+    alias: String,
+    style: SessionStyle,
+    options: Vec<(String, String)>,
+    retry_policy: RetryPolicy,
+    auto_reconnect: bool,
+    forwards: Vec<Forward>,
+    subsessions: Vec<Subsession>,
+    event_handler: Option<EventHandler>,
     session: Session
 }
 
+type EventHandler = Box<dyn Fn(&ClientEvent) + Send>;
+
+/// Errors meaning the socket to the SAM bridge is gone rather than the request failing; streams open
+/// new sockets, which are refused while the router is down and get INVALID_ID (`NotConnected`) after
+/// it restarted
+fn is_disconnect(e: &Error) -> bool {
+    matches!(e.kind(),
+        ErrorKind::ConnectionRefused
+        | ErrorKind::BrokenPipe
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::UnexpectedEof)
+}
+
 impl I2PClient {
+    /// Connect to the SAM bridge given by the default config file and the SAM_* environment variables (see [`SamConfig::load`]).
     pub fn new(use_local: bool, alias: String, min_version: &str, max_version: &str, max_connection_attempts: u8) -> Result<I2PClient, Error> {
-        info!("{}", "Initializing I2P Client...");
-        // Build paths
-        let home = dirs::home_dir().unwrap();
-        info!("home directory: {}", home.to_str().unwrap());
-
-        let mut i2p_home = home.clone();
-        i2p_home.push(".i2p");
-        info!("i2p directory: {}", i2p_home.to_str().unwrap());
-
-        let mut i2p_pid_file = i2p_home.clone();
-        i2p_pid_file.push(I2P_PID);
-        info!("i2p pid file: {}", i2p_pid_file.to_str().unwrap());
-
-        let mut i2p_status_file = i2p_home.clone();
-        i2p_status_file.push(I2P_STATUS);
-        info!("i2p status file: {}", i2p_status_file.to_str().unwrap());
-
-        let mut i2p_local_dest_file = i2p_home.clone();
-        i2p_local_dest_file.push(alias.clone());
-        let i2p_local_dest_path = i2p_local_dest_file.to_str().unwrap();
-
-        let mut local_full_dest = String::new();
-        let mut local_dest = String::new();
-        let mut local_addr_loaded = false;
-
-        if use_local {
-            info!("i2p local dest file: {}", i2p_local_dest_path);
-
-            if Path::new(i2p_local_dest_path).exists() {
-                let mut i2p_local_dest_file = File::open(Path::new(i2p_local_dest_path)).unwrap();
-                match i2p_local_dest_file.read_to_string(&mut local_full_dest) {
-                    Ok(len) => {
-                        if len > 0 {
-                            local_addr_loaded = true;
-                            info!("dest from file ({}): {}", len, &local_full_dest);
-                        } else {
-                            info!("{}","dest file empty");
-                        }
-                    },
-                    Err(e) => warn!("{}", e)
-                }
-            }
-        }
-        if local_full_dest.is_empty() {
-            // Establish Session, write to local_dest, and set dest
-            match Session::create(DEFAULT_API,
-                                  "TRANSIENT",
-                                  alias.as_str(),
-                                  SessionStyle::Datagram,
-                                  min_version,
-                                  max_version,
-            ) {
-                Ok(session) => {
-                    local_full_dest = session.local_full_dest;
-                    local_dest = session.local_dest;
-                    if use_local && !local_addr_loaded {
-                        info!("Saving dest to file: {}",i2p_local_dest_path);
-                        match File::create(i2p_local_dest_path) {
-                            Ok(f) => {
-                                let mut d_file = f;
-                                d_file.write_all(local_full_dest.clone().as_bytes()).unwrap();
-                            },
-                            Err(e) => warn!("{}",e)
-                        }
-                    }
-                },
-                Err(err) => {
-                    warn!("Error: {}",err.to_string());
-                }
-            }
-        }
+        I2PClient::with_sam(SamConfig::load(None)?, use_local, alias, min_version, max_version, max_connection_attempts)
+    }
 
-        let mut attempts: u8 = 0;
-        loop {
-            info!("{}","Trying to create session...");
-            let res = Session::create(DEFAULT_API,
-                                      &local_full_dest.as_str(),
-                                      &alias.as_str(),
-                                      SessionStyle::Datagram,
-                                      min_version,
-                                      max_version);
-            if res.is_ok() {
-                info!("{}", "I2P Client initialized.");
-                return Ok(I2PClient {
-                    local_full_dest,
-                    local_dest,
-                    session: res.unwrap()
-                })
-            }
-            attempts += 1;
-            if attempts == max_connection_attempts {
-                return Err(Error::new(ErrorKind::ConnectionRefused, format!("Unable to connect: max attempts ({}) reached", max_connection_attempts)))
-            }
-            warn!("Unable to create Session ({})...waiting a few seconds...", res.err().unwrap());
-            thread::sleep(Duration::from_secs(3));
-        }
+    /// Connect to the SAM bridge at the provided address.
+    pub fn with_sam(sam: SamConfig, use_local: bool, alias: String, min_version: &str, max_version: &str, max_connection_attempts: u8) -> Result<I2PClient, Error> {
+        I2PClientBuilder::new()
+            .sam(sam)
+            .versions(min_version, max_version)
+            .use_local(use_local)
+            .alias(&alias)
+            .max_connection_attempts(max_connection_attempts)
+            .build()
     }
 
     pub fn aliases() -> HashMap<String,String> {
-        let home = dirs::home_dir().unwrap();
-        info!("home directory: {}", home.to_str().unwrap());
-
-        let mut i2p_home = home.clone();
-        i2p_home.push(".i2p");
-        info!("i2p directory: {}", i2p_home.to_str().unwrap());
-
-        let mut i2p_hosts = i2p_home.clone();
-        i2p_hosts.push(I2P_ADDR_BK);
-        info!("i2p personal address book: {}", i2p_hosts.to_str().unwrap());
-
-        let mut list: Vec<String> = Vec::new();
-        let mut m: HashMap<String,String> = HashMap::new();
-        if i2p_hosts.exists() {
-            let i2p_hosts_file = File::open(i2p_hosts).unwrap();
-            let reader = BufReader::new(i2p_hosts_file);
-            let mut first_line_skipped = false;
-            for line in reader.lines() {
-                if !first_line_skipped {
-                    first_line_skipped = true;
-                    continue;
-                }
-                list.push(line.unwrap());
-            }
-            for s in list {
-                let v: Vec<&str> = s.split('=').collect();
-                m.insert(String::from(v[0]), String::from(v[1]));
-            }
-        }
-        m
+        address_book().unwrap_or_default()
     }
 
     pub fn dest(alias: &str) -> String {
@@ -597,30 +1315,172 @@ impl I2PClient {
         }
     }
 
+    /// SAM bridge this client is connected to
+    pub fn sam_config(&self) -> &SamConfig {
+        &self.sam
+    }
+
+    /// Receive [`ClientEvent`]s, e.g. to log or count reconnects
+    pub fn set_event_handler<F: Fn(&ClientEvent) + Send + 'static>(&mut self, handler: F) {
+        self.event_handler = Some(Box::new(handler));
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        if let Some(handler) = &self.event_handler {
+            handler(&event);
+        }
+    }
+
+    /// Recreate the session with the same destination and options per the retry policy, then replay forwards.
+    pub fn reconnect(&mut self) -> Result<(), Error> {
+        let I2PClient { sam, local_full_dest, alias, style, options, retry_policy, session, .. } = &*self;
+        let session = match retry_policy.retry("Recreate session", |_| {
+            Session::create_with_resolver(sam, local_full_dest.as_str(), alias.as_str(), *style, options, session.resolver.clone())
+        }) {
+            Ok(session) => session,
+            Err(e) => {
+                self.emit(ClientEvent::ReconnectFailed(e.to_string()));
+                return Err(e);
+            }
+        };
+        self.session = session;
+        for sub in &self.subsessions {
+            if let Err(e) = self.session.add_subsession(sub.style, &sub.nickname, &sub.options) {
+                self.emit(ClientEvent::ReconnectFailed(e.to_string()));
+                return Err(e);
+            }
+        }
+        for i in 0..self.forwards.len() {
+            let f = &self.forwards[i];
+            match self.session.forward(&self.alias, f.host.as_deref(), f.port, f.silent) {
+                Ok(sam) => self.forwards[i].sam = sam,
+                Err(e) => {
+                    self.emit(ClientEvent::ReconnectFailed(e.to_string()));
+                    return Err(e);
+                }
+            }
+        }
+        info!("{}", "I2P Client reconnected.");
+        self.emit(ClientEvent::Reconnected);
+        Ok(())
+    }
+
+    /// Run `op` on the session; when the control socket turns out to be broken, reconnect and run it once more.
+    fn with_session<T, F>(&mut self, mut op: F) -> Result<T, Error>
+        where
+            F: FnMut(&mut Session) -> Result<T, Error>,
+    {
+        match op(&mut self.session) {
+            Err(e) if self.auto_reconnect && is_disconnect(&e) => {
+                warn!("Lost connection to SAM bridge ({})...reconnecting", e);
+                self.emit(ClientEvent::Disconnected(e.to_string()));
+                self.reconnect()?;
+                op(&mut self.session)
+            },
+            res => res
+        }
+    }
+
     /// Generate Public and Private keys IAW sig_type; return in tuple (PUB,PRIV)
     pub fn gen(&mut self, sig_type: SigType) -> Result<(String,String), Error> {
-        self.session.gen(sig_type)
+        self.with_session(|session| session.gen(sig_type))
+    }
+
+    /// Full destination for `dest`, looked up with the session's resolver
+    pub fn resolve(&mut self, dest: &Destination) -> Result<Destination, Error> {
+        self.with_session(|session| session.resolve(dest))
     }
 
-    /// Send to destination UTF-8 formatted bytes
-    pub fn send(&mut self, to: String, msg: Vec<u8>) {
-        self.session.send_msg(to, msg);
+    /// Send a datagram to the first of `to` that resolves, e.g. a destination, `name.i2p` or `<hash>.b32.i2p`
+    pub fn send<A: ToI2pSocketAddrs>(&mut self, to: A, msg: Vec<u8>) -> Result<(), Error> {
+        self.with_session(|session| session.send_msg(&to, msg.clone()))
+    }
+
+    /// Like [`I2PClient::send`], from `from_port` and to the port of `to` (0 for none)
+    pub fn send_datagram<A: ToI2pSocketAddrs>(&mut self, to: A, from_port: u16, msg: &[u8]) -> Result<(), Error> {
+        self.with_session(|session| session.send_datagram(&to, from_port, msg))
     }
 
     /// Receive tuple with from destination and message in UTF-8 formatted bytes
     pub fn receive(&mut self) -> Result<(String,Vec<u8>), Error> {
-        self.session.recv_msg()
+        self.with_session(|session| session.recv_msg())
+    }
+
+    /// Like [`I2PClient::receive`], including the sender's and the destination port
+    pub fn receive_datagram(&mut self) -> Result<ReceivedDatagram, Error> {
+        self.with_session(|session| session.recv_datagram())
+    }
+
+    /// Like [`I2PClient::receive`] but fails with `ErrorKind::TimedOut` when nothing arrives within `timeout`
+    pub fn receive_timeout(&mut self, timeout: Duration) -> Result<(String,Vec<u8>), Error> {
+        self.with_session(|session| session.recv_msg_timeout(timeout))
+    }
+
+    /// Forward incoming streams to `host:port` (STREAM sessions); re-registered after reconnecting.
+    pub fn forward(&mut self, host: Option<&str>, port: u16, silent: bool) -> Result<(), Error> {
+        let alias = self.alias.clone();
+        let sam = self.with_session(|session| session.forward(&alias, host, port, silent))?;
+        self.forwards.push(Forward { host: host.map(String::from), port, silent, sam });
+        Ok(())
+    }
+
+    /// Wait for an incoming stream (STREAM sessions); a pending accept survives reconnecting.
+    pub fn accept(&mut self) -> Result<StreamConnect, Error> {
+        let alias = self.alias.clone();
+        self.with_session(|session| session.accept(&alias))
+    }
+
+    /// Open a stream to the first of `addr` that succeeds on this client's (STREAM) session
+    pub fn connect<A: ToI2pSocketAddrs>(&mut self, addr: A) -> Result<StreamConnect, Error> {
+        let alias = self.alias.clone();
+        self.with_session(|session| session.connect(&alias, &addr))
+    }
+
+    /// Add the subsession `nickname` to this client's PRIMARY session; re-added after reconnecting.
+    pub fn add_subsession(&mut self, style: SessionStyle, nickname: &str, options: &[(&str, &str)]) -> Result<(), Error> {
+        let options: Vec<(String, String)> = options.iter().map(|&(k, v)| (String::from(k), String::from(v))).collect();
+        self.with_session(|session| session.add_subsession(style, nickname, &options))?;
+        self.subsessions.push(Subsession { style, nickname: String::from(nickname), options });
+        Ok(())
+    }
+
+    /// Remove the subsession `nickname` from this client's PRIMARY session
+    pub fn remove_subsession(&mut self, nickname: &str) -> Result<(), Error> {
+        self.with_session(|session| session.remove_subsession(nickname))?;
+        self.subsessions.retain(|sub| sub.nickname != nickname);
+        Ok(())
+    }
+
+    /// Like [`I2PClient::accept`], on the STREAM subsession `nickname`
+    pub fn accept_as(&mut self, nickname: &str) -> Result<StreamConnect, Error> {
+        self.with_session(|session| session.accept(nickname))
+    }
+
+    /// Like [`I2PClient::connect`], on the STREAM subsession `nickname`
+    pub fn connect_as<A: ToI2pSocketAddrs>(&mut self, nickname: &str, addr: A) -> Result<StreamConnect, Error> {
+        self.with_session(|session| session.connect(nickname, &addr))
+    }
+
+    /// Like [`I2PClient::send_datagram`], from the DATAGRAM subsession `nickname`
+    pub fn send_datagram_as<A: ToI2pSocketAddrs>(&mut self, nickname: &str, to: A, from_port: u16, msg: &[u8]) -> Result<(), Error> {
+        self.with_session(|session| session.send_datagram_as(nickname, &to, from_port, msg))
     }
 
     // pub fn ping(&mut self, msg: &str) -> Option<String> {
     //     self.session.ping(msg)
     // }
 
-    // pub fn site(&mut self, host: &str) -> Result<Vec<u8>, Error> {
-    //     let host_dest = I2PClient::dest(host);
-    //     info!("Sending request for site: {}", &host_dest);
-    //     self.session.site(host_dest.as_str())
-    // }
+    /// HTTP/1.1 GET of an `http://` eepsite URL over a stream (STREAM sessions), following redirects
+    /// that stay on I2P.
+    pub fn fetch(&mut self, url: &str) -> Result<Response, Error> {
+        site::fetch_with(url, |addr| self.connect(addr))
+    }
+
+    /// Save `url` and the same-host pages and resources linked from it, up to `depth` links away,
+    /// below `dir/<host>/`; returns the files written.
+    pub fn mirror<P: AsRef<Path>>(&mut self, url: &str, dir: P, depth: u32) -> Result<Vec<PathBuf>, Error> {
+        site::mirror_with(url, dir.as_ref(), depth, |url| self.fetch(url))
+    }
 
     // pub fn shutdown(&mut self) {
     //     if self.session.is_some() {
@@ -629,6 +1489,290 @@ impl I2PClient {
     // }
 }
 
+/// Private keys saved for `alias`, or those of a new TRANSIENT session, which are then saved
+fn load_or_create_keys(sam: &SamConfig, store: &KeyStore, alias: &str, style: SessionStyle, retry_policy: &RetryPolicy, resolver: &Arc<Resolver>) -> Result<Zeroizing<String>, Error> {
+    match store.get(alias) {
+        Ok(keys) => {
+            info!("Using the keys of {} in {}", alias, store.dir().display());
+            return Ok(keys);
+        },
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+    let session = retry_policy.retry("Create TRANSIENT session", |_| {
+        Session::create_with_resolver(sam, "TRANSIENT", alias, style, &[], resolver.clone())
+    })?;
+    info!("Saving the keys of {} in {}", alias, store.dir().display());
+    store.save(alias, &session.local_full_dest, false)?;
+    Ok(session.local_full_dest.clone())
+}
+
+/// Builds an [`I2PClient`], replacing the positional arguments of [`I2PClient::new`].
+///
+/// ```no_run
+/// use i2p_client::{I2PClientBuilder, SamConfig, SessionStyle};
+///
+/// let client = I2PClientBuilder::new()
+///     .sam(SamConfig::load(None).unwrap())
+///     .alias("Bob")
+///     .style(SessionStyle::Datagram)
+///     .option("inbound.length", "2")
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct I2PClientBuilder {
+    sam: SamConfig,
+    alias: String,
+    use_local: bool,
+    key_store: Option<KeyStore>,
+    style: SessionStyle,
+    options: Vec<(String, String)>,
+    retry_policy: RetryPolicy,
+    auto_reconnect: bool,
+}
+
+impl Default for I2PClientBuilder {
+    fn default() -> Self {
+        I2PClientBuilder {
+            sam: SamConfig::default(),
+            alias: String::from("Anon"),
+            use_local: true,
+            key_store: None,
+            style: SessionStyle::Datagram,
+            options: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            auto_reconnect: true,
+        }
+    }
+}
+
+impl I2PClientBuilder {
+    /// Defaults: SAM bridge at 127.0.0.1:7656, alias 'Anon', local keys in [`KeyStore::open_default`], datagram session, [`RetryPolicy::default`]
+    pub fn new() -> I2PClientBuilder {
+        I2PClientBuilder::default()
+    }
+
+    /// SAM bridge address, credentials and versions
+    pub fn sam(mut self, sam: SamConfig) -> Self {
+        self.sam = sam;
+        self
+    }
+
+    pub fn sam_address(mut self, host: &str, port: u16) -> Self {
+        self.sam.host = String::from(host);
+        self.sam.port = port;
+        self
+    }
+
+    /// Where datagrams are sent, see [`SamConfig::udp_addr`]
+    pub fn sam_udp_address(mut self, host: &str, port: u16) -> Self {
+        self.sam.udp_host = Some(String::from(host));
+        self.sam.udp_port = port;
+        self
+    }
+
+    /// SAMv3.2 USER/PASSWORD sent in HELLO
+    pub fn credentials(mut self, user: &str, password: &str) -> Self {
+        self.sam.user = Some(String::from(user));
+        self.sam.password = Some(String::from(password));
+        self
+    }
+
+    pub fn versions(mut self, min_version: &str, max_version: &str) -> Self {
+        self.sam.min_version = String::from(min_version);
+        self.sam.max_version = String::from(max_version);
+        self
+    }
+
+    /// Session nickname and, with local keys, the name of the key file
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.alias = String::from(alias);
+        self
+    }
+
+    /// When true, reuse (or create and save) the destination stored under the alias in the key directory;
+    /// when false, use whatever the I2P router provides
+    pub fn use_local(mut self, use_local: bool) -> Self {
+        self.use_local = use_local;
+        self
+    }
+
+    /// Directory of the [`KeyStore`] holding local keys; [`KeyStore::open_default`] by default
+    pub fn key_dir<P: Into<PathBuf>>(mut self, key_dir: P) -> Self {
+        self.key_store = Some(KeyStore::new(key_dir));
+        self
+    }
+
+    /// [`KeyStore`] holding local keys, e.g. one with a passphrase; replaces [`I2PClientBuilder::key_dir`]
+    pub fn key_store(mut self, key_store: KeyStore) -> Self {
+        self.key_store = Some(key_store);
+        self
+    }
+
+    pub fn style(mut self, style: SessionStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Option passed through to SESSION CREATE, e.g. `inbound.length=2`
+    pub fn option(mut self, key: &str, value: &str) -> Self {
+        self.options.push((String::from(key), String::from(value)));
+        self
+    }
+
+    pub fn options(mut self, options: &[(&str, &str)]) -> Self {
+        for (k, v) in options {
+            self.options.push((String::from(*k), String::from(*v)));
+        }
+        self
+    }
+
+    /// Backoff applied to connecting, the handshake and session creation
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn max_connection_attempts(mut self, max_connection_attempts: u8) -> Self {
+        self.retry_policy.max_attempts = u32::from(max_connection_attempts);
+        self
+    }
+
+    /// Wait before the first retry; later retries back off from it
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_policy.initial_delay = retry_delay;
+        self
+    }
+
+    /// Transparently recreate the session when the router restarts; on by default
+    pub fn auto_reconnect(mut self, auto_reconnect: bool) -> Self {
+        self.auto_reconnect = auto_reconnect;
+        self
+    }
+
+    /// Connect, handshake, reply and idle timeouts on SAM sockets
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.sam.timeouts = timeouts;
+        self
+    }
+
+    /// Limit on establishing TCP connections to the SAM bridge
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.sam.timeouts.connect = Some(connect_timeout);
+        self
+    }
+
+    /// Limit on waiting for HELLO REPLY
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.sam.timeouts.handshake = Some(handshake_timeout);
+        self
+    }
+
+    /// Limit on waiting for replies to SAM commands
+    pub fn reply_timeout(mut self, reply_timeout: Duration) -> Self {
+        self.sam.timeouts.reply = Some(reply_timeout);
+        self
+    }
+
+    /// Limit on waiting for the next datagram or for stream data
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.sam.timeouts.idle = Some(idle_timeout);
+        self
+    }
+
+    /// Create a datagram session
+    pub fn build_datagram(self) -> Result<I2PClient, Error> {
+        self.style(SessionStyle::Datagram).build()
+    }
+
+    /// Create a stream session
+    pub fn build_stream(self) -> Result<I2PClient, Error> {
+        self.style(SessionStyle::Stream).build()
+    }
+
+    /// Create a SAMv3.3 primary session; streams and datagrams go through the subsessions added with
+    /// [`I2PClient::add_subsession`]
+    pub fn build_primary(self) -> Result<I2PClient, Error> {
+        self.style(SessionStyle::Primary).build()
+    }
+
+    /// Create the session with the configured style
+    pub fn build(self) -> Result<I2PClient, Error> {
+        let I2PClientBuilder { sam, alias, use_local, key_store, style, options, retry_policy, auto_reconnect } = self;
+        info!("{}", "Initializing I2P Client...");
+        info!("sam bridge: {} (udp: {})", sam.tcp_addr(), sam.udp_addr());
+        // Shared by every session of this client, including those recreated on reconnect
+        let resolver = Arc::new(Resolver::from_address_book());
+        let local_full_dest = if use_local {
+            let store = match key_store {
+                Some(store) => store,
+                None => KeyStore::open_default()?
+            };
+            load_or_create_keys(&sam, &store, alias.as_str(), style, &retry_policy, &resolver)?
+        } else {
+            let session = retry_policy.retry("Create TRANSIENT session", |_| {
+                Session::create_with_resolver(&sam, "TRANSIENT", alias.as_str(), style, &[], resolver.clone())
+            })?;
+            session.local_full_dest.clone()
+        };
+
+        let session = retry_policy.retry("Create session", |attempt| {
+            info!("Trying to create session (attempt {})...", attempt);
+            Session::create_with_resolver(&sam, local_full_dest.as_str(), alias.as_str(), style, &options, resolver.clone())
+        })?;
+        info!("{}", "I2P Client initialized.");
+        Ok(I2PClient {
+            local_full_dest,
+            local_dest: session.local_dest.clone(),
+            sam,
+            alias,
+            style,
+            options,
+            retry_policy,
+            auto_reconnect,
+            forwards: Vec::new(),
+            subsessions: Vec::new(),
+            event_handler: None,
+            session
+        })
+    }
+}
+
+/// Entry points for the cargo-fuzz targets in `fuzz/`; not part of the public API.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing {
+    use std::io::Cursor;
+    use nom::IResult;
+    use crate::parsers::{datagram_received, gen_reply, sam_hello, sam_naming_reply, sam_session_status, sam_stream_status};
+
+    fn reply<F>(data: &[u8], reply_parser: F)
+        where
+            F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
+    {
+        if let Ok(line) = crate::read_line_bounded(&mut Cursor::new(data)) {
+            let _ = crate::parse_reply(&line, reply_parser);
+        }
+    }
+
+    pub fn hello_reply(data: &[u8]) { reply(data, sam_hello) }
+    pub fn session_status(data: &[u8]) { reply(data, sam_session_status) }
+    pub fn stream_status(data: &[u8]) { reply(data, sam_stream_status) }
+    pub fn naming_reply(data: &[u8]) { reply(data, sam_naming_reply) }
+    pub fn dest_reply(data: &[u8]) { reply(data, gen_reply) }
+    pub fn datagram_header(data: &[u8]) { reply(data, datagram_received) }
+
+    /// Header/body framing of DATAGRAM RECEIVED, as done by `SamConnection::recv_msg`.
+    pub fn datagram(data: &[u8]) {
+        let mut reader = Cursor::new(data);
+        while crate::read_datagram(&mut reader).is_ok() {}
+    }
+
+    pub fn hosts(data: &[u8]) {
+        let _ = crate::parse_hosts(Cursor::new(data));
+    }
+}
+
 // impl Service for I2PClient {
 //     fn operate(&mut self, operation: u8, msg: Envelope) {
 //         let mut packet = Packet::new(0, PacketType::Data as u8, NetworkId::I2P as u8, env.)
@@ -645,8 +1789,439 @@ impl I2PClient {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use crate::parsers::sam_naming_reply;
+    use crate::{datagram_send_msg, datagram_udp_msg, parse_hosts, parse_reply, parse_stream_peer, read_datagram, read_line_bounded, redact, Destination, I2PClientBuilder, ReceivedDatagram, RetryPolicy, SamConfig, SessionStyle, StreamSession, MAX_LINE_LEN};
+
+    pub(crate) struct FakeBridge {
+        pub(crate) port: u16,
+        pub(crate) udp_port: u16,
+        /// Every command line received
+        pub(crate) received: Arc<Mutex<Vec<String>>>,
+        /// Leave STREAM ACCEPT waiting for a peer instead of announcing one
+        pub(crate) hold_accepts: Arc<AtomicBool>,
+        conns: Arc<Mutex<Vec<TcpStream>>>,
+        /// Control connection of each session and subsession ID
+        sessions: Arc<Mutex<HashMap<String, TcpStream>>>,
+    }
+
+    impl FakeBridge {
+        /// Drop every open connection and forget the sessions, as a router restart would
+        fn restart(&self) {
+            self.sessions.lock().unwrap().clear();
+            for conn in self.conns.lock().unwrap().drain(..) {
+                // Sessions the test already closed are gone
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Minimal SAM bridge on an ephemeral port answering HELLO, SESSION CREATE/ADD/REMOVE and NAMING LOOKUP;
+    /// datagrams sent with DATAGRAM SEND or to its UDP port are looped back to the sending session
+    /// as DATAGRAM RECEIVED and once STREAM CONNECT or STREAM ACCEPT succeeds the connection echoes
+    /// every line back until the peer shuts down writing. STREAM commands for unknown IDs get INVALID_ID.
+    pub(crate) fn fake_bridge() -> FakeBridge {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_port = udp.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let sessions: Arc<Mutex<HashMap<String, TcpStream>>> = Arc::new(Mutex::new(HashMap::new()));
+        let by_nickname = sessions.clone();
+        let known = sessions.clone();
+        let udp_log = received.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 65_536];
+            while let Ok(len) = udp.recv(&mut buf) {
+                let newline = buf[..len].iter().position(|&b| b == b'\n').unwrap();
+                let header = String::from_utf8_lossy(&buf[..newline]).into_owned();
+                let payload = &buf[newline + 1..len];
+                let nickname = header.split(' ').nth(1).unwrap();
+                udp_log.lock().unwrap().push(header.clone());
+                if let Some(conn) = by_nickname.lock().unwrap().get_mut(nickname) {
+                    let payload = base64::encode(payload);
+                    let msg = format!("DATAGRAM RECEIVED DESTINATION=pubkey SIZE={}\n{}", payload.len(), payload);
+                    conn.write_all(msg.as_bytes()).unwrap();
+                }
+            }
+        });
+        let hold_accepts = Arc::new(AtomicBool::new(false));
+        let hold = hold_accepts.clone();
+        let log = received.clone();
+        let open = conns.clone();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                open.lock().unwrap().push(conn.try_clone().unwrap());
+                let log = log.clone();
+                let sessions = known.clone();
+                let hold = hold.clone();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(conn.try_clone().unwrap());
+                    let mut line = String::new();
+                    let mut streaming = false;
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        if streaming {
+                            conn.write_all(line.as_bytes()).unwrap();
+                            line.clear();
+                            continue;
+                        }
+                        if line.starts_with("DATAGRAM SEND") {
+                            // Loop the datagram back to the sender
+                            let mut payload = String::new();
+                            reader.read_line(&mut payload).unwrap();
+                            let payload = payload.trim_end();
+                            log.lock().unwrap().push(line.clone());
+                            conn.write_all(format!("DATAGRAM RECEIVED DESTINATION=pubkey SIZE={}\n{}", payload.len(), payload).as_bytes()).unwrap();
+                            line.clear();
+                            continue;
+                        }
+                        let id = line.split(' ').find_map(|option| option.strip_prefix("ID=")).map(String::from);
+                        let hello;
+                        let reply = if line.starts_with("HELLO") {
+                            // Agree to the highest version offered
+                            let max = line.split(' ').find_map(|option| option.strip_prefix("MAX=")).unwrap_or("3.1");
+                            hello = format!("HELLO REPLY RESULT=OK VERSION={}\n", max);
+                            hello.as_str()
+                        } else if line.starts_with("SESSION CREATE") {
+                            if let Some(id) = id {
+                                sessions.lock().unwrap().insert(id, conn.try_clone().unwrap());
+                            }
+                            "SESSION STATUS RESULT=OK DESTINATION=privkey\n"
+                        } else if line.starts_with("SESSION ADD") {
+                            if let Some(id) = id {
+                                sessions.lock().unwrap().insert(id, conn.try_clone().unwrap());
+                            }
+                            "SESSION STATUS RESULT=OK\n"
+                        } else if line.starts_with("SESSION REMOVE") {
+                            if let Some(id) = id {
+                                sessions.lock().unwrap().remove(&id);
+                            }
+                            "SESSION STATUS RESULT=OK\n"
+                        } else if line.starts_with("STREAM") && !id.is_some_and(|id| sessions.lock().unwrap().contains_key(&id)) {
+                            "STREAM STATUS RESULT=INVALID_ID\n"
+                        } else if line.starts_with("NAMING LOOKUP") {
+                            "NAMING REPLY RESULT=OK NAME=ME VALUE=pubkey\n"
+                        } else if line.starts_with("STREAM CONNECT") || (line.starts_with("STREAM ACCEPT") && hold.load(Ordering::SeqCst)) {
+                            streaming = true;
+                            "STREAM STATUS RESULT=OK\n"
+                        } else if line.starts_with("STREAM ACCEPT") {
+                            streaming = true;
+                            "STREAM STATUS RESULT=OK\npeerdest FROM_PORT=1234 TO_PORT=80\n"
+                        } else {
+                            "UNKNOWN\n"
+                        };
+                        log.lock().unwrap().push(line.clone());
+                        conn.write_all(reply.as_bytes()).unwrap();
+                        line.clear();
+                    }
+                    if streaming {
+                        // Close the echo once the peer is done writing
+                        let _ = conn.shutdown(Shutdown::Write);
+                    }
+                });
+            }
+        });
+        FakeBridge { port, udp_port, received, hold_accepts, conns, sessions }
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn redacted() {
+        assert_eq!(redact("SESSION CREATE STYLE=STREAM ID=a DESTINATION=privkey inbound.length=2 \n"),
+                   "SESSION CREATE STYLE=STREAM ID=a DESTINATION=[redacted] inbound.length=2 \n");
+        assert_eq!(redact("SESSION STATUS RESULT=OK DESTINATION=privkey\n"), "SESSION STATUS RESULT=OK DESTINATION=[redacted]");
+        assert_eq!(redact("SESSION CREATE STYLE=STREAM ID=a DESTINATION=TRANSIENT \n"), "SESSION CREATE STYLE=STREAM ID=a DESTINATION=TRANSIENT \n");
+        assert_eq!(redact("DEST REPLY PUB=pubkey PRIV=privkey\n"), "DEST REPLY PUB=pubkey PRIV=[redacted]");
+        assert_eq!(redact("HELLO VERSION MIN=3.1 MAX=3.3 USER=\"u\" PASSWORD=\"a b\" \n"), "HELLO VERSION MIN=3.1 MAX=3.3 USER=\"u\" PASSWORD=[redacted]");
+        // Peers' destinations are public
+        assert_eq!(redact("STREAM CONNECT ID=a DESTINATION=pubkey SILENT=false\n"), "STREAM CONNECT ID=a DESTINATION=pubkey SILENT=false\n");
+    }
+
+    #[test]
+    fn malformed_reply() {
+        let err = parse_reply("NAMING REPLY RESULT\n", sam_naming_reply).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = parse_reply("NAMING REPLY RESULT=KEY_NOT_FOUND\n", sam_naming_reply).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn bounded_line() {
+        let long = vec![b'A'; MAX_LINE_LEN + 1];
+        let err = read_line_bounded(&mut Cursor::new(long)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = read_line_bounded(&mut Cursor::new("HELLO REPLY")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let err = read_line_bounded(&mut Cursor::new(vec![0xff, b'\n'])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn datagram_send() {
+        assert_eq!(datagram_send_msg("dest", 0, 0, b"hello").unwrap(), "DATAGRAM SEND DESTINATION=dest SIZE=8 \naGVsbG8=\n");
+        assert_eq!(datagram_send_msg("dest", 7, 9, b"hello").unwrap(),
+                   "DATAGRAM SEND DESTINATION=dest SIZE=8 FROM_PORT=7 TO_PORT=9 \naGVsbG8=\n");
+        // Large messages are only warned about; beyond the tunnel limit they are rejected
+        assert!(datagram_send_msg("dest", 0, 0, &[0; 40_000]).is_ok());
+        let err = datagram_send_msg("dest", 0, 0, &[0; 50_000]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn datagram_udp() {
+        assert_eq!(datagram_udp_msg("Bob", "dest", 0, 0, b"hello").unwrap(), b"3.0 Bob dest\nhello".to_vec());
+        assert_eq!(datagram_udp_msg("Bob", "dest", 7, 9, &[0, 255]).unwrap(), b"3.0 Bob dest FROM_PORT=7 TO_PORT=9\n\x00\xff".to_vec());
+        let err = datagram_udp_msg("Bob", "dest", 0, 0, &[0; 62_000]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn datagram_framing() {
+        let mut reader = Cursor::new("DATAGRAM RECEIVED DESTINATION=dest SIZE=8\naGVsbG8=DATAGRAM RECEIVED DESTINATION=dest2 SIZE=4 FROM_PORT=7 TO_PORT=9\nYWI=");
+        assert_eq!(read_datagram(&mut reader).unwrap(),
+                   ReceivedDatagram { from: Destination::new("dest"), from_port: 0, to_port: 0, payload: b"hello".to_vec() });
+        assert_eq!(read_datagram(&mut reader).unwrap(),
+                   ReceivedDatagram { from: Destination::new("dest2"), from_port: 7, to_port: 9, payload: b"ab".to_vec() });
+
+        let err = read_datagram(&mut Cursor::new("DATAGRAM RECEIVED DESTINATION=dest SIZE=99999999999\n")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = read_datagram(&mut Cursor::new("DATAGRAM RECEIVED DESTINATION=dest\naGVsbG8=")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = read_datagram(&mut Cursor::new("DATAGRAM RECEIVED DESTINATION=dest SIZE=8\naGVs")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let err = read_datagram(&mut Cursor::new("DATAGRAM RECEIVED DESTINATION=dest SIZE=3\n!!!")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = read_datagram(&mut Cursor::new("DATAGRAM RECEIVED DESTINATION=dest SIZE=4 FROM_PORT=x\nYWI=")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn builder() {
+        let bridge = fake_bridge();
+        let client = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", bridge.port)
+            .credentials("user", "secret")
+            .versions("3.1", "3.3")
+            .use_local(false)
+            .alias("Test")
+            .style(SessionStyle::Stream)
+            .option("inbound.length", "2")
+            .build()
+            .unwrap();
+        assert_eq!(*client.local_full_dest, "privkey");
+        assert_eq!(client.local_dest, "pubkey");
+        let received = bridge.received.lock().unwrap();
+        assert_eq!(received[0], "HELLO VERSION MIN=3.1 MAX=3.3 USER=\"user\" PASSWORD=\"secret\" \n");
+        assert!(received.contains(&String::from("SESSION CREATE STYLE=STREAM ID=Test DESTINATION=privkey inbound.length=2 \n")));
+    }
+
+    #[test]
+    fn datagram_over_udp() {
+        let bridge = fake_bridge();
+        let mut client = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", bridge.port)
+            .sam_udp_address("127.0.0.1", bridge.udp_port)
+            .use_local(false)
+            .build()
+            .unwrap();
+        client.send_datagram("peer.i2p:80", 7, b"hello").unwrap();
+        assert_eq!(client.receive_timeout(Duration::from_secs(5)).unwrap(), (String::from("pubkey"), b"hello".to_vec()));
+        assert!(!bridge.received.lock().unwrap().iter().any(|l| l.starts_with("DATAGRAM SEND")));
+    }
+
+    #[test]
+    fn timeouts() {
+        let bridge = fake_bridge();
+        let mut client = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", bridge.port)
+            .use_local(false)
+            .build()
+            .unwrap();
+        let err = client.receive_timeout(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let err = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", silent.local_addr().unwrap().port())
+            .handshake_timeout(Duration::from_millis(50))
+            .retry_policy(RetryPolicy::none())
+            .use_local(false)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn reconnect() {
+        let bridge = fake_bridge();
+        let mut client = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", bridge.port)
+            .sam_udp_address("127.0.0.1", bridge.udp_port)
+            .use_local(false)
+            .build()
+            .unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = events.clone();
+        client.set_event_handler(move |e| log.lock().unwrap().push(format!("{:?}", e)));
+        let reconnected = || {
+            let events: Vec<String> = events.lock().unwrap().drain(..).collect();
+            events.len() == 2 && events[0].starts_with("Disconnected") && events[1] == "Reconnected"
+        };
+        let resolver = client.session.resolver.clone();
+
+        bridge.restart();
+        let err = client.receive_timeout(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(reconnected());
+        let sessions: Vec<String> = bridge.received.lock().unwrap().iter()
+            .filter(|l| l.starts_with("SESSION CREATE"))
+            .cloned()
+            .collect();
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[1], sessions[2]);
+        // The recreated session keeps the resolver and its cache
+        assert!(Arc::ptr_eq(&resolver, &client.session.resolver));
+
+        // Datagrams go over UDP, which cannot tell that the session is gone
+        client.send_datagram("peer.i2p:80", 7, b"hello").unwrap();
+        assert_eq!(client.receive_timeout(Duration::from_secs(5)).unwrap().1, b"hello");
+        bridge.restart();
+        client.send_datagram("peer.i2p:80", 7, b"hello").unwrap();
+        assert!(reconnected());
+
+        // Streams use new connections, on which the restarted bridge does not know the session
+        let mut client = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", bridge.port)
+            .use_local(false)
+            .style(SessionStyle::Stream)
+            .build()
+            .unwrap();
+        let log = events.clone();
+        client.set_event_handler(move |e| log.lock().unwrap().push(format!("{:?}", e)));
+        bridge.restart();
+        client.connect("peer.i2p:80").unwrap();
+        assert!(reconnected());
+        bridge.restart();
+        client.accept().unwrap();
+        assert!(reconnected());
+    }
+
+    #[test]
+    fn primary() {
+        let bridge = fake_bridge();
+        let err = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", bridge.port)
+            .versions("3.0", "3.1")
+            .use_local(false)
+            .build_primary()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+
+        let mut client = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", bridge.port)
+            .use_local(false)
+            .build_primary()
+            .unwrap();
+        client.add_subsession(SessionStyle::Stream, "web", &[("FROM_PORT", "80")]).unwrap();
+        let err = client.add_subsession(SessionStyle::Primary, "nested", &[]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        client.connect_as("web", "peer.i2p:80").unwrap();
+        bridge.restart();
+        let err = client.receive_timeout(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        client.connect_as("web", "peer.i2p:80").unwrap();
+        client.remove_subsession("web").unwrap();
+
+        let received = bridge.received.lock().unwrap();
+        let commands: Vec<&str> = received.iter()
+            .filter(|l| l.starts_with("SESSION") || l.starts_with("STREAM CONNECT"))
+            .map(|l| l.trim_end())
+            .collect();
+        assert_eq!(commands, [
+            "SESSION CREATE STYLE=PRIMARY ID=Anon DESTINATION=TRANSIENT",
+            "SESSION CREATE STYLE=PRIMARY ID=Anon DESTINATION=privkey",
+            "SESSION ADD STYLE=STREAM ID=web FROM_PORT=80",
+            "STREAM CONNECT ID=web DESTINATION=pubkey SILENT=false TO_PORT=80",
+            // Recreated with its subsession after the restart
+            "SESSION CREATE STYLE=PRIMARY ID=Anon DESTINATION=privkey",
+            "SESSION ADD STYLE=STREAM ID=web FROM_PORT=80",
+            "STREAM CONNECT ID=web DESTINATION=pubkey SILENT=false TO_PORT=80",
+            "SESSION REMOVE ID=web",
+        ]);
+    }
+
+    #[test]
+    fn split() {
+        let bridge = fake_bridge();
+        let mut client = I2PClientBuilder::new()
+            .sam_address("127.0.0.1", bridge.port)
+            .use_local(false)
+            .style(SessionStyle::Stream)
+            .build()
+            .unwrap();
+        let stream = client.accept().unwrap();
+        let mut clone = stream.try_clone().unwrap();
+        let (mut read, mut write) = stream.into_split();
+        let writer = thread::spawn(move || write.write_all(b"one\ntwo\n"));
+        let mut buf = [0u8; 4];
+        read.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"one\n");
+        writer.join().unwrap().unwrap();
+        // Whatever the read half buffered is still there for the clone
+        clone.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"two\n");
+    }
+
+    #[test]
+    fn stream_session() {
+        let bridge = fake_bridge();
+        let session = StreamSession::create(&SamConfig::new("127.0.0.1", bridge.port), "TRANSIENT", "Shared", &[]).unwrap();
+        for _ in 0..3 {
+            let mut stream = session.connect("peer.i2p:80").unwrap();
+            stream.write_all(b"ping\n").unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping\n");
+            assert_eq!(stream.peer_addr().unwrap().to_string(), "pubkey:80");
+        }
+        let received = bridge.received.lock().unwrap();
+        assert_eq!(received.iter().filter(|l| l.starts_with("SESSION CREATE")).count(), 1);
+        assert_eq!(received.iter().filter(|l| l.starts_with("NAMING LOOKUP NAME=peer.i2p")).count(), 1);
+        assert_eq!(received.iter().filter(|l| l.starts_with("STREAM CONNECT ID=Shared DESTINATION=pubkey")).count(), 3);
+        // Zero ports are left out, as for datagrams
+        assert!(received.contains(&String::from("STREAM CONNECT ID=Shared DESTINATION=pubkey SILENT=false TO_PORT=80\n")));
+    }
+
+    #[test]
+    fn stream_peer() {
+        let (peer, local_port) = parse_stream_peer("AAAA FROM_PORT=1234 TO_PORT=80\n").unwrap();
+        assert_eq!(peer.to_string(), "AAAA:1234");
+        assert_eq!(local_port, 80);
+        let (peer, local_port) = parse_stream_peer("AAAA\n").unwrap();
+        assert_eq!((peer.port(), local_port), (0, 0));
+        assert_eq!(parse_stream_peer("AAAA TO_PORT=x\n").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(parse_stream_peer("\n").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hosts() {
+        let m = parse_hosts(Cursor::new("# comment\n\nfoo.i2p=AAAA\nbroken\nbar.i2p=BB=B\n"));
+        assert_eq!(m.len(), 2);
+        assert_eq!(m["foo.i2p"], "AAAA");
+        assert_eq!(m["bar.i2p"], "BB=B");
+    }
 }
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::sync::Mutex;
+
+use log::{debug, warn};
+
+use crate::{parse_hosts, Destination};
+
+static I2P_ADDR_BK: &str = "eepsite/docroot/hosts.txt";
+
+/// Local address book `~/.i2p/eepsite/docroot/hosts.txt`; `None` without a home directory or when it
+/// cannot be read
+pub fn address_book() -> Option<HashMap<String, String>> {
+    let mut i2p_hosts = dirs::home_dir()?;
+    i2p_hosts.push(".i2p");
+    i2p_hosts.push(I2P_ADDR_BK);
+    match File::open(&i2p_hosts) {
+        Ok(i2p_hosts_file) => Some(parse_hosts(BufReader::new(i2p_hosts_file))),
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("{}: {}", i2p_hosts.display(), e);
+            }
+            None
+        }
+    }
+}
+
+/// Resolves names and b32 addresses to full destinations, trying the address book, then
+/// earlier lookups, then `NAMING LOOKUP` on the router. Full base64 destinations resolve to themselves.
+#[derive(Debug, Default)]
+pub struct Resolver {
+    hosts: HashMap<String, String>,
+    cache: Mutex<HashMap<String, String>>,
+}
+
+impl Resolver {
+    /// Resolver without an address book
+    pub fn new() -> Resolver {
+        Resolver::default()
+    }
+
+    /// Resolver using the local address book, see [`address_book`]
+    pub fn from_address_book() -> Resolver {
+        Resolver::with_hosts(address_book().unwrap_or_default())
+    }
+
+    pub fn with_hosts(hosts: HashMap<String, String>) -> Resolver {
+        Resolver { hosts, cache: Mutex::new(HashMap::new()) }
+    }
+
+    /// Resolve without asking the router
+    pub fn resolve_local(&self, dest: &Destination) -> Option<Destination> {
+        if dest.is_base64() {
+            return Some(dest.clone());
+        }
+        if let Some(d) = self.hosts.get(dest.as_str()) {
+            debug!("{} found in address book", dest);
+            return Some(Destination::new(d));
+        }
+        self.cache.lock().unwrap().get(dest.as_str()).map(|d| Destination::new(d))
+    }
+
+    /// Resolve `dest`, calling `lookup` (normally `NAMING LOOKUP`) when it is not known locally
+    pub fn resolve<F>(&self, dest: &Destination, lookup: F) -> Result<Destination, Error>
+        where
+            F: FnOnce(&str) -> Result<String, Error>,
+    {
+        if let Some(d) = self.resolve_local(dest) {
+            return Ok(d);
+        }
+        let full = lookup(dest.as_str())?;
+        self.insert(dest, &full);
+        Ok(Destination::from(full))
+    }
+
+    /// Remember the result of a lookup
+    pub fn insert(&self, name: &Destination, dest: &str) {
+        self.cache.lock().unwrap().insert(String::from(name.as_str()), String::from(dest));
+    }
+
+    /// Forget earlier lookups, e.g. after a destination moved to new keys
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{Error, ErrorKind};
+    use crate::naming::Resolver;
+    use crate::Destination;
+
+    #[test]
+    fn resolve() {
+        let mut hosts = HashMap::new();
+        hosts.insert(String::from("local.i2p"), String::from("LOCAL"));
+        let resolver = Resolver::with_hosts(hosts);
+        let mut lookups = 0;
+        let mut lookup = |name: &str| {
+            lookups += 1;
+            match name {
+                "remote.i2p" => Ok(String::from("REMOTE")),
+                _ => Err(Error::new(ErrorKind::NotFound, "KEY_NOT_FOUND"))
+            }
+        };
+        assert_eq!(resolver.resolve(&Destination::new("local.i2p"), &mut lookup).unwrap().as_str(), "LOCAL");
+        assert_eq!(resolver.resolve(&Destination::new("remote.i2p"), &mut lookup).unwrap().as_str(), "REMOTE");
+        assert_eq!(resolver.resolve(&Destination::new("remote.i2p"), &mut lookup).unwrap().as_str(), "REMOTE");
+        assert_eq!(resolver.resolve(&Destination::new("missing.i2p"), &mut lookup).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(lookups, 2);
+        resolver.clear_cache();
+        assert!(resolver.resolve_local(&Destination::new("remote.i2p")).is_none());
+    }
+}
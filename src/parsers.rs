@@ -1,4 +1,4 @@
-use nom::{alphanumeric, space};
+use nom::space;
 
 fn is_space(chr: char) -> bool {
     chr == ' ' || chr == '\t'
@@ -16,6 +16,11 @@ fn is_double_quote(chr: char) -> bool {
     chr == '\"'
 }
 
+/// Keys such as FROM_PORT or inbound.length
+fn is_key_char(chr: char) -> bool {
+    chr.is_ascii_alphanumeric() || chr == '_' || chr == '.'
+}
+
 named!(quoted_value <&str, &str>,
     do_parse!(
              tag_s!("\"")                  >>
@@ -29,7 +34,7 @@ named!(value <&str, &str>, take_till_s!(is_space_or_next_line));
 
 named!(key_value <&str, (&str, &str)>,
     do_parse!(
-        key: alphanumeric               >>
+        key: take_while1_s!(is_key_char) >>
              tag_s!("=")                >>
         val: alt!(quoted_value | value) >>
         (key, val)
@@ -155,6 +160,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn datagram() {
+        use crate::parsers::datagram_received;
+
+        assert_eq!(
+            datagram_received("DATAGRAM RECEIVED DESTINATION=dest SIZE=8 FROM_PORT=7 TO_PORT=0\n"),
+            Done("", vec![("DESTINATION", "dest"), ("SIZE", "8"), ("FROM_PORT", "7"), ("TO_PORT", "0")])
+        );
+    }
+
     #[test]
     fn session_status() {
         use crate::parsers::sam_session_status;
@@ -0,0 +1,194 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+/// Exponential backoff with jitter for connecting to the SAM bridge, the handshake and session creation.
+///
+/// The delay before retry `n` (1-based) is `initial_delay * multiplier^(n-1)`, capped at `max_delay`,
+/// then reduced by a random fraction of up to `jitter`. Only errors for which [`RetryPolicy::is_retryable`]
+/// holds are retried; INVALID_KEY, DUPLICATED_DEST and the like fail immediately.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one; 0 and 1 both mean no retries
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    /// Fraction of each delay that is randomized, from 0.0 (none) to 1.0 (full jitter)
+    pub jitter: f64,
+    /// Give up once this much time has passed since the first attempt
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_secs(3),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Single attempt, no retries
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Delay before retry number `retry` (1-based), without jitter
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(retry.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let delay = self.initial_delay.as_secs_f64() * factor;
+        if !delay.is_finite() || delay >= self.max_delay.as_secs_f64() {
+            self.max_delay
+        } else {
+            Duration::from_secs_f64(delay)
+        }
+    }
+
+    fn jittered(&self, delay: Duration) -> Duration {
+        if self.jitter.is_nan() || self.jitter <= 0.0 {
+            return delay;
+        }
+        let jitter = self.jitter.min(1.0);
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        delay.mul_f64(1.0 - jitter * random)
+    }
+
+    /// Connection level failures and router TIMEOUT replies are worth retrying; rejected keys,
+    /// duplicated IDs and destinations, other router errors and malformed replies are not.
+    pub fn is_retryable(e: &Error) -> bool {
+        matches!(e.kind(),
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::AddrNotAvailable
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::UnexpectedEof)
+    }
+
+    /// Run `op` until it succeeds, fails with a fatal error, or attempts/deadline run out.
+    /// `op` receives the attempt number starting at 1.
+    pub fn retry<T, F>(&self, what: &str, mut op: F) -> Result<T, Error>
+        where
+            F: FnMut(u32) -> Result<T, Error>,
+    {
+        let start = Instant::now();
+        let mut attempt: u32 = 1;
+        loop {
+            let err = match op(attempt) {
+                Ok(t) => return Ok(t),
+                Err(e) => e
+            };
+            if !RetryPolicy::is_retryable(&err) {
+                return Err(err);
+            }
+            if attempt >= self.max_attempts {
+                return Err(Error::new(err.kind(), format!("{}: max attempts ({}) reached: {}", what, self.max_attempts, err)));
+            }
+            let delay = self.jittered(self.backoff(attempt));
+            if let Some(deadline) = self.deadline {
+                if start.elapsed() + delay > deadline {
+                    return Err(Error::new(ErrorKind::TimedOut, format!("{}: deadline ({:?}) reached: {}", what, deadline, err)));
+                }
+            }
+            warn!("{} failed ({})...retrying in {:?}", what, err, delay);
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+    use std::time::Duration;
+    use crate::parse_reply;
+    use crate::parsers::sam_session_status;
+    use crate::retry::RetryPolicy;
+
+    fn fast() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(3),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_delay: Duration::from_secs(10),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(3));
+        assert_eq!(policy.backoff(2), Duration::from_secs(6));
+        assert_eq!(policy.backoff(3), Duration::from_secs(10));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(10));
+        let jittered = policy.jittered(Duration::from_secs(3));
+        assert!(jittered <= Duration::from_secs(3) && jittered >= Duration::from_millis(2400));
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let mut calls = 0;
+        let res = fast().retry("test", |attempt| {
+            calls += 1;
+            if attempt < 3 {
+                Err(Error::new(ErrorKind::ConnectionRefused, "refused"))
+            } else {
+                Ok(attempt)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn gives_up() {
+        let mut calls = 0;
+        let err = fast().retry("test", |_| -> Result<(), Error> {
+            calls += 1;
+            Err(Error::new(ErrorKind::TimedOut, "timeout"))
+        }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(calls, 4);
+
+        calls = 0;
+        let err = fast().retry("test", |_| -> Result<(), Error> {
+            calls += 1;
+            Err(Error::new(ErrorKind::InvalidInput, "INVALID_KEY"))
+        }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn duplicated_not_retried() {
+        for reply in &["SESSION STATUS RESULT=DUPLICATED_ID\n", "SESSION STATUS RESULT=DUPLICATED_DEST\n"] {
+            let mut calls = 0;
+            let err = fast().retry("test", |_| {
+                calls += 1;
+                parse_reply(reply, sam_session_status)
+            }).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+            assert_eq!(calls, 1);
+        }
+    }
+}
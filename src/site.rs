@@ -0,0 +1,465 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+use crate::http::{is_i2p_host, parse_authority, read_head, read_line};
+use crate::{Destination, I2pSocketAddr};
+
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 10;
+/// Largest response body accepted
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+
+/// Response to [`I2PClient::fetch`](crate::I2PClient::fetch)
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// URL the response came from, after following redirects
+    pub url: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    /// Body with any chunked transfer encoding removed
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// First header named `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn is_html(&self) -> bool {
+        self.header("Content-Type").map(|t| t.to_ascii_lowercase().contains("text/html")).unwrap_or(false)
+    }
+}
+
+/// An `http://` URL on an I2P host
+#[derive(Debug, Clone, PartialEq)]
+struct Url {
+    host: String,
+    port: u16,
+    /// Path and query
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid eepsite URL: {}", url));
+        if url.len() < 7 || !url[..7].eq_ignore_ascii_case("http://") {
+            return Err(invalid());
+        }
+        let rest = url[7..].split('#').next().unwrap_or_default();
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], String::from(&rest[i..])),
+            None => (rest, String::from("/"))
+        };
+        let target = parse_authority(authority, 80).ok_or_else(invalid)?;
+        if !is_i2p_host(&target.host) {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("{} is not an I2P host", target.host)));
+        }
+        Ok(Url { host: target.host, port: target.port, path })
+    }
+
+    /// Resolve a link or `Location` relative to this URL; None for links that are not `http://`
+    /// (`https:`, `mailto:`, ...) or only point within the page.
+    fn join(&self, reference: &str) -> Option<Url> {
+        let reference = reference.trim().split('#').next().unwrap_or_default();
+        if reference.is_empty() {
+            return None;
+        }
+        if reference.starts_with("//") {
+            return Url::parse(&format!("http:{}", reference)).ok();
+        }
+        let scheme_end = reference.find(':');
+        if scheme_end.is_some() && scheme_end < reference.find(['/', '?']).or(Some(reference.len())) {
+            return Url::parse(reference).ok();
+        }
+        let own_path = self.path.split('?').next().unwrap_or_default();
+        let path = if reference.starts_with('/') {
+            String::from(reference)
+        } else if reference.starts_with('?') {
+            format!("{}{}", own_path, reference)
+        } else {
+            format!("{}{}", &own_path[..own_path.rfind('/').map(|i| i + 1).unwrap_or(0)], reference)
+        };
+        Some(Url { host: self.host.clone(), port: self.port, path: remove_dot_segments(&path) })
+    }
+
+    fn addr(&self) -> I2pSocketAddr {
+        I2pSocketAddr::new(Destination::new(&self.host), self.port)
+    }
+
+    /// Where a mirrored copy is saved below the mirror directory: the query is dropped and
+    /// directories get an `index.html`.
+    fn local_path(&self) -> PathBuf {
+        let path = self.path.split('?').next().unwrap_or_default();
+        let mut local = PathBuf::from(&self.host);
+        for segment in path.split('/').filter(|s| !s.is_empty() && *s != "." && *s != "..") {
+            local.push(segment);
+        }
+        if path.ends_with('/') || path.is_empty() {
+            local.push("index.html");
+        }
+        local
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            80 => write!(f, "http://{}{}", self.host, self.path),
+            port => write!(f, "http://{}:{}{}", self.host, port, self.path)
+        }
+    }
+}
+
+/// RFC 3986 5.2.4, keeping the query as is
+fn remove_dot_segments(path: &str) -> String {
+    let (path, query) = match path.find('?') {
+        Some(i) => (&path[..i], &path[i..]),
+        None => (path, "")
+    };
+    let mut out: Vec<&str> = Vec::new();
+    let segments: Vec<&str> = path.split('/').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i == segments.len() - 1;
+        match *segment {
+            "." | ".." => {
+                if *segment == ".." && out.len() > 1 {
+                    out.pop();
+                }
+                if last {
+                    out.push("");
+                }
+            },
+            s => out.push(s)
+        }
+    }
+    let mut path = out.join("/");
+    if !path.starts_with('/') {
+        path.insert(0, '/');
+    }
+    path + query
+}
+
+/// GET `url` over streams opened by `connect`, following redirects as long as they stay on I2P.
+pub(crate) fn fetch_with<S, F>(url: &str, mut connect: F) -> Result<Response, Error>
+    where
+        S: Read + Write,
+        F: FnMut(&I2pSocketAddr) -> Result<S, Error>,
+{
+    let mut url = Url::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        info!("GET {}", url);
+        let mut stream = connect(&url.addr())?;
+        let host = match url.port {
+            80 => url.host.clone(),
+            port => format!("{}:{}", url.host, port)
+        };
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\nAccept-Encoding: identity\r\nConnection: close\r\n\r\n",
+                              url.path, host);
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+        let response = read_response(&mut BufReader::new(stream), url.to_string())?;
+        if let (301 | 302 | 303 | 307 | 308, Some(location)) = (response.status, response.header("Location")) {
+            url = url.join(location).ok_or_else(|| Error::new(ErrorKind::PermissionDenied,
+                format!("Redirect from {} leaves I2P: {}", url, location)))?;
+            debug!("Redirected to {}", url);
+            continue;
+        }
+        return Ok(response);
+    }
+    Err(Error::new(ErrorKind::Other, format!("More than {} redirects", MAX_REDIRECTS)))
+}
+
+/// Read a response to a GET, skipping interim (1xx) responses
+fn read_response<R: BufRead>(reader: &mut R, url: String) -> Result<Response, Error> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{}: {}", url, msg));
+    loop {
+        let head = read_head(reader)?;
+        if head.is_empty() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!("{}: no response", url)));
+        }
+        let mut status_line = head[0].splitn(3, ' ');
+        if !status_line.next().unwrap_or_default().starts_with("HTTP/1.") {
+            return Err(invalid("malformed status line"));
+        }
+        let status: u16 = status_line.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("malformed status line"))?;
+        let reason = String::from(status_line.next().unwrap_or_default());
+        let mut headers = Vec::new();
+        for line in &head[1..] {
+            let i = line.find(':').ok_or_else(|| invalid("malformed header"))?;
+            headers.push((String::from(line[..i].trim()), String::from(line[i + 1..].trim())));
+        }
+        if (100..200).contains(&status) {
+            continue;
+        }
+        let mut response = Response { url: url.clone(), status, reason, headers, body: Vec::new() };
+        if status == 204 || status == 304 {
+            return Ok(response);
+        }
+        let chunked = response.header("Transfer-Encoding")
+            .map(|te| te.rsplit(',').next().unwrap_or_default().trim().eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false);
+        if chunked {
+            response.body = read_chunked(reader)?;
+        } else if let Some(len) = response.header("Content-Length") {
+            let len: u64 = len.parse().map_err(|_| invalid("malformed Content-Length"))?;
+            if len > MAX_BODY_LEN {
+                return Err(invalid("body too large"));
+            }
+            let mut body = vec![0u8; len as usize];
+            reader.read_exact(&mut body)?;
+            response.body = body;
+        } else {
+            reader.take(MAX_BODY_LEN + 1).read_to_end(&mut response.body)?;
+            if response.body.len() as u64 > MAX_BODY_LEN {
+                return Err(invalid("body too large"));
+            }
+        }
+        return Ok(response);
+    }
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Malformed chunk size: {}", line.trim_end())))?;
+        if size == 0 {
+            // Trailers up to the final empty line
+            while !read_line(reader)?.trim_end().is_empty() {}
+            return Ok(body);
+        }
+        if body.len() as u64 + size > MAX_BODY_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "Chunked body too large"));
+        }
+        let start = body.len();
+        body.resize(start + size as usize, 0);
+        reader.read_exact(&mut body[start..])?;
+        read_line(reader)?;
+    }
+}
+
+/// `href` and `src` attribute values in an HTML page
+fn links(html: &str) -> Vec<&str> {
+    let lower = html.to_ascii_lowercase();
+    let mut links = Vec::new();
+    for attr in &["href=", "src="] {
+        let mut from = 0;
+        while let Some(i) = lower[from..].find(attr) {
+            let start = from + i + attr.len();
+            from = start;
+            // Skip e.g. data-src=
+            if start > attr.len() && !lower.as_bytes()[start - attr.len() - 1].is_ascii_whitespace() {
+                continue;
+            }
+            let value = &html[start..];
+            let link = match value.chars().next() {
+                Some(q @ ('"' | '\'')) => value[1..].split(q).next(),
+                Some(_) => value.split(|c: char| c.is_ascii_whitespace() || c == '>').next(),
+                None => None
+            };
+            if let Some(link) = link {
+                links.push(link);
+            }
+        }
+    }
+    links
+}
+
+/// Fetch `url` and, up to `depth` links away, the pages and resources it links to on the same host,
+/// saving them below `dir/<host>/`. Returns the saved files; linked resources that fail are skipped.
+pub(crate) fn mirror_with<F>(url: &str, dir: &Path, depth: u32, mut fetch: F) -> Result<Vec<PathBuf>, Error>
+    where
+        F: FnMut(&str) -> Result<Response, Error>,
+{
+    let root = Url::parse(url)?;
+    let mut queue = VecDeque::new();
+    let mut seen = HashSet::new();
+    let mut saved = Vec::new();
+    seen.insert(root.to_string());
+    queue.push_back((root.clone(), depth));
+    while let Some((url, remaining)) = queue.pop_front() {
+        let response = match fetch(&url.to_string()) {
+            Ok(response) if (200..300).contains(&response.status) => response,
+            Ok(response) if url == root => {
+                return Err(Error::new(ErrorKind::Other, format!("{}: {} {}", url, response.status, response.reason)));
+            },
+            Err(e) if url == root => return Err(e),
+            Ok(response) => {
+                warn!("Skipping {}: {} {}", url, response.status, response.reason);
+                continue;
+            },
+            Err(e) => {
+                warn!("Skipping {}: {}", url, e);
+                continue;
+            }
+        };
+        let path = dir.join(url.local_path());
+        // "/a" is saved as the file a and "/a/" as a/index.html, so only the first of them fits
+        if path.is_dir() || path.parent().is_some_and(|p| p.ancestors().take_while(|a| *a != dir).any(Path::is_file)) {
+            warn!("Skipping {}: {} conflicts with a file already saved", url, path.display());
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, &response.body)?;
+        info!("Saved {} to {}", url, path.display());
+        saved.push(path);
+        if remaining == 0 || !response.is_html() {
+            continue;
+        }
+        let base = Url::parse(&response.url).unwrap_or(url);
+        for link in links(&String::from_utf8_lossy(&response.body)) {
+            if let Some(link) = base.join(link) {
+                if link.host == root.host && link.port == root.port && seen.insert(link.to_string()) {
+                    queue.push_back((link, remaining - 1));
+                }
+            }
+        }
+    }
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::rc::Rc;
+    use std::io::{Cursor, ErrorKind, Read, Write};
+    use crate::site::{fetch_with, links, mirror_with, read_response, Response, Url};
+
+    /// Replays a canned response and records the request
+    struct Canned {
+        response: Cursor<Vec<u8>>,
+        request: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Canned {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    impl Write for Canned {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.request.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bodies() {
+        let read = |s: &str| read_response(&mut Cursor::new(s.as_bytes().to_vec()), String::from("http://x.i2p/"));
+        let r = read("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello world").unwrap();
+        assert_eq!((r.status, r.reason.as_str(), r.body.as_slice()), (200, "OK", &b"hello"[..]));
+        let r = read("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5;x=y\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: t\r\n\r\n").unwrap();
+        assert_eq!(r.body, b"hello world");
+        assert_eq!(read("HTTP/1.0 200 OK\r\n\r\nuntil close").unwrap().body, b"until close");
+        assert!(read("HTTP/1.1 304 Not Modified\r\n\r\n").unwrap().body.is_empty());
+        assert!(read("HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nshort").is_err());
+        assert!(read("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").is_err());
+        let err = read("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5").unwrap_err();
+        assert_eq!((err.kind(), err.to_string().as_str()), (ErrorKind::UnexpectedEof, "HTTP body truncated"));
+        assert!(read("SSH-2.0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn urls() {
+        let base = Url::parse("http://Example.i2p/a/b/page.html?q=1#top").unwrap();
+        assert_eq!(base.to_string(), "http://example.i2p/a/b/page.html?q=1");
+        assert_eq!(base.join("img.png").unwrap().to_string(), "http://example.i2p/a/b/img.png");
+        assert_eq!(base.join("../../../c/./d").unwrap().to_string(), "http://example.i2p/c/d");
+        assert_eq!(base.join("/x?y").unwrap().to_string(), "http://example.i2p/x?y");
+        assert_eq!(base.join("?p=2").unwrap().to_string(), "http://example.i2p/a/b/page.html?p=2");
+        assert_eq!(base.join("//other.i2p:8080").unwrap().to_string(), "http://other.i2p:8080/");
+        assert_eq!(base.join("HTTP://other.i2p/z").unwrap().to_string(), "http://other.i2p/z");
+        assert!(base.join("#section").is_none());
+        assert!(base.join("mailto:a@b.i2p").is_none());
+        assert!(base.join("https://example.i2p/").is_none());
+        assert!(base.join("http://example.com/").is_none());
+        assert_eq!(Url::parse("http://example.com/").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(Url::parse("http://example.i2p").unwrap().local_path(), std::path::Path::new("example.i2p/index.html"));
+        assert_eq!(base.join("../../../etc/passwd").unwrap().local_path(), std::path::Path::new("example.i2p/etc/passwd"));
+        assert_eq!(links("<a href=\"/a\">a</a><IMG SRC='b.png'><img data-src=\"c\"><a href=d>"), vec!["/a", "d", "b.png"]);
+    }
+
+    #[test]
+    fn redirects() {
+        let mut responses = vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+            "HTTP/1.1 302 Found\r\nLocation: /new\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 301 Moved\r\nLocation: http://b.i2p:8080/old\r\n\r\n",
+        ];
+        let mut requests = Vec::new();
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let response = fetch_with("http://a.i2p/", |addr| {
+            requests.push(addr.to_string());
+            Ok(Canned { response: Cursor::new(responses.pop().unwrap().as_bytes().to_vec()), request: written.clone() })
+        }).unwrap();
+        assert_eq!(response.body, b"ok");
+        assert_eq!(response.url, "http://b.i2p:8080/new");
+        assert_eq!(requests, vec!["a.i2p:80", "b.i2p:8080", "b.i2p:8080"]);
+        let written = String::from_utf8(written.borrow().clone()).unwrap();
+        assert!(written.starts_with("GET / HTTP/1.1\r\nHost: a.i2p\r\n"));
+        assert!(written.ends_with("GET /new HTTP/1.1\r\nHost: b.i2p:8080\r\nAccept: */*\r\nAccept-Encoding: identity\r\nConnection: close\r\n\r\n"));
+
+        let err = fetch_with("http://a.i2p/x", |_| {
+            Ok(Canned { response: Cursor::new(b"HTTP/1.1 302 Found\r\nLocation: https://clearnet.com/\r\n\r\n".to_vec()), request: Rc::default() })
+        }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn mirror() {
+        let mut pages = HashMap::new();
+        pages.insert("http://site.i2p/", ("text/html", "<a href=\"about/\">about</a><img src=logo.png><a href=\"http://other.i2p/\">x</a><a href=missing>"));
+        pages.insert("http://site.i2p/about/", ("text/html; charset=utf-8", "<a href=\"../deep.html\">deep</a>"));
+        pages.insert("http://site.i2p/logo.png", ("image/png", "PNG"));
+        pages.insert("http://site.i2p/deep.html", ("text/html", "too deep"));
+        let dir = std::env::temp_dir().join(format!("i2p_client_mirror_{}", std::process::id()));
+        let mut fetched = Vec::new();
+        let saved = mirror_with("http://site.i2p/", &dir, 1, |url| {
+            fetched.push(String::from(url));
+            let (status, (content_type, body)) = match pages.get(url) {
+                Some(page) => (200, *page),
+                None => (404, ("text/html", "not found"))
+            };
+            Ok(Response { url: String::from(url), status, reason: String::new(),
+                          headers: vec![(String::from("Content-Type"), String::from(content_type))], body: body.as_bytes().to_vec() })
+        }).unwrap();
+        assert_eq!(fetched, vec!["http://site.i2p/", "http://site.i2p/about/", "http://site.i2p/missing", "http://site.i2p/logo.png"]);
+        assert_eq!(saved.len(), 3);
+        assert_eq!(fs::read_to_string(dir.join("site.i2p/logo.png")).unwrap(), "PNG");
+        assert!(dir.join("site.i2p/about/index.html").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mirror_collision() {
+        let dir = std::env::temp_dir().join(format!("i2p_client_mirror_collision_{}", std::process::id()));
+        let saved = mirror_with("http://site.i2p/", &dir, 1, |url| {
+            let body = if url == "http://site.i2p/" { "<a href=a>a</a><a href=a/>a/</a><a href=b/>b/</a><a href=b>b</a>" } else { url };
+            Ok(Response { url: String::from(url), status: 200, reason: String::new(),
+                          headers: vec![(String::from("Content-Type"), String::from("text/html"))], body: body.as_bytes().to_vec() })
+        }).unwrap();
+        // Whichever of "/a" and "/a/" comes first is kept, the other is skipped
+        assert_eq!(saved.len(), 3);
+        assert_eq!(fs::read_to_string(dir.join("site.i2p/a")).unwrap(), "http://site.i2p/a");
+        assert_eq!(fs::read_to_string(dir.join("site.i2p/b/index.html")).unwrap(), "http://site.i2p/b/");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
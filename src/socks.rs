@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, info, warn};
+
+use crate::http::is_i2p_host;
+use crate::{pipe, SamConfig, StreamSession};
+
+const SOCKS4: u8 = 0x04;
+const SOCKS5: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// SOCKS5 reply codes
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NOT_ALLOWED: u8 = 0x02;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const TTL_EXPIRED: u8 = 0x06;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+// SOCKS4 reply codes
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+
+/// A parsed CONNECT request
+#[derive(Debug, PartialEq)]
+struct Request {
+    version: u8,
+    host: String,
+    port: u16,
+    /// SOCKS5 username or SOCKS4 user id, used to pick an isolated session
+    user: Option<String>,
+}
+
+/// Why a request is refused, with the SOCKS5 reply code to send
+struct Refused(u8, String);
+
+/// Sessions per SOCKS username when isolation is on
+struct Isolation {
+    config: SamConfig,
+    sessions: Mutex<HashMap<String, Arc<UserSession>>>,
+}
+
+/// Session of one SOCKS user, created on first use; only that user's connections wait for it
+struct UserSession {
+    nickname: String,
+    session: Mutex<Option<StreamSession>>,
+}
+
+/// SOCKS5 and SOCKS4a proxy for `.i2p` hosts: every CONNECT opens a [`StreamConnect`](crate::StreamConnect)
+/// over a shared STREAM session and pipes data both ways.
+///
+/// With isolation on, each SOCKS username (or SOCKS4 user id) gets its own TRANSIENT session, i.e.
+/// its own destination, so traffic of different identities cannot be linked by the peers.
+pub struct Socks5Proxy {
+    session: StreamSession,
+    isolation: Option<Isolation>,
+}
+
+impl Socks5Proxy {
+    pub fn new(session: StreamSession) -> Socks5Proxy {
+        Socks5Proxy { session, isolation: None }
+    }
+
+    /// Give each username its own session created on the bridge at `config`; clients without a
+    /// username use the shared session.
+    pub fn isolate(mut self, config: SamConfig) -> Socks5Proxy {
+        self.isolation = Some(Isolation { config, sessions: Mutex::new(HashMap::new()) });
+        self
+    }
+
+    /// Accept clients on `listener` until it fails, handling each on its own thread.
+    pub fn serve(self, listener: TcpListener) -> Result<(), Error> {
+        info!("SOCKS proxy listening on {}", listener.local_addr()?);
+        let proxy = Arc::new(self);
+        for client in listener.incoming() {
+            let client = client?;
+            let proxy = proxy.clone();
+            thread::spawn(move || {
+                let peer = client.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                if let Err(e) = proxy.handle(client) {
+                    debug!("SOCKS client {}: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Serve a single client connection until either side closes.
+    pub fn handle(&self, mut client: TcpStream) -> Result<(), Error> {
+        let request = match handshake(&mut client, self.isolation.is_some())? {
+            Ok(request) => request,
+            Err(Refused(code, msg)) => {
+                let version = if code == SOCKS4_REJECTED { SOCKS4 } else { SOCKS5 };
+                reply(&mut client, version, code)?;
+                let _ = client.shutdown(Shutdown::Both);
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+        };
+        info!("SOCKS{} CONNECT {}:{}", request.version, request.host, request.port);
+        let session = match self.session_for(request.user.as_deref()) {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("Unable to create a session for {}: {}", request.user.as_deref().unwrap_or_default(), e);
+                let code = if request.version == SOCKS4 { SOCKS4_REJECTED } else { GENERAL_FAILURE };
+                reply(&mut client, request.version, code)?;
+                return Err(e);
+            }
+        };
+        let stream = match session.connect((request.host.as_str(), request.port)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Unable to reach {}:{}: {}", request.host, request.port, e);
+                let code = if request.version == SOCKS4 { SOCKS4_REJECTED } else { reply_code(&e) };
+                reply(&mut client, request.version, code)?;
+                return Err(e);
+            }
+        };
+        let code = if request.version == SOCKS4 { SOCKS4_GRANTED } else { SUCCEEDED };
+        reply(&mut client, request.version, code)?;
+        let (sent, received) = pipe(client, stream)?;
+        debug!("{}:{} closed (sent={}, received={})", request.host, request.port, sent, received);
+        Ok(())
+    }
+
+    fn session_for(&self, user: Option<&str>) -> Result<StreamSession, Error> {
+        let (isolation, user) = match (&self.isolation, user) {
+            (Some(isolation), Some(user)) if !user.is_empty() => (isolation, user),
+            _ => return Ok(self.session.clone())
+        };
+        let slot = {
+            let mut sessions = isolation.sessions.lock().unwrap();
+            let nickname = format!("{}-{}", self.session.nickname(), sessions.len() + 1);
+            sessions.entry(String::from(user))
+                .or_insert_with(|| Arc::new(UserSession { nickname, session: Mutex::new(None) }))
+                .clone()
+        };
+        let mut session = slot.session.lock().unwrap();
+        if let Some(session) = session.as_ref() {
+            return Ok(session.clone());
+        }
+        info!("Creating session {} for SOCKS user {}", slot.nickname, user);
+        let created = StreamSession::create_with_resolver(&isolation.config, "TRANSIENT", &slot.nickname, &[], self.session.resolver().clone())?;
+        *session = Some(created.clone());
+        Ok(created)
+    }
+}
+
+/// SOCKS5 reply code for a failed STREAM CONNECT
+fn reply_code(e: &Error) -> u8 {
+    match e.kind() {
+        ErrorKind::NotFound => HOST_UNREACHABLE,
+        ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        ErrorKind::TimedOut => TTL_EXPIRED,
+        _ => GENERAL_FAILURE
+    }
+}
+
+fn reply<W: Write>(client: &mut W, version: u8, code: u8) -> Result<(), Error> {
+    if version == SOCKS4 {
+        client.write_all(&[0x00, code, 0, 0, 0, 0, 0, 0])
+    } else {
+        // The bound address means nothing over I2P
+        client.write_all(&[SOCKS5, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8, Error> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_vec<R: Read>(r: &mut R, len: usize) -> Result<Vec<u8>, Error> {
+    let mut v = vec![0u8; len];
+    r.read_exact(&mut v)?;
+    Ok(v)
+}
+
+/// NUL terminated string of at most 255 bytes, as in SOCKS4
+fn read_cstr<R: Read>(r: &mut R) -> Result<String, Error> {
+    let mut v = Vec::new();
+    loop {
+        match read_u8(r)? {
+            0 => break,
+            _ if v.len() == 255 => return Err(Error::new(ErrorKind::InvalidData, "SOCKS4 field too long")),
+            b => v.push(b)
+        }
+    }
+    String::from_utf8(v).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Negotiate with the client up to its CONNECT request. The outer error is an I/O failure, the
+/// inner one a request to refuse with the given reply code.
+fn handshake<S: Read + Write>(client: &mut S, isolate: bool) -> Result<Result<Request, Refused>, Error> {
+    match read_u8(client)? {
+        SOCKS5 => socks5(client, isolate),
+        SOCKS4 => socks4(client),
+        v => Err(Error::new(ErrorKind::InvalidData, format!("Unsupported SOCKS version {}", v)))
+    }
+}
+
+fn socks5<S: Read + Write>(client: &mut S, isolate: bool) -> Result<Result<Request, Refused>, Error> {
+    let num_methods = read_u8(client)? as usize;
+    let methods = read_vec(client, num_methods)?;
+    let method = if isolate && methods.contains(&USER_PASS) {
+        USER_PASS
+    } else if methods.contains(&NO_AUTH) {
+        NO_AUTH
+    } else if methods.contains(&USER_PASS) {
+        USER_PASS
+    } else {
+        NO_ACCEPTABLE_METHODS
+    };
+    client.write_all(&[SOCKS5, method])?;
+    let user = match method {
+        NO_ACCEPTABLE_METHODS => return Err(Error::new(ErrorKind::InvalidData, "No acceptable SOCKS5 authentication method")),
+        USER_PASS => {
+            // RFC 1929; any credentials are accepted, the username only selects the identity
+            let _version = read_u8(client)?;
+            let len = read_u8(client)? as usize;
+            let user = String::from_utf8_lossy(&read_vec(client, len)?).into_owned();
+            let len = read_u8(client)? as usize;
+            read_vec(client, len)?;
+            client.write_all(&[0x01, 0x00])?;
+            Some(user)
+        },
+        _ => None
+    };
+
+    let header = read_vec(client, 4)?;
+    if header[0] != SOCKS5 {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 request"));
+    }
+    let host = match header[3] {
+        ATYP_DOMAIN => {
+            let len = read_u8(client)? as usize;
+            Some(String::from_utf8_lossy(&read_vec(client, len)?).into_owned())
+        },
+        ATYP_IPV4 => { read_vec(client, 4)?; None },
+        ATYP_IPV6 => { read_vec(client, 16)?; None },
+        t => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid SOCKS5 address type {}", t)))
+    };
+    let port = read_vec(client, 2)?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    if header[1] != CMD_CONNECT {
+        return Ok(Err(Refused(COMMAND_NOT_SUPPORTED, format!("Unsupported SOCKS5 command {}", header[1]))));
+    }
+    let host = match host {
+        Some(host) => host,
+        None => return Ok(Err(Refused(ADDRESS_TYPE_NOT_SUPPORTED, String::from("IP addresses cannot be reached over I2P"))))
+    };
+    if !is_i2p_host(&host) {
+        return Ok(Err(Refused(NOT_ALLOWED, format!("Not an I2P host: {}", host))));
+    }
+    Ok(Ok(Request { version: SOCKS5, host, port, user }))
+}
+
+fn socks4<S: Read + Write>(client: &mut S) -> Result<Result<Request, Refused>, Error> {
+    let header = read_vec(client, 7)?;
+    let port = u16::from_be_bytes([header[1], header[2]]);
+    let ip = &header[3..7];
+    let user = read_cstr(client)?;
+    // SOCKS4a signals a hostname with the invalid address 0.0.0.x
+    if !(ip[..3] == [0, 0, 0] && ip[3] != 0) {
+        return Ok(Err(Refused(SOCKS4_REJECTED, String::from("SOCKS4 without a hostname (SOCKS4a) cannot be reached over I2P"))));
+    }
+    let host = read_cstr(client)?;
+    if header[0] != CMD_CONNECT {
+        return Ok(Err(Refused(SOCKS4_REJECTED, format!("Unsupported SOCKS4 command {}", header[0]))));
+    }
+    if !is_i2p_host(&host) {
+        return Ok(Err(Refused(SOCKS4_REJECTED, format!("Not an I2P host: {}", host))));
+    }
+    Ok(Ok(Request { version: SOCKS4, host, port, user: Some(user).filter(|u| !u.is_empty()) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use crate::socks::{handshake, Request, Socks5Proxy, ADDRESS_TYPE_NOT_SUPPORTED, GENERAL_FAILURE, NOT_ALLOWED, SOCKS4,
+                       SOCKS4_REJECTED, SOCKS5};
+    use crate::tests::fake_bridge;
+    use crate::{SamConfig, StreamSession};
+
+    /// Scripted client: reads come from `input`, writes are collected
+    struct Client {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Client {
+        fn new(input: &[u8]) -> Client {
+            Client { input: Cursor::new(input.to_vec()), output: Vec::new() }
+        }
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn socks5_connect(host: &str, port: u16) -> Vec<u8> {
+        let mut req = vec![5, 1, 0, 3, host.len() as u8];
+        req.extend_from_slice(host.as_bytes());
+        req.extend_from_slice(&port.to_be_bytes());
+        req
+    }
+
+    #[test]
+    fn requests() {
+        let mut input = vec![5, 1, 0];
+        input.extend(socks5_connect("example.i2p", 80));
+        let mut client = Client::new(&input);
+        let req = handshake(&mut client, false).unwrap().ok().unwrap();
+        assert_eq!(req, Request { version: SOCKS5, host: String::from("example.i2p"), port: 80, user: None });
+        assert_eq!(client.output, vec![5, 0]);
+
+        // Username/password when isolating
+        let mut input = vec![5, 2, 0, 2, 1, 5];
+        input.extend_from_slice(b"alice");
+        input.extend_from_slice(&[1, b'x']);
+        input.extend(socks5_connect("example.i2p", 443));
+        let mut client = Client::new(&input);
+        let req = handshake(&mut client, true).unwrap().ok().unwrap();
+        assert_eq!(req.user.as_deref(), Some("alice"));
+        assert_eq!(client.output, vec![5, 2, 1, 0]);
+
+        let mut input = vec![4, 1, 0, 80, 0, 0, 0, 1];
+        input.extend_from_slice(b"bob\0example.i2p\0");
+        let req = handshake(&mut Client::new(&input), false).unwrap().ok().unwrap();
+        assert_eq!(req, Request { version: SOCKS4, host: String::from("example.i2p"), port: 80, user: Some(String::from("bob")) });
+
+        let refused = handshake(&mut Client::new(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80]), false).unwrap().err().unwrap();
+        assert_eq!(refused.0, ADDRESS_TYPE_NOT_SUPPORTED);
+        let mut input = vec![5, 1, 0];
+        input.extend(socks5_connect("example.com", 80));
+        assert_eq!(handshake(&mut Client::new(&input), false).unwrap().err().unwrap().0, NOT_ALLOWED);
+        assert!(handshake(&mut Client::new(&[5, 1, 2]), false).is_err());
+    }
+
+    #[test]
+    fn isolation() {
+        let bridge = fake_bridge();
+        let config = SamConfig::new("127.0.0.1", bridge.port);
+        let session = StreamSession::create(&config, "TRANSIENT", "Socks", &[]).unwrap();
+        let proxy = Arc::new(Socks5Proxy::new(session).isolate(config));
+        let users = ["alice", "bob", "alice", "bob"];
+        let threads: Vec<_> = users.iter().map(|&user| {
+            let proxy = proxy.clone();
+            thread::spawn(move || proxy.session_for(Some(user)).unwrap().nickname().to_string())
+        }).collect();
+        let nicknames: Vec<String> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(nicknames[0], nicknames[2]);
+        assert_eq!(nicknames[1], nicknames[3]);
+        assert_ne!(nicknames[0], nicknames[1]);
+        assert_eq!(proxy.session_for(None).unwrap().nickname(), "Socks");
+        // User sessions share the proxy's resolver
+        assert!(Arc::ptr_eq(proxy.session_for(Some("alice")).unwrap().resolver(), proxy.session.resolver()));
+        // One session per user, however many connections raced to create it
+        assert_eq!(bridge.received.lock().unwrap().iter().filter(|l| l.starts_with("SESSION CREATE")).count(), 3);
+    }
+
+    #[test]
+    fn session_failure() {
+        let bridge = fake_bridge();
+        let session = StreamSession::create(&SamConfig::new("127.0.0.1", bridge.port), "TRANSIENT", "Socks", &[]).unwrap();
+        // Nothing listens there, so no session can be created for a user
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let proxy = Socks5Proxy::new(session).isolate(SamConfig::new("127.0.0.1", closed));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || proxy.serve(listener));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut input = vec![5, 1, 2, 1, 5];
+        input.extend_from_slice(b"alice");
+        input.extend_from_slice(&[1, b'x']);
+        input.extend(socks5_connect("example.i2p", 80));
+        client.write_all(&input).unwrap();
+        let mut buf = [0u8; 14];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[4..6], &[5, GENERAL_FAILURE]);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut input = vec![4, 1, 0, 80, 0, 0, 0, 1];
+        input.extend_from_slice(b"bob\0example.i2p\0");
+        client.write_all(&input).unwrap();
+        let mut buf = [0u8; 8];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[1], SOCKS4_REJECTED);
+    }
+
+    #[test]
+    fn proxy() {
+        let bridge = fake_bridge();
+        let session = StreamSession::create(&SamConfig::new("127.0.0.1", bridge.port), "TRANSIENT", "Socks", &[]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || Socks5Proxy::new(session).serve(listener));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[5, 1, 0]).unwrap();
+        client.write_all(&socks5_connect("example.i2p", 80)).unwrap();
+        let mut buf = [0u8; 12];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..2], &[5, 0]);
+        assert_eq!(buf[3], 0);
+        client.write_all(b"ping\n").unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping\n");
+        assert!(bridge.received.lock().unwrap().iter().any(|l| l.starts_with("STREAM CONNECT ID=Socks DESTINATION=pubkey SILENT=false TO_PORT=80")));
+    }
+}
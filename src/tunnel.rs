@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Error, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::destination::encode_i2p_base64;
+use crate::http::read_head;
+use crate::{pipe, Destination, I2pSocketAddr, StreamConnect, StreamSession};
+
+/// Request headers set by a server tunnel; copies sent by the peer are removed so they cannot be spoofed
+static DEST_HEADERS: &[&str] = &["x-i2p-destb64", "x-i2p-destb32", "x-i2p-desthash"];
+
+/// Bytes moved over one tunnelled connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// TCP to I2P
+    pub sent: u64,
+    /// I2P to TCP
+    pub received: u64,
+    pub duration: Duration,
+}
+
+/// Totals over the connections a tunnel has handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TunnelStats {
+    pub connections: u64,
+    pub active: u64,
+    pub failed: u64,
+    pub sent: u64,
+    pub received: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    connections: AtomicU64,
+    active: AtomicU64,
+    failed: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn snapshot(&self) -> TunnelStats {
+        TunnelStats {
+            connections: self.connections.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a connection refused before it was piped
+    pub(crate) fn refuse(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection while `f` pipes it
+    pub(crate) fn track<F>(&self, f: F) -> Result<ConnectionStats, Error>
+        where
+            F: FnOnce() -> Result<(u64, u64), Error>,
+    {
+        let start = Instant::now();
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let res = f();
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        let (sent, received) = res.map_err(|e| {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+            e
+        })?;
+        self.sent.fetch_add(sent, Ordering::Relaxed);
+        self.received.fetch_add(received, Ordering::Relaxed);
+        Ok(ConnectionStats { sent, received, duration: start.elapsed() })
+    }
+}
+
+/// Exposes a remote I2P destination as a local TCP port, like an i2ptunnel client tunnel: every
+/// TCP connection accepted gets its own [`StreamConnect`](crate::StreamConnect) to `to` over a
+/// shared STREAM session. Clones share the session and statistics.
+#[derive(Clone)]
+pub struct ClientTunnel {
+    session: StreamSession,
+    to: I2pSocketAddr,
+    counters: Arc<Counters>,
+}
+
+impl ClientTunnel {
+    pub fn new(session: StreamSession, to: I2pSocketAddr) -> ClientTunnel {
+        ClientTunnel { session, to, counters: Arc::new(Counters::default()) }
+    }
+
+    pub fn to(&self) -> &I2pSocketAddr {
+        &self.to
+    }
+
+    /// Totals so far, including connections still open
+    pub fn stats(&self) -> TunnelStats {
+        self.counters.snapshot()
+    }
+
+    /// Accept TCP connections on `listener` until it fails, tunnelling each on its own thread.
+    pub fn serve(self, listener: TcpListener) -> Result<(), Error> {
+        info!("Client tunnel {} -> {}", listener.local_addr()?, self.to);
+        for client in listener.incoming() {
+            let client = client?;
+            let tunnel = self.clone();
+            thread::spawn(move || {
+                let peer = client.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                match tunnel.handle(client) {
+                    Ok(stats) => info!("{} <-> {}: sent {} bytes, received {} bytes in {:?}",
+                                       peer, tunnel.to, stats.sent, stats.received, stats.duration),
+                    Err(e) => warn!("{} <-> {}: {}", peer, tunnel.to, e)
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Tunnel a single TCP connection until both directions are closed.
+    pub fn handle(&self, client: TcpStream) -> Result<ConnectionStats, Error> {
+        self.counters.track(|| {
+            let stream = self.session.connect(&self.to)?;
+            debug!("Tunnel connected to {}", stream.peer_addr()?);
+            pipe(client, stream)
+        })
+    }
+}
+
+/// Open connections per peer destination, bounded by `max`
+struct PeerLimit {
+    max: usize,
+    active: Mutex<HashMap<String, usize>>,
+}
+
+/// A peer's place in a [`PeerLimit`], given back when dropped
+struct PeerSlot<'a> {
+    limit: &'a PeerLimit,
+    peer: String,
+}
+
+impl PeerLimit {
+    fn new(max: usize) -> PeerLimit {
+        PeerLimit { max, active: Mutex::new(HashMap::new()) }
+    }
+
+    fn acquire(&self, peer: &str) -> Option<PeerSlot<'_>> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(String::from(peer)).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(PeerSlot { limit: self, peer: String::from(peer) })
+    }
+}
+
+impl Drop for PeerSlot<'_> {
+    fn drop(&mut self) {
+        let mut active = self.limit.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Publishes a local TCP service on the destination of a STREAM session, like an i2ptunnel server
+/// tunnel: every incoming stream is connected to `target`. Use [`StreamSession::create_persistent`]
+/// to keep the same address across restarts. Clones share the session, limits and statistics.
+#[derive(Clone)]
+pub struct ServerTunnel {
+    session: StreamSession,
+    target: SocketAddr,
+    inject_headers: bool,
+    peer_limit: Option<Arc<PeerLimit>>,
+    counters: Arc<Counters>,
+}
+
+impl ServerTunnel {
+    pub fn new(session: StreamSession, target: SocketAddr) -> ServerTunnel {
+        ServerTunnel { session, target, inject_headers: false, peer_limit: None, counters: Arc::new(Counters::default()) }
+    }
+
+    /// Treat the target as an HTTP server: add `X-I2P-DestB64`, `X-I2P-DestB32` and `X-I2P-DestHash`
+    /// with the peer's destination to each request and ask for the connection to be closed after the
+    /// response, so every request carries the headers.
+    pub fn inject_headers(mut self, inject_headers: bool) -> ServerTunnel {
+        self.inject_headers = inject_headers;
+        self
+    }
+
+    /// Refuse streams from a destination that already has `max` open
+    pub fn max_connections_per_peer(mut self, max: usize) -> ServerTunnel {
+        self.peer_limit = Some(Arc::new(PeerLimit::new(max)));
+        self
+    }
+
+    /// Count streams in `counters`, shared with whoever reports them
+    pub(crate) fn counters(mut self, counters: Arc<Counters>) -> ServerTunnel {
+        self.counters = counters;
+        self
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    /// Totals so far, including connections still open; streams refused by the per-peer limit count as failed
+    pub fn stats(&self) -> TunnelStats {
+        self.counters.snapshot()
+    }
+
+    /// Accept streams on the session until accepting fails, tunnelling each on its own thread.
+    pub fn serve(self) -> Result<(), Error> {
+        let local = Destination::new(self.session.local_dest());
+        info!("Server tunnel {} -> {}", local.to_b32().unwrap_or_else(|| local.to_string()), self.target);
+        loop {
+            let stream = self.session.accept()?;
+            let tunnel = self.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                match tunnel.handle(stream) {
+                    Ok(stats) => info!("{} <-> {}: sent {} bytes, received {} bytes in {:?}",
+                                       tunnel.target, peer, stats.sent, stats.received, stats.duration),
+                    Err(e) => warn!("{} <-> {}: {}", tunnel.target, peer, e)
+                }
+            });
+        }
+    }
+
+    /// Connect an accepted stream to the target until both directions are closed.
+    pub fn handle(&self, stream: StreamConnect) -> Result<ConnectionStats, Error> {
+        let peer = stream.peer_addr()?;
+        let _slot = match &self.peer_limit {
+            Some(limit) => match limit.acquire(peer.dest().as_str()) {
+                Some(slot) => Some(slot),
+                None => {
+                    self.counters.refuse();
+                    return Err(Error::new(ErrorKind::ConnectionRefused, "Too many connections from peer"));
+                }
+            },
+            None => None
+        };
+        self.counters.track(|| {
+            let mut tcp = TcpStream::connect(self.target)?;
+            let mut received = 0;
+            if self.inject_headers {
+                let mut reader = BufReader::new(stream.try_clone()?);
+                let head = read_head(&mut reader)?;
+                if head.is_empty() {
+                    return Ok((0, 0));
+                }
+                let head = inject_dest_headers(&head, &peer);
+                tcp.write_all(head.as_bytes())?;
+                tcp.write_all(reader.buffer())?;
+                received = (head.len() + reader.buffer().len()) as u64;
+            }
+            debug!("Tunnel connected {} to {}", peer, self.target);
+            let (sent, rest) = pipe(tcp, stream)?;
+            Ok((sent, received + rest))
+        })
+    }
+}
+
+/// Request head with the peer's destination headers added and `Connection: close`
+fn inject_dest_headers(head: &[String], peer: &I2pSocketAddr) -> String {
+    let mut out = format!("{}\r\n", head[0]);
+    out.push_str(&format!("X-I2P-DestB64: {}\r\n", peer.dest()));
+    if let Some(b32) = peer.dest().to_b32() {
+        out.push_str(&format!("X-I2P-DestB32: {}\r\n", b32));
+    }
+    if let Some(bytes) = peer.dest().dest_bytes() {
+        out.push_str(&format!("X-I2P-DestHash: {}\r\n", encode_i2p_base64(&Sha256::digest(&bytes))));
+    }
+    for line in &head[1..] {
+        let name = line.split(':').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if name == "connection" || name == "keep-alive" || DEST_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out.push_str("Connection: close\r\n\r\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use sha2::{Digest, Sha256};
+    use crate::destination::encode_i2p_base64;
+    use crate::tests::fake_bridge;
+    use crate::tunnel::{inject_dest_headers, ClientTunnel, PeerLimit, ServerTunnel, TunnelStats};
+    use crate::{Destination, I2pSocketAddr, SamConfig, StreamSession};
+
+    fn wait_for<F: Fn() -> bool>(done: F) {
+        let start = Instant::now();
+        while !done() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn client() {
+        let bridge = fake_bridge();
+        let session = StreamSession::create(&SamConfig::new("127.0.0.1", bridge.port), "TRANSIENT", "Tunnel", &[]).unwrap();
+        let tunnel = ClientTunnel::new(session, "service.i2p:8080".parse().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = tunnel.clone();
+        thread::spawn(move || serving.serve(listener));
+
+        // The fake bridge echoes and closes once we are done writing
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"hello\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut echo = String::new();
+        client.read_to_string(&mut echo).unwrap();
+        assert_eq!(echo, "hello\n");
+
+        let expected = TunnelStats { connections: 1, active: 0, failed: 0, sent: 6, received: 6 };
+        wait_for(|| tunnel.stats() == expected);
+        assert_eq!(tunnel.stats(), expected);
+        assert!(bridge.received.lock().unwrap().iter().any(|l| l.starts_with("STREAM CONNECT") && l.contains("TO_PORT=8080")));
+    }
+
+    #[test]
+    fn server() {
+        let bridge = fake_bridge();
+        let session = StreamSession::create(&SamConfig::new("127.0.0.1", bridge.port), "TRANSIENT", "Server", &[]).unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tunnel = ServerTunnel::new(session, target.local_addr().unwrap());
+        let serving = tunnel.clone();
+        thread::spawn(move || serving.serve());
+
+        // What the service writes comes back through the fake bridge's echo
+        let (mut conn, _) = target.accept().unwrap();
+        conn.write_all(b"hello\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(conn.try_clone().unwrap()).read_line(&mut line).unwrap();
+        assert_eq!(line, "hello\n");
+        conn.shutdown(Shutdown::Write).unwrap();
+        wait_for(|| tunnel.stats().received == 6);
+        assert_eq!(tunnel.stats().sent, 6);
+        assert!(bridge.received.lock().unwrap().iter().any(|l| l.starts_with("STREAM ACCEPT ID=Server")));
+    }
+
+    #[test]
+    fn dest_headers() {
+        let mut bytes = vec![7u8; 384];
+        bytes.extend_from_slice(&[0, 0, 0]);
+        let dest = Destination::from(base64::encode(&bytes).replace('+', "-").replace('/', "~"));
+        let peer = I2pSocketAddr::new(dest.clone(), 1234);
+        let head: Vec<String> = ["GET / HTTP/1.1", "Host: site.i2p", "X-I2P-DestB64: spoofed", "x-i2p-desthash: spoofed",
+                                 "Connection: keep-alive"]
+            .iter().map(|l| String::from(*l)).collect();
+        let hash = encode_i2p_base64(&Sha256::digest(&bytes));
+        assert_eq!(hash.len(), 44);
+        assert_eq!(inject_dest_headers(&head, &peer), format!(
+            "GET / HTTP/1.1\r\nX-I2P-DestB64: {}\r\nX-I2P-DestB32: {}\r\nX-I2P-DestHash: {}\r\nHost: site.i2p\r\n\
+             Connection: close\r\n\r\n",
+            dest, dest.to_b32().unwrap(), hash));
+    }
+
+    #[test]
+    fn peer_limit() {
+        let limit = PeerLimit::new(2);
+        let a1 = limit.acquire("a").unwrap();
+        let _a2 = limit.acquire("a").unwrap();
+        assert!(limit.acquire("a").is_none());
+        assert!(limit.acquire("b").is_some());
+        drop(a1);
+        assert!(limit.acquire("a").is_some());
+        assert!(limit.active.lock().unwrap().get("b").is_none());
+    }
+}
@@ -0,0 +1,583 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::tunnel::Counters;
+use crate::{ClientTunnel, Destination, HttpProxy, I2pSocketAddr, KeyStore, PendingAccept, Resolver, SamConfig, ServerTunnel, Socks5Proxy, StreamSession, TunnelStats};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TunnelType {
+    /// Local TCP port to a remote destination, see [`ClientTunnel`]
+    Client,
+    /// Local TCP service published on I2P, see [`ServerTunnel`]
+    Server,
+    /// See [`Socks5Proxy`]
+    Socks,
+    /// See [`HttpProxy`]
+    HttpProxy,
+}
+
+impl fmt::Display for TunnelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TunnelType::Client => "client",
+            TunnelType::Server => "server",
+            TunnelType::Socks => "socks",
+            TunnelType::HttpProxy => "http-proxy",
+        })
+    }
+}
+
+/// One `[tunnels.<name>]` table of a tunnels file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelConfig {
+    #[serde(rename = "type")]
+    pub kind: TunnelType,
+    /// Local address to accept TCP clients on (client, socks, http-proxy)
+    pub listen: Option<String>,
+    /// `dest:port` to connect to (client)
+    pub to: Option<String>,
+    /// Local service to connect incoming streams to (server)
+    pub target: Option<String>,
+    /// Alias of persistent keys in the key store; TRANSIENT if not given
+    pub keys: Option<String>,
+    /// I2CP options for the session, e.g. `inbound.length`
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+    /// Allowed TCP client addresses, or for server tunnels allowed peer destinations (b32 or base64); all if empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Add destination headers to HTTP requests (server)
+    #[serde(default)]
+    pub http: bool,
+    /// Open connections allowed per peer destination (server)
+    pub max_per_peer: Option<usize>,
+    /// Separate destination per SOCKS username (socks)
+    #[serde(default)]
+    pub isolate: bool,
+}
+
+/// Tunnels file, e.g.
+///
+/// ```toml
+/// [tunnels.irc]
+/// type = "client"
+/// listen = "127.0.0.1:6668"
+/// to = "irc.postman.i2p:6667"
+///
+/// [tunnels.site]
+/// type = "server"
+/// target = "127.0.0.1:8000"
+/// keys = "mysite"
+/// http = true
+/// max_per_peer = 10
+/// ```
+///
+/// Tunnels with the same `keys` (or without keys and with the same `options`) share one session.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelsConfig {
+    #[serde(default)]
+    pub tunnels: BTreeMap<String, TunnelConfig>,
+}
+
+/// Sessions are shared by tunnels with the same keys and options
+type SessionKey = (Option<String>, BTreeMap<String, String>);
+
+/// Serves a TCP client of a tunnel, returning the bytes sent and received if known
+type Handler = dyn Fn(TcpStream) -> Result<(u64, u64), Error> + Send + Sync;
+
+impl TunnelConfig {
+    fn session_key(&self) -> SessionKey {
+        (self.keys.clone(), self.options.clone())
+    }
+
+    fn validate<'a>(&'a self, name: &str) -> Result<(), Error> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, format!("Tunnel {}: {}", name, msg));
+        let require = |field: &'a Option<String>, what: &str| field.as_deref().ok_or_else(|| invalid(format!("{} tunnels need `{}`", self.kind, what)));
+        if self.kind == TunnelType::Server {
+            require(&self.target, "target")?.parse::<SocketAddr>().map_err(|e| invalid(format!("target: {}", e)))?;
+            for peer in &self.allow {
+                let dest = Destination::new(peer);
+                if !dest.is_b32() && !dest.is_base64() {
+                    return Err(invalid(format!("allow: {} is not a b32 address or destination", peer)));
+                }
+            }
+        } else {
+            require(&self.listen, "listen")?.parse::<SocketAddr>().map_err(|e| invalid(format!("listen: {}", e)))?;
+            for ip in &self.allow {
+                ip.parse::<IpAddr>().map_err(|e| invalid(format!("allow: {}: {}", ip, e)))?;
+            }
+        }
+        if self.kind == TunnelType::Client {
+            require(&self.to, "to")?.parse::<I2pSocketAddr>().map_err(|e| invalid(format!("to: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+impl TunnelsConfig {
+    pub fn load(path: &Path) -> Result<TunnelsConfig, Error> {
+        let s = fs::read_to_string(path).map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        s.parse()
+    }
+
+    /// Check every tunnel has what its type needs and that shared sessions agree
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut options = HashMap::new();
+        let mut servers = HashMap::new();
+        for (name, tunnel) in &self.tunnels {
+            tunnel.validate(name)?;
+            if let Some(keys) = &tunnel.keys {
+                if options.entry(keys).or_insert(&tunnel.options) != &&tunnel.options {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Tunnels using keys {} have different options", keys)));
+                }
+            }
+            // Incoming streams of a session cannot be told apart, so only one server may accept on it
+            if tunnel.kind == TunnelType::Server {
+                if let Some(other) = servers.insert(tunnel.session_key(), name) {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Server tunnels {} and {} share a session", other, name)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for TunnelsConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: TunnelsConfig = toml::from_str(s).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunnelState {
+    Running,
+    /// Not started or stopped by an error
+    Failed(String),
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelState::Running => f.write_str("running"),
+            TunnelState::Failed(e) => write!(f, "failed: {}", e)
+        }
+    }
+}
+
+/// Status of one tunnel run by a [`TunnelManager`]
+#[derive(Debug, Clone)]
+pub struct TunnelStatus {
+    pub name: String,
+    pub kind: TunnelType,
+    /// Local address listened on, or the b32 address of server tunnels
+    pub address: String,
+    pub state: TunnelState,
+    /// Connections refused by the access list count as failed
+    pub stats: TunnelStats,
+}
+
+struct Running {
+    config: TunnelConfig,
+    address: String,
+    state: Arc<Mutex<TunnelState>>,
+    counters: Arc<Counters>,
+    stop: Arc<AtomicBool>,
+    /// Listener to connect to so a blocked accept sees `stop`, and the thread accepting on it
+    wake: Option<(SocketAddr, JoinHandle<()>)>,
+    /// STREAM ACCEPT of a server tunnel to cancel on stop, and the thread accepting on it
+    accept: Option<(Arc<PendingAccept>, JoinHandle<()>)>,
+}
+
+impl Running {
+    fn failed(config: TunnelConfig, e: &Error) -> Running {
+        Running {
+            config,
+            address: String::new(),
+            state: Arc::new(Mutex::new(TunnelState::Failed(e.to_string()))),
+            counters: Arc::default(),
+            stop: Arc::default(),
+            wake: None,
+            accept: None,
+        }
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some((addr, thread)) = self.wake {
+            if TcpStream::connect(addr).is_ok() {
+                let _ = thread.join();
+            }
+        }
+        if let Some((pending, thread)) = self.accept {
+            pending.cancel();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Runs the tunnels of a [`TunnelsConfig`] and applies changed configurations: only tunnels that
+/// were added, changed or failed are (re)started, the others keep running with their connections.
+/// Tunnels sharing a session with a server tunnel that changed are restarted with it, as stopping
+/// the server means closing its session.
+pub struct TunnelManager {
+    sam: SamConfig,
+    key_store: Option<KeyStore>,
+    /// Shared by the sessions of every tunnel
+    resolver: Arc<Resolver>,
+    sessions: HashMap<SessionKey, StreamSession>,
+    tunnels: BTreeMap<String, Running>,
+    transient: usize,
+}
+
+impl TunnelManager {
+    pub fn new(sam: SamConfig) -> TunnelManager {
+        TunnelManager {
+            sam,
+            key_store: None,
+            resolver: Arc::new(Resolver::from_address_book()),
+            sessions: HashMap::new(),
+            tunnels: BTreeMap::new(),
+            transient: 0,
+        }
+    }
+
+    /// Take the keys of tunnels from `key_store`, e.g. one with a passphrase, instead of
+    /// [`KeyStore::open_default`]
+    pub fn with_key_store(mut self, key_store: KeyStore) -> TunnelManager {
+        self.key_store = Some(key_store);
+        self
+    }
+
+    /// Start, restart and stop tunnels to match `config`. Tunnels that cannot start are reported as
+    /// failed in [`TunnelManager::status`]; only an invalid configuration is an error, leaving the
+    /// running tunnels as they were.
+    pub fn apply(&mut self, config: &TunnelsConfig) -> Result<(), Error> {
+        config.validate()?;
+        let wanted_sessions: BTreeSet<SessionKey> = config.tunnels.values().map(TunnelConfig::session_key).collect();
+        let mut stop: BTreeSet<String> = self.tunnels.iter()
+            .filter(|(name, running)| config.tunnels.get(*name) != Some(&running.config)
+                || *running.state.lock().unwrap() != TunnelState::Running)
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut close: BTreeSet<SessionKey> = self.sessions.keys().filter(|key| !wanted_sessions.contains(*key)).cloned().collect();
+        for name in &stop {
+            let running = &self.tunnels[name];
+            if running.config.kind == TunnelType::Server {
+                close.insert(running.config.session_key());
+            }
+        }
+        for (name, running) in &self.tunnels {
+            if close.contains(&running.config.session_key()) {
+                stop.insert(name.clone());
+            }
+        }
+        for name in &stop {
+            info!("Stopping tunnel {}", name);
+            self.tunnels.remove(name).unwrap().stop();
+        }
+        for key in &close {
+            if let Some(session) = self.sessions.remove(key) {
+                session.close();
+            }
+        }
+        for (name, tunnel) in &config.tunnels {
+            if !self.tunnels.contains_key(name) {
+                info!("Starting {} tunnel {}", tunnel.kind, name);
+                let running = self.start(name, tunnel).unwrap_or_else(|e| {
+                    warn!("Tunnel {} failed to start: {}", name, e);
+                    Running::failed(tunnel.clone(), &e)
+                });
+                self.tunnels.insert(name.clone(), running);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> Vec<TunnelStatus> {
+        self.tunnels.iter().map(|(name, running)| TunnelStatus {
+            name: name.clone(),
+            kind: running.config.kind,
+            address: running.address.clone(),
+            state: running.state.lock().unwrap().clone(),
+            stats: running.counters.snapshot(),
+        }).collect()
+    }
+
+    /// Stop every tunnel and close the sessions
+    pub fn stop(&mut self) {
+        for (_, running) in std::mem::take(&mut self.tunnels) {
+            running.stop();
+        }
+        for (_, session) in self.sessions.drain() {
+            session.close();
+        }
+    }
+
+    fn session(&mut self, config: &TunnelConfig) -> Result<StreamSession, Error> {
+        let key = config.session_key();
+        if let Some(session) = self.sessions.get(&key) {
+            return Ok(session.clone());
+        }
+        let options: Vec<(String, String)> = config.options.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        let session = match &config.keys {
+            Some(keys) => {
+                let default;
+                let store = match &self.key_store {
+                    Some(store) => store,
+                    None => {
+                        default = KeyStore::open_default()?;
+                        &default
+                    }
+                };
+                StreamSession::create_persistent_with_resolver(&self.sam, store, keys, &options, self.resolver.clone())?
+            },
+            None => {
+                self.transient += 1;
+                let nickname = format!("tunnels-{}-{}", process::id(), self.transient);
+                StreamSession::create_with_resolver(&self.sam, "TRANSIENT", &nickname, &options, self.resolver.clone())?
+            }
+        };
+        self.sessions.insert(key, session.clone());
+        Ok(session)
+    }
+
+    fn start(&mut self, name: &str, config: &TunnelConfig) -> Result<Running, Error> {
+        let session = self.session(config)?;
+        let state = Arc::new(Mutex::new(TunnelState::Running));
+        let counters = Arc::new(Counters::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        if config.kind == TunnelType::Server {
+            let target = config.target.as_deref().unwrap_or_default().parse().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            let mut tunnel = ServerTunnel::new(session.clone(), target).inject_headers(config.http).counters(counters.clone());
+            if let Some(max) = config.max_per_peer {
+                tunnel = tunnel.max_connections_per_peer(max);
+            }
+            let local = Destination::new(session.local_dest());
+            let address = local.to_b32().unwrap_or_else(|| local.to_string());
+            let allow = config.allow.clone();
+            let (name, run_state, run_counters, run_stop) = (String::from(name), state.clone(), counters.clone(), stop.clone());
+            let pending = Arc::new(PendingAccept::default());
+            let run_pending = pending.clone();
+            let thread = thread::spawn(move || loop {
+                let stream = match session.accept_cancellable(&run_pending) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        if !run_stop.load(Ordering::SeqCst) {
+                            warn!("Tunnel {}: {}", name, e);
+                            *run_state.lock().unwrap() = TunnelState::Failed(e.to_string());
+                        }
+                        return;
+                    }
+                };
+                if run_stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                let peer = match stream.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(_) => continue
+                };
+                if !allow.is_empty() && !allow.iter().any(|a| a == peer.dest().as_str() || Some(a) == peer.dest().to_b32().as_ref()) {
+                    info!("Tunnel {}: refused {}", name, peer);
+                    run_counters.refuse();
+                    continue;
+                }
+                let (tunnel, name) = (tunnel.clone(), name.clone());
+                thread::spawn(move || {
+                    if let Err(e) = tunnel.handle(stream) {
+                        warn!("Tunnel {} <-> {}: {}", name, peer, e);
+                    }
+                });
+            });
+            return Ok(Running { config: config.clone(), address, state, counters, stop, wake: None, accept: Some((pending, thread)) });
+        }
+
+        let listener = TcpListener::bind(config.listen.as_deref().unwrap_or_default())?;
+        let addr = listener.local_addr()?;
+        let handler: Arc<Handler> = match config.kind {
+            TunnelType::Client => {
+                let to = config.to.as_deref().unwrap_or_default().parse()?;
+                let tunnel = ClientTunnel::new(session, to);
+                Arc::new(move |tcp| tunnel.handle(tcp).map(|s| (s.sent, s.received)))
+            },
+            TunnelType::Socks => {
+                let mut proxy = Socks5Proxy::new(session);
+                if config.isolate {
+                    proxy = proxy.isolate(self.sam.clone());
+                }
+                Arc::new(move |tcp| proxy.handle(tcp).map(|_| (0, 0)))
+            },
+            _ => {
+                let proxy = HttpProxy::new(session);
+                Arc::new(move |tcp| proxy.handle(tcp).map(|_| (0, 0)))
+            }
+        };
+        let allow: Vec<IpAddr> = config.allow.iter().filter_map(|ip| ip.parse().ok()).collect();
+        let (name, run_state, run_counters, run_stop) = (String::from(name), state.clone(), counters.clone(), stop.clone());
+        let thread = thread::spawn(move || {
+            for client in listener.incoming() {
+                if run_stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                let client = match client {
+                    Ok(client) => client,
+                    Err(e) => {
+                        warn!("Tunnel {}: {}", name, e);
+                        *run_state.lock().unwrap() = TunnelState::Failed(e.to_string());
+                        return;
+                    }
+                };
+                let peer = match client.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(_) => continue
+                };
+                if !allow.is_empty() && !allow.contains(&peer.ip()) {
+                    info!("Tunnel {}: refused {}", name, peer);
+                    run_counters.refuse();
+                    continue;
+                }
+                let (handler, counters, name) = (handler.clone(), run_counters.clone(), name.clone());
+                thread::spawn(move || {
+                    if let Err(e) = counters.track(|| handler(client)) {
+                        warn!("Tunnel {} <-> {}: {}", name, peer, e);
+                    }
+                });
+            }
+        });
+        Ok(Running { config: config.clone(), address: addr.to_string(), state, counters, stop, wake: Some((addr, thread)), accept: None })
+    }
+}
+
+impl Drop for TunnelManager {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+    use crate::tests::fake_bridge;
+    use crate::tunnels::{TunnelManager, TunnelState, TunnelType, TunnelsConfig};
+    use crate::SamConfig;
+
+    #[test]
+    fn config() {
+        let config: TunnelsConfig = r#"
+            [tunnels.irc]
+            type = "client"
+            listen = "127.0.0.1:6668"
+            to = "irc.postman.i2p:6667"
+            options = { "inbound.length" = "2" }
+
+            [tunnels.site]
+            type = "server"
+            target = "127.0.0.1:8000"
+            keys = "mysite"
+            http = true
+            allow = ["abcd.b32.i2p"]
+        "#.parse().unwrap();
+        assert_eq!(config.tunnels["irc"].kind, TunnelType::Client);
+        assert_eq!(config.tunnels["irc"].options["inbound.length"], "2");
+        assert!(config.tunnels["site"].http);
+
+        let invalid = |s: &str| s.parse::<TunnelsConfig>().unwrap_err().kind();
+        assert_eq!(invalid("[tunnels.a]\ntype = \"client\"\nlisten = \"127.0.0.1:1\""), ErrorKind::InvalidData);
+        assert_eq!(invalid("[tunnels.a]\ntype = \"tcp\""), ErrorKind::InvalidData);
+        assert_eq!(invalid("[tunnels.a]\ntype = \"socks\"\nlisten = \"127.0.0.1:1\"\nallow = [\"localhost\"]"), ErrorKind::InvalidData);
+        assert_eq!(invalid("[tunnels.a]\ntype = \"socks\"\nlisten = \"127.0.0.1:1\"\nport = 1"), ErrorKind::InvalidData);
+        assert_eq!(invalid("[tunnels.a]\ntype = \"server\"\ntarget = \"127.0.0.1:1\"\n[tunnels.b]\ntype = \"server\"\ntarget = \"127.0.0.1:2\""), ErrorKind::InvalidData);
+    }
+
+    /// What comes back through the fake bridge's echo; nothing if the tunnel drops the connection
+    fn echo(addr: &str) -> String {
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let _ = conn.write_all(b"ping\n").and_then(|_| conn.shutdown(Shutdown::Write));
+        let mut reply = String::new();
+        let _ = conn.read_to_string(&mut reply);
+        reply
+    }
+
+    #[test]
+    fn reload() {
+        let bridge = fake_bridge();
+        let mut manager = TunnelManager::new(SamConfig::new("127.0.0.1", bridge.port));
+        let config = |to: &str| -> TunnelsConfig {
+            format!("[tunnels.a]\ntype = \"client\"\nlisten = \"127.0.0.1:0\"\nto = \"a.i2p:80\"\n\
+                     [tunnels.b]\ntype = \"client\"\nlisten = \"127.0.0.1:0\"\nto = \"{}\"\n\
+                     [tunnels.c]\ntype = \"http-proxy\"\nlisten = \"127.0.0.1:0\"\nallow = [\"10.0.0.1\"]\n", to).parse().unwrap()
+        };
+        manager.apply(&config("b.i2p:80")).unwrap();
+        let status = manager.status();
+        assert_eq!(status.len(), 3);
+        assert!(status.iter().all(|s| s.state == TunnelState::Running));
+        let a = status[0].address.clone();
+        assert_eq!(echo(&a), "ping\n");
+        assert_eq!(echo(&status[1].address), "ping\n");
+        // Not on the access list
+        assert_eq!(echo(&status[2].address), "");
+
+        manager.apply(&config("b.i2p:8080")).unwrap();
+        let status = manager.status();
+        // a kept its listener and counts, b was restarted
+        assert_eq!(status[0].address, a);
+        assert_eq!(status[0].stats.connections, 1);
+        assert_eq!(status[1].stats.connections, 0);
+        assert_eq!(status[2].stats.failed, 1);
+        assert_eq!(echo(&status[1].address), "ping\n");
+        // One TRANSIENT session shared by all tunnels
+        assert_eq!(bridge.received.lock().unwrap().iter().filter(|l| l.starts_with("SESSION CREATE")).count(), 1);
+
+        manager.apply(&TunnelsConfig::default()).unwrap();
+        assert!(manager.status().is_empty());
+        assert!(TcpStream::connect(&a).is_err());
+    }
+
+    #[test]
+    fn server_stats() {
+        let bridge = fake_bridge();
+        let mut manager = TunnelManager::new(SamConfig::new("127.0.0.1", bridge.port));
+        manager.apply(&"[tunnels.site]\ntype = \"server\"\ntarget = \"127.0.0.1:1\"\nmax_per_peer = 0\n".parse().unwrap()).unwrap();
+        while manager.status()[0].stats.failed == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Streams refused by the per-peer limit are counted once, as failed
+        assert_eq!(manager.status()[0].stats.connections, 0);
+        manager.apply(&TunnelsConfig::default()).unwrap();
+    }
+
+    #[test]
+    fn stop_server() {
+        let bridge = fake_bridge();
+        bridge.hold_accepts.store(true, Ordering::SeqCst);
+        let mut manager = TunnelManager::new(SamConfig::new("127.0.0.1", bridge.port));
+        manager.apply(&"[tunnels.site]\ntype = \"server\"\ntarget = \"127.0.0.1:1\"\n".parse().unwrap()).unwrap();
+        while !bridge.received.lock().unwrap().iter().any(|l| l.starts_with("STREAM ACCEPT")) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Returns once the accept thread is gone, although the bridge never answers the accept
+        manager.apply(&TunnelsConfig::default()).unwrap();
+        assert!(manager.status().is_empty());
+    }
+}
@@ -4,4 +4,37 @@ use assert_cmd::prelude::*; // Add methods on commands
 #[test]
 fn it_works() {
     assert_eq!(2 + 2, 4);
-}
\ No newline at end of file
+}
+
+#[test]
+fn help() {
+    Command::cargo_bin("i2p_client").unwrap()
+        .arg("--help")
+        .assert()
+        .success();
+}
+
+#[test]
+fn json_errors() {
+    let home = std::env::temp_dir().join(format!("i2p_client-json-{}", std::process::id()));
+    std::fs::create_dir_all(&home).unwrap();
+    Command::cargo_bin("i2p_client").unwrap()
+        .env("HOME", &home)
+        .env("XDG_DATA_HOME", home.join(".local/share"))
+        .args(["--output", "json", "dest", "--dest_alias", "nobody.i2p"])
+        .assert()
+        .code(3)
+        .stdout("")
+        .stderr("{\"error\":{\"kind\":\"not_found\",\"message\":\"Alias nobody.i2p not found\"}}\n");
+    Command::cargo_bin("i2p_client").unwrap()
+        .args(["--output", "json", "send", "--to", "bob.i2p", "--message", "hi", "--to_port", "x"])
+        .assert()
+        .code(2);
+    let _ = std::fs::remove_dir_all(&home);
+}
+
+#[test]
+fn sig_type_without_from_str_trait() {
+    assert_eq!(i2p_client::SigType::from_str("EDDSA_SHA512_ED25519").unwrap().as_string(), "EDDSA_SHA512_ED25519");
+    assert_eq!(i2p_client::SigType::from_str("RSA").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}